@@ -1,25 +1,61 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Uint128,
+    to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdError, StdResult,
+    Uint128,
 };
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
 use execute::{
-    execute_add_token, execute_borrow, execute_deposit, execute_fill_position, execute_liquidate,
-    execute_repay, execute_withdraw,
+    backfill_market_stats, execute_accept_counter_offer, execute_accept_extension,
+    execute_accept_partial_fill, execute_account_borrow, execute_account_deposit,
+    execute_account_repay, execute_account_withdraw, execute_add_collateral, execute_add_token,
+    execute_approve_operator, execute_batch, execute_bid, execute_borrow, execute_cancel_auction,
+    execute_cancel_fill, execute_cancel_offers, execute_counter_offer, execute_deleverage,
+    execute_deposit, execute_deposit_insurance, execute_fill_position, execute_flash_loan,
+    execute_fund_insurance, execute_liquidate, execute_liquidate_account, execute_open_leveraged,
+    execute_propose_extension, execute_refinance, execute_register_offer_key, execute_repay,
+    execute_repay_flash_loan, execute_revoke_operator, execute_start_auction,
+    execute_swap_collateral, execute_take_over_loan, execute_take_signed_offer,
+    execute_update_auction_config, execute_update_collateral_weight,
+    execute_update_e_mode_category, execute_update_isolation, execute_update_lender_nft,
+    execute_update_liquidation_fee, execute_update_margin_params, execute_update_swap_router,
+    execute_update_token_caps, execute_withdraw, execute_withdraw_collateral,
+    execute_withdraw_counter_offer, migrate_positions, rebuild_order_book, reply_deleverage,
+    reply_flash_loan, reply_open_leveraged, reply_swap_collateral, validate_auction_config,
+    validate_liquidation_fee,
 };
 use query::{
-    query_all_positions, query_config, query_position, query_token_configs, query_user_info,
+    query_account_health, query_all_positions, query_auction, query_auctions, query_config,
+    query_counter_offers, query_e_mode_categories, query_extension_proposal, query_insurance_funds,
+    query_isolation, query_lender_notes, query_liquidatable_positions, query_margin_account,
+    query_margin_params, query_market_stats, query_offer_key, query_offer_nonce,
+    query_open_requests, query_operators, query_position, query_position_health,
+    query_position_history, query_protocol_stats, query_shortfalls, query_simulate_borrow,
+    query_simulate_liquidation, query_simulate_repay, query_token_configs, query_user_info,
 };
 
-use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
-use crate::state::{Config, CONFIG, POSITION_COUNTER, SUPPORTED_TOKENS};
+use crate::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
+use crate::state::{Config, CONFIG, LEGACY_CONFIG, POSITION_COUNTER, SUPPORTED_TOKENS};
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:encke-contract";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// reply ids
+pub const FLASH_LOAN_REPLY_ID: u64 = 1;
+pub const SWAP_REPLY_ID: u64 = 2;
+pub const LEVERAGE_REPLY_ID: u64 = 3;
+pub const DELEVERAGE_REPLY_ID: u64 = 4;
+
+// liquidator premium on the repaid debt value outside e-mode, in basis points
+pub const DEFAULT_LIQUIDATION_BONUS: u128 = 500;
+
+// cap on the protocol's fee on liquidation repayments, in basis points
+pub const MAX_LIQUIDATION_FEE: u128 = 1_000;
+
+// share of margin account interest kept as protocol reserves, in basis points
+pub const MARGIN_RESERVE_FACTOR: u128 = 1_000;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -27,11 +63,32 @@ pub fn instantiate(
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> StdResult<Response> {
+    if let Some(auction) = &msg.auction {
+        validate_auction_config(auction)?;
+    }
+    if msg.flash_loan_fee >= Uint128::from(10_000u128) {
+        return Err(StdError::generic_err(
+            "Flash loan fee must be below 10000 basis points",
+        ));
+    }
+    validate_liquidation_fee(msg.liquidation_fee)?;
+
     // Save configuration
     let config = Config {
         admin: info.sender.clone(),
         mock_oracle: deps.api.addr_validate(&msg.mock_oracle)?,
         liquidation_threshold: msg.liquidation_threshold,
+        flash_loan_fee: msg.flash_loan_fee,
+        liquidation_fee: msg.liquidation_fee,
+        auction: msg.auction,
+        lender_nft: msg
+            .lender_nft
+            .map(|address| deps.api.addr_validate(&address))
+            .transpose()?,
+        swap_router: msg
+            .swap_router
+            .map(|address| deps.api.addr_validate(&address))
+            .transpose()?,
     };
 
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
@@ -57,8 +114,11 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             borrow_token,
             amount,
             interest_rate,
-            collateral_token,
             collateral,
+            term,
+            allowed_lenders,
+            collateral_source,
+            return_to_deposits,
         } => execute_borrow(
             deps,
             env,
@@ -66,24 +126,286 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             borrow_token,
             amount,
             interest_rate,
-            collateral_token,
             collateral,
+            term,
+            allowed_lenders,
+            collateral_source,
+            return_to_deposits,
         ),
         ExecuteMsg::Withdraw { token, amount } => execute_withdraw(deps, env, info, token, amount),
         ExecuteMsg::FillPosition {
             position_id,
             amount,
         } => execute_fill_position(deps, env, info, position_id, amount),
+        ExecuteMsg::AcceptPartialFill { position_id } => {
+            execute_accept_partial_fill(deps, env, info, position_id)
+        }
+        ExecuteMsg::CancelFill { position_id } => execute_cancel_fill(deps, info, position_id),
+        ExecuteMsg::CounterOffer {
+            position_id,
+            amount,
+            interest_rate,
+            term,
+        } => execute_counter_offer(deps, env, info, position_id, amount, interest_rate, term),
+        ExecuteMsg::WithdrawCounterOffer { position_id } => {
+            execute_withdraw_counter_offer(deps, info, position_id)
+        }
+        ExecuteMsg::AcceptCounterOffer {
+            position_id,
+            lender,
+        } => execute_accept_counter_offer(deps, env, info, position_id, lender),
+        ExecuteMsg::Refinance {
+            position_id,
+            offer,
+            signature,
+        } => execute_refinance(deps, env, info, position_id, offer, signature),
+        ExecuteMsg::TakeOverLoan {
+            position_id,
+            interest_rate,
+        } => execute_take_over_loan(deps, env, info, position_id, interest_rate),
+        ExecuteMsg::ProposeExtension {
+            position_id,
+            maturity,
+            interest_rate,
+            capitalize_interest,
+        } => execute_propose_extension(
+            deps,
+            env,
+            info,
+            position_id,
+            maturity,
+            interest_rate,
+            capitalize_interest,
+        ),
+        ExecuteMsg::AcceptExtension { position_id } => {
+            execute_accept_extension(deps, env, info, position_id)
+        }
+        ExecuteMsg::RegisterOfferKey { public_key } => {
+            execute_register_offer_key(deps, info, public_key)
+        }
+        ExecuteMsg::CancelOffers { nonces } => execute_cancel_offers(deps, info, nonces),
+        ExecuteMsg::TakeSignedOffer {
+            offer,
+            signature,
+            collateral,
+        } => execute_take_signed_offer(deps, env, info, offer, signature, collateral),
+        ExecuteMsg::ApproveOperator { operator } => execute_approve_operator(deps, info, operator),
+        ExecuteMsg::RevokeOperator { operator } => execute_revoke_operator(deps, info, operator),
+        ExecuteMsg::AddCollateral {
+            position_id,
+            token,
+            amount,
+            source,
+        } => execute_add_collateral(deps, env, info, position_id, token, amount, source),
+        ExecuteMsg::WithdrawCollateral {
+            position_id,
+            token,
+            amount,
+        } => execute_withdraw_collateral(deps, env, info, position_id, token, amount),
         ExecuteMsg::Repay { position_id } => execute_repay(deps, env, info, position_id),
-        ExecuteMsg::Liquidate { position_id } => execute_liquidate(deps, env, info, position_id),
+        ExecuteMsg::Liquidate {
+            position_id,
+            collateral_token,
+        } => execute_liquidate(deps, env, info, position_id, collateral_token),
+        ExecuteMsg::FlashLoan {
+            token,
+            amount,
+            callback,
+        } => execute_flash_loan(deps, env, info, token, amount, callback),
+        ExecuteMsg::RepayFlashLoan {} => execute_repay_flash_loan(deps, env, info),
+        ExecuteMsg::UpdateAuctionConfig { auction } => {
+            execute_update_auction_config(deps, info, auction)
+        }
+        ExecuteMsg::UpdateLiquidationFee { fee } => execute_update_liquidation_fee(deps, info, fee),
+        ExecuteMsg::StartAuction { position_id } => {
+            execute_start_auction(deps, env, info, position_id)
+        }
+        ExecuteMsg::Bid {
+            position_id,
+            amount,
+            collateral_token,
+        } => execute_bid(deps, env, info, position_id, amount, collateral_token),
+        ExecuteMsg::CancelAuction { position_id } => {
+            execute_cancel_auction(deps, env, info, position_id)
+        }
+        ExecuteMsg::DepositInsurance { token, amount } => {
+            execute_deposit_insurance(deps, env, info, token, amount)
+        }
+        ExecuteMsg::FundInsurance { token, amount } => {
+            execute_fund_insurance(deps, info, token, amount)
+        }
+        ExecuteMsg::UpdateTokenCaps { token, caps } => {
+            execute_update_token_caps(deps, info, token, caps)
+        }
+        ExecuteMsg::UpdateIsolation { token, isolation } => {
+            execute_update_isolation(deps, info, token, isolation)
+        }
+        ExecuteMsg::UpdateEModeCategory { id, category } => {
+            execute_update_e_mode_category(deps, info, id, category)
+        }
+        ExecuteMsg::UpdateCollateralWeight { token, weight } => {
+            execute_update_collateral_weight(deps, info, token, weight)
+        }
+        ExecuteMsg::UpdateMarginParams { token, params } => {
+            execute_update_margin_params(deps, info, token, params)
+        }
+        ExecuteMsg::UpdateLenderNft { address } => execute_update_lender_nft(deps, info, address),
+        ExecuteMsg::UpdateSwapRouter { address } => execute_update_swap_router(deps, info, address),
+        ExecuteMsg::SwapCollateral {
+            position_id,
+            from_token,
+            amount,
+            to_token,
+            min_receive,
+        } => execute_swap_collateral(
+            deps,
+            env,
+            info,
+            position_id,
+            from_token,
+            amount,
+            to_token,
+            min_receive,
+        ),
+        ExecuteMsg::AccountDeposit { token, amount } => {
+            execute_account_deposit(deps, env, info, token, amount)
+        }
+        ExecuteMsg::AccountWithdraw { token, amount } => {
+            execute_account_withdraw(deps, env, info, token, amount)
+        }
+        ExecuteMsg::AccountBorrow { token, amount } => {
+            execute_account_borrow(deps, env, info, token, amount)
+        }
+        ExecuteMsg::AccountRepay {
+            token,
+            amount,
+            borrower,
+        } => execute_account_repay(deps, env, info, token, amount, borrower),
+        ExecuteMsg::LiquidateAccount {
+            borrower,
+            debt_token,
+            amount,
+            collateral_token,
+        } => execute_liquidate_account(
+            deps,
+            env,
+            info,
+            borrower,
+            debt_token,
+            amount,
+            collateral_token,
+        ),
+        ExecuteMsg::OpenLeveraged {
+            collateral_token,
+            amount,
+            debt_token,
+            leverage,
+            min_receive,
+        } => execute_open_leveraged(
+            deps,
+            env,
+            info,
+            collateral_token,
+            amount,
+            debt_token,
+            leverage,
+            min_receive,
+        ),
+        ExecuteMsg::Deleverage {
+            collateral_token,
+            amount,
+            debt_token,
+            min_receive,
+        } => execute_deleverage(
+            deps,
+            env,
+            info,
+            collateral_token,
+            amount,
+            debt_token,
+            min_receive,
+        ),
+        ExecuteMsg::Batch(actions) => execute_batch(deps, env, info, actions),
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
+    match msg.id {
+        FLASH_LOAN_REPLY_ID => reply_flash_loan(deps, env),
+        SWAP_REPLY_ID => reply_swap_collateral(deps, env),
+        LEVERAGE_REPLY_ID => reply_open_leveraged(deps, env),
+        DELEVERAGE_REPLY_ID => reply_deleverage(deps, env),
+        id => Err(StdError::generic_err(format!("Unknown reply id: {}", id))),
+    }
+}
+
+/// Upgrade state written by earlier versions: the configuration gains its newer settings
+/// unset, positions move to the basket layout and market statistics are rebuilt
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
+    let version = get_contract_version(deps.storage)?;
+    if version.contract != CONTRACT_NAME {
+        return Err(StdError::generic_err(
+            "Cannot migrate from a different contract",
+        ));
+    }
+
+    if CONFIG.load(deps.storage).is_err() {
+        let legacy = LEGACY_CONFIG.load(deps.storage)?;
+        CONFIG.save(
+            deps.storage,
+            &Config {
+                admin: legacy.admin,
+                liquidation_threshold: legacy.liquidation_threshold,
+                mock_oracle: legacy.mock_oracle,
+                flash_loan_fee: Uint128::zero(),
+                liquidation_fee: Uint128::zero(),
+                auction: None,
+                lender_nft: None,
+                swap_router: None,
+            },
+        )?;
     }
+    let migrated = migrate_positions(deps.branch())?;
+    backfill_market_stats(deps.branch())?;
+    rebuild_order_book(deps.branch())?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", version.version)
+        .add_attribute("positions_migrated", migrated.to_string()))
 }
 
 pub mod execute {
-    use cosmwasm_std::{BankMsg, Coin, CosmosMsg, QueryRequest, StdError, WasmMsg, WasmQuery};
-    use cw20::Cw20ExecuteMsg;
+    use cosmwasm_std::{
+        to_json_vec, Addr, BankMsg, Coin, CosmosMsg, Decimal, Empty, QueryRequest, Storage, SubMsg,
+        WasmMsg, WasmQuery,
+    };
+    use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg};
+    use encke_oracle::msg::{PriceResponse, QueryMsg as OracleQueryMsg};
+    use encke_router::msg::{
+        AssetInfo, Cw20HookMsg as RouterCw20HookMsg, ExecuteMsg as RouterExecuteMsg, SwapOperation,
+    };
+
+    use sha2::{Digest, Sha256};
+    use std::collections::BTreeMap;
 
-    use crate::state::{Position, Token, DEPOSITS, POSITIONS};
+    use crate::cw721::{Cw721ExecuteMsg, Cw721QueryMsg, OwnerOfResponse};
+    use crate::msg::{Action, CollateralSource, LendOffer, MarginAccountHealth, PositionHealth};
+    use crate::state::{
+        AccountDebt, Auction, AuctionConfig, CounterOffer, EModeCategory, ExtensionProposal,
+        FlashLoan, Isolation, MarginParams, MarketStats, OpenRequestKeys, PendingDeleverage,
+        PendingLeverage, PendingSwap, Position, PositionEvent, Shortfall, Token, TokenCaps,
+        ACCOUNT_COLLATERAL, ACCOUNT_DEBTS, ACCOUNT_SHORTFALLS, AUCTIONS, CANCELLED_NONCES,
+        COLLATERAL_WEIGHTS, COUNTER_OFFERS, DEPOSITS, DEPOSIT_CHECKPOINTS, DEPOSIT_INDEX,
+        EXTENSION_PROPOSALS, E_MODE_CATEGORIES, FLASH_LOAN, INSURANCE_FUNDS, ISOLATED_DEBT,
+        ISOLATED_TOKENS, LEGACY_POSITIONS, MARGIN_PARAMS, MARKET_STATS, OFFER_KEYS, OPEN_REQUESTS,
+        OPEN_REQUESTS_BY_COLLATERALIZATION, OPEN_REQUESTS_BY_RATE, OPERATORS, PENDING_DELEVERAGE,
+        PENDING_LEVERAGE, PENDING_SWAP, POSITIONS, POSITION_HISTORY, RESERVES, SHORTFALLS,
+        TOKEN_CAPS, TOKEN_E_MODE, USED_NONCES,
+    };
 
     use super::*;
 
@@ -119,7 +441,20 @@ pub mod execute {
         }
 
         let token_type = determine_token_type(&deps.as_ref(), &token)?;
-        let msg = match token_type {
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        check_cap(
+            "Supply",
+            caps.supply_cap,
+            stats.total_deposited + stats.total_lent,
+            amount,
+        )?;
+
+        let msg = match token_type.clone() {
             Token::Native(denom) => {
                 verify_funds(&info.funds, &denom, amount)?;
                 BankMsg::Send {
@@ -140,11 +475,12 @@ pub mod execute {
         };
 
         // Update deposit balance
-        let key = (&info.sender, token.as_str());
-        let current = DEPOSITS
-            .may_load(deps.storage, key)?
-            .unwrap_or(Uint128::zero());
-        DEPOSITS.save(deps.storage, key, &(current + amount))?;
+        let current = deposit_balance(deps.storage, &info.sender, &token)?;
+        save_deposit(deps.storage, &info.sender, &token, current + amount)?;
+        update_market_stats(deps.storage, &token_type, |stats| {
+            stats.total_deposited += amount;
+            Ok(())
+        })?;
 
         Ok(Response::new()
             .add_message(msg)
@@ -153,499 +489,8386 @@ pub mod execute {
             .add_attribute("amount", amount.to_string()))
     }
 
+    /// Run the actions of a batch in order with their share of the attached native funds,
+    /// merging their responses. Any failing action reverts the whole batch. Actions see the
+    /// state changes of earlier ones, while their messages only run after the batch.
+    pub fn execute_batch(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        actions: Vec<Action>,
+    ) -> StdResult<Response> {
+        if actions.is_empty() {
+            return Err(StdError::generic_err("Empty batch"));
+        }
+
+        // The funds of the steps must add up to exactly the funds sent
+        let mut allocated: Vec<Coin> = vec![];
+        for coin in actions.iter().flat_map(|action| &action.funds) {
+            match allocated.iter_mut().find(|c| c.denom == coin.denom) {
+                Some(total) => total.amount += coin.amount,
+                None => allocated.push(coin.clone()),
+            }
+        }
+        allocated.retain(|coin| !coin.amount.is_zero());
+        let sent = info
+            .funds
+            .iter()
+            .filter(|coin| !coin.amount.is_zero())
+            .collect::<Vec<_>>();
+        if allocated.len() != sent.len() || !sent.iter().all(|coin| allocated.contains(coin)) {
+            return Err(StdError::generic_err(
+                "Batch funds must match the funds sent",
+            ));
+        }
+
+        let mut response = Response::new()
+            .add_attribute("action", "batch")
+            .add_attribute("actions", actions.len().to_string());
+        for action in actions {
+            // Flash loans and swaps settle in a reply, after the batch has returned, and swaps
+            // measure their output against balances other actions' transfers would move
+            if matches!(
+                action.msg,
+                ExecuteMsg::Batch(_)
+                    | ExecuteMsg::FlashLoan { .. }
+                    | ExecuteMsg::RepayFlashLoan {}
+                    | ExecuteMsg::SwapCollateral { .. }
+                    | ExecuteMsg::OpenLeveraged { .. }
+                    | ExecuteMsg::Deleverage { .. }
+            ) {
+                return Err(StdError::generic_err("Action not allowed in a batch"));
+            }
+            let action_info = MessageInfo {
+                sender: info.sender.clone(),
+                funds: action.funds,
+            };
+            let result = super::execute(deps.branch(), env.clone(), action_info, action.msg)?;
+            response = response
+                .add_submessages(result.messages)
+                .add_attributes(result.attributes)
+                .add_events(result.events);
+        }
+        Ok(response)
+    }
+
     /// Create a new borrow position
+    #[allow(clippy::too_many_arguments)]
     pub fn execute_borrow(
-        deps: DepsMut,
+        mut deps: DepsMut,
         env: Env,
         info: MessageInfo,
         borrow_token: String,
         amount: Uint128,
         interest_rate: Uint128,
-        collateral_token: String,
-        collateral: Uint128,
+        collateral: Vec<(String, Uint128)>,
+        term: Option<u64>,
+        allowed_lenders: Option<Vec<String>>,
+        collateral_source: Option<CollateralSource>,
+        return_to_deposits: Option<bool>,
     ) -> StdResult<Response> {
+        if collateral.is_empty() {
+            return Err(StdError::generic_err("No collateral"));
+        }
+        if term == Some(0) {
+            return Err(StdError::generic_err("Term must be positive"));
+        }
+        let allowed_lenders = allowed_lenders
+            .map(|lenders| {
+                if lenders.is_empty() {
+                    return Err(StdError::generic_err("No allowed lenders"));
+                }
+                lenders
+                    .iter()
+                    .map(|lender| deps.api.addr_validate(lender))
+                    .collect::<StdResult<Vec<_>>>()
+            })
+            .transpose()?;
         if !SUPPORTED_TOKENS
             .may_load(deps.storage, &borrow_token)?
             .unwrap_or(false)
-            || !SUPPORTED_TOKENS
-                .may_load(deps.storage, &collateral_token)?
-                .unwrap_or(false)
         {
             return Err(StdError::generic_err("Unsupported token"));
         }
+        for (i, (token, _)) in collateral.iter().enumerate() {
+            if !SUPPORTED_TOKENS
+                .may_load(deps.storage, token)?
+                .unwrap_or(false)
+            {
+                return Err(StdError::generic_err("Unsupported token"));
+            }
+            if collateral[..i].iter().any(|(other, _)| other == token) {
+                return Err(StdError::generic_err("Duplicate collateral token"));
+            }
+        }
 
+        let config = CONFIG.load(deps.storage)?;
         let borrow_token_type = determine_token_type(&deps.as_ref(), &borrow_token)?;
-        let collateral_token_type = determine_token_type(&deps.as_ref(), &collateral_token)?;
 
-        // Transfer collateral to contract
-        let transfer_msg = match collateral_token_type.clone() {
-            Token::Native(denom) => {
-                verify_funds(&info.funds, &denom, collateral)?;
-                BankMsg::Send {
-                    to_address: env.contract.address.to_string(),
-                    amount: vec![Coin {
-                        denom,
-                        amount: collateral,
-                    }],
-                }
-                .into()
+        // Fail early when the request could never be filled or collateralized
+        let borrow_caps = TOKEN_CAPS
+            .may_load(deps.storage, &borrow_token)?
+            .unwrap_or_default();
+        let borrow_stats = MARKET_STATS
+            .may_load(deps.storage, &borrow_token)?
+            .unwrap_or_default();
+        check_cap(
+            "Borrow",
+            borrow_caps.borrow_cap,
+            borrow_stats.total_borrowed,
+            amount,
+        )?;
+        for (token, amount) in &collateral {
+            let caps = TOKEN_CAPS
+                .may_load(deps.storage, token)?
+                .unwrap_or_default();
+            let stats = MARKET_STATS
+                .may_load(deps.storage, token)?
+                .unwrap_or_default();
+            check_cap(
+                "Collateral",
+                caps.collateral_cap,
+                stats.total_collateral,
+                *amount,
+            )?;
+        }
+
+        // Isolated collateral must back the position alone, only for allowed borrow
+        // tokens and up to its debt ceiling
+        for (collateral_token, _) in &collateral {
+            let Some(isolation) = ISOLATED_TOKENS.may_load(deps.storage, collateral_token)? else {
+                continue;
+            };
+            if collateral.len() > 1 {
+                return Err(StdError::generic_err(
+                    "Isolated collateral cannot be combined with other collateral",
+                ));
             }
-            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: addr.to_string(),
-                msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
-                    owner: info.sender.to_string(),
-                    recipient: env.contract.address.to_string(),
-                    amount: collateral,
-                })?,
-                funds: vec![],
-            }),
-        };
+            if !isolation.borrow_tokens.contains(&borrow_token) {
+                return Err(StdError::generic_err(
+                    "Borrow token not allowed against isolated collateral",
+                ));
+            }
+            // The debt only counts towards the ceiling once the loan starts
+            check_debt_ceiling(
+                &deps.as_ref(),
+                &config,
+                collateral_token,
+                &isolation,
+                &borrow_token_type,
+                amount,
+            )?;
+        }
+
+        // Transfer collateral to contract
+        let collateral_source = collateral_source.unwrap_or(CollateralSource::Wallet);
+        let mut transfer_msgs = vec![];
+        let mut basket = vec![];
+        for (token, amount) in collateral {
+            let token_type = determine_token_type(&deps.as_ref(), &token)?;
+            transfer_msgs.extend(receive_collateral(
+                deps.storage,
+                &env,
+                &info,
+                &token_type,
+                amount,
+                &collateral_source,
+            )?);
+            update_market_stats(deps.storage, &token_type, |stats| {
+                stats.total_collateral += amount;
+                Ok(())
+            })?;
+            basket.push((token_type, amount));
+        }
 
         // Create and save position
         let position_id = POSITION_COUNTER.load(deps.storage)? + Uint128::one();
         let position = Position {
             borrower: info.sender.clone(),
-            lender: None,
+            lenders: vec![],
             borrow_token: borrow_token_type,
-            collateral_token: collateral_token_type,
             amount,
             interest_rate,
-            collateral,
+            collateral: basket,
             start_time: 0,
             filled: false,
+            lender_nft: None,
+            refinance_count: 0,
+            term,
+            maturity: None,
+            allowed_lenders,
+            return_to_deposits: return_to_deposits.unwrap_or(false),
+            e_mode: None,
         };
+        let health = position_health(&deps.as_ref(), &config, &position, env.block.time.seconds())?;
+        if health.ltv > health.max_ltv {
+            return Err(StdError::generic_err(format!(
+                "Insufficient collateral: LTV {} above max {}",
+                health.ltv, health.max_ltv
+            )));
+        }
         POSITIONS.save(deps.storage, position_id.u128(), &position)?;
         POSITION_COUNTER.save(deps.storage, &position_id)?;
+        // Private requests stay out of the public order book
+        if position.allowed_lenders.is_none() {
+            index_open_request(deps.branch(), position_id.u128(), &position)?;
+        }
 
         Ok(Response::new()
-            .add_message(transfer_msg)
+            .add_messages(transfer_msgs)
             .add_attribute("action", "borrow")
             .add_attribute("position_id", position_id.to_string())
             .add_attribute("borrower", info.sender.to_string()))
     }
 
-    /// Fill an existing borrow position
+    /// Fund part or all of an open borrow request. Funds are held by the contract until
+    /// the request is fully funded, when the loan starts and the borrower receives them.
     pub fn execute_fill_position(
-        deps: DepsMut,
+        mut deps: DepsMut,
         env: Env,
         info: MessageInfo,
         position_id: Uint128,
         amount: Uint128,
     ) -> StdResult<Response> {
         let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
-        if position.filled || position.lender.is_some() {
+        if position.filled {
             return Err(StdError::generic_err("Position already filled"));
         }
-        if position.amount != amount {
-            return Err(StdError::generic_err("Amount mismatch"));
+        if let Some(allowed_lenders) = &position.allowed_lenders {
+            if !allowed_lenders.contains(&info.sender) {
+                return Err(StdError::generic_err("Not an allowed lender"));
+            }
+        }
+        let funded = funded_amount(&position);
+        if amount.is_zero() || funded + amount > position.amount {
+            return Err(StdError::generic_err(format!(
+                "Amount mismatch: {} left to fund",
+                position.amount - funded
+            )));
         }
+        let key = token_to_string(&position.borrow_token);
+        let caps = TOKEN_CAPS.may_load(deps.storage, &key)?.unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &key)?
+            .unwrap_or_default();
+        check_cap("Borrow", caps.borrow_cap, stats.total_borrowed, amount)?;
+        check_cap(
+            "Supply",
+            caps.supply_cap,
+            stats.total_deposited + stats.total_lent,
+            amount,
+        )?;
 
-        // Transfer borrowed amount to borrower
-        let transfer_msg = match &position.borrow_token {
-            Token::Native(denom) => {
-                verify_funds(&info.funds, denom, amount)?;
-                BankMsg::Send {
-                    to_address: position.borrower.to_string(),
-                    amount: vec![Coin {
-                        denom: denom.clone(),
-                        amount,
-                    }],
-                }
-                .into()
-            }
-            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: addr.to_string(),
-                msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
-                    owner: info.sender.to_string(),
-                    recipient: position.borrower.to_string(),
-                    amount,
-                })?,
-                funds: vec![],
-            }),
-        };
+        // Escrow the lender's funds and record their share
+        let mut messages: Vec<CosmosMsg> =
+            receive_msg(&env, &info, &position.borrow_token, amount)?
+                .into_iter()
+                .collect();
+        match position
+            .lenders
+            .iter_mut()
+            .find(|(lender, _)| *lender == info.sender)
+        {
+            Some((_, share)) => *share += amount,
+            None => position.lenders.push((info.sender.clone(), amount)),
+        }
 
-        // Update position
-        position.lender = Some(info.sender.clone());
-        position.filled = true;
-        position.start_time = env.block.time.seconds();
+        let mut response = Response::new()
+            .add_attribute("action", "fill_position")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("amount", amount.to_string());
+        if funded + amount == position.amount {
+            messages.extend(activate_position(
+                deps.branch(),
+                &env,
+                position_id.u128(),
+                &mut position,
+            )?);
+            response = response.add_attribute("activated", "true");
+        }
         POSITIONS.save(deps.storage, position_id.u128(), &position)?;
 
-        Ok(Response::new()
-            .add_message(transfer_msg)
-            .add_attribute("action", "fill_position")
-            .add_attribute("lender", info.sender.to_string()))
+        Ok(response.add_messages(messages))
     }
 
-    /// Repay a filled position
-    pub fn execute_repay(
-        deps: DepsMut,
+    /// Start a partially funded loan for the amount funded so far (borrower only)
+    pub fn execute_accept_partial_fill(
+        mut deps: DepsMut,
         env: Env,
         info: MessageInfo,
         position_id: Uint128,
     ) -> StdResult<Response> {
-        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
         if position.borrower != info.sender {
             return Err(StdError::generic_err("Not borrower"));
         }
-        if !position.filled {
-            return Err(StdError::generic_err("Position not filled"));
+        if position.filled {
+            return Err(StdError::generic_err("Position already filled"));
+        }
+        if position.lenders.is_empty() {
+            return Err(StdError::generic_err("Position not funded"));
         }
 
-        let time_elapsed = env.block.time.seconds() - position.start_time;
-        let interest = position.amount * position.interest_rate * Uint128::from(time_elapsed)
-            / Uint128::from(31_536_000u64 * 100u64);
-        let total_repayment = position.amount + interest;
-
-        // Transfer repayment to lender
-        let repay_msg = match &position.borrow_token {
-            Token::Native(denom) => {
-                verify_funds(&info.funds, denom, total_repayment)?;
-                BankMsg::Send {
-                    to_address: position.lender.clone().unwrap().to_string(),
-                    amount: vec![Coin {
-                        denom: denom.clone(),
-                        amount: total_repayment,
-                    }],
-                }
-                .into()
-            }
-            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: addr.to_string(),
-                msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
-                    owner: info.sender.to_string(),
-                    recipient: position.lender.clone().unwrap().to_string(),
-                    amount: total_repayment,
-                })?,
-                funds: vec![],
-            }),
-        };
-
-        // Return collateral to borrower
-        let collateral_msg = match &position.collateral_token {
-            Token::Native(denom) => BankMsg::Send {
-                to_address: info.sender.to_string(),
-                amount: vec![Coin {
-                    denom: denom.clone(),
-                    amount: position.collateral,
-                }],
-            }
-            .into(),
-            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: addr.to_string(),
-                msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
-                    recipient: info.sender.to_string(),
-                    amount: position.collateral,
-                })?,
-                funds: vec![],
-            }),
-        };
+        let messages = activate_position(deps.branch(), &env, position_id.u128(), &mut position)?;
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
 
-        POSITIONS.remove(deps.storage, position_id.u128());
         Ok(Response::new()
-            .add_messages(vec![repay_msg, collateral_msg])
-            .add_attribute("action", "repay")
-            .add_attribute("position_id", position_id.to_string()))
+            .add_messages(messages)
+            .add_attribute("action", "accept_partial_fill")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("amount", position.amount.to_string()))
     }
 
-    /// Withdraw deposited tokens from the contract
-    pub fn execute_withdraw(
-        deps: DepsMut,
-        _env: Env,
+    /// Move a loan to the lender of a signed offer, at the borrower's request. The lender's
+    /// deposit pays off the current lenders' principal and accrued interest, which becomes
+    /// the new principal at the offer's rate and term. The collateral stays in escrow.
+    pub fn execute_refinance(
+        mut deps: DepsMut,
+        env: Env,
         info: MessageInfo,
-        token: String,
-        amount: Uint128,
+        position_id: Uint128,
+        offer: LendOffer,
+        signature: Binary,
     ) -> StdResult<Response> {
-        if !SUPPORTED_TOKENS
-            .may_load(deps.storage, &token)?
-            .unwrap_or(false)
-        {
-            return Err(StdError::generic_err("Unsupported token"));
-        }
-
-        let token_type = determine_token_type(&deps.as_ref(), &token)?;
-        let key = (&info.sender, token.as_str());
-        let current = DEPOSITS.load(deps.storage, key)?;
-        if current < amount {
-            return Err(StdError::generic_err("Insufficient deposit"));
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        ensure_position_manager(deps.storage, &position, &info.sender)?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        if offer.token != token_to_string(&position.borrow_token) {
+            return Err(StdError::generic_err("Offer token does not match the loan"));
         }
+        if offer.interest_rate >= position.interest_rate {
+            return Err(StdError::generic_err(
+                "Refinance rate must be below the current rate",
+            ));
+        }
+        let lender = consume_signed_offer(deps.branch(), &env, &offer, &signature)?;
 
-        // Transfer tokens back to user
-        let withdraw_msg = match token_type {
-            Token::Native(denom) => BankMsg::Send {
-                to_address: info.sender.to_string(),
-                amount: vec![Coin { denom, amount }],
-            }
-            .into(),
-            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: addr.to_string(),
-                msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
-                    recipient: info.sender.to_string(),
-                    amount,
-                })?,
-                funds: vec![],
-            }),
-        };
+        let now = env.block.time.seconds();
+        let interest = accrued_interest(&position, now);
+        let payoff = position.amount + interest;
+        if offer.amount < payoff {
+            return Err(StdError::generic_err(format!(
+                "Offer amount {} below the payoff {}",
+                offer.amount, payoff
+            )));
+        }
+        let deposit = deposit_balance(deps.storage, &lender, &offer.token)?;
+        if deposit < payoff {
+            return Err(StdError::generic_err("Insufficient lender deposit"));
+        }
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &offer.token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &offer.token)?
+            .unwrap_or_default();
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < payoff {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+        check_cap("Borrow", caps.borrow_cap, stats.total_borrowed, interest)?;
+        save_deposit(deps.storage, &lender, &offer.token, deposit - payoff)?;
+        let mut messages =
+            lender_payments(&deps.as_ref(), position_id.u128(), &position, payoff, None)?;
 
-        // Update deposit balance
-        if current == amount {
-            DEPOSITS.remove(deps.storage, key);
-        } else {
-            DEPOSITS.save(deps.storage, key, &(current - amount))?;
+        // The accrued interest is capitalized, so the new principal must stay healthy
+        position.lenders = vec![(lender.clone(), payoff)];
+        position.amount = payoff;
+        position.interest_rate = offer.interest_rate;
+        position.start_time = now;
+        position.term = offer.term;
+        position.maturity = offer.term.map(|term| now + term);
+        position.refinance_count += 1;
+        EXTENSION_PROPOSALS.remove(deps.storage, position_id.u128());
+        let config = CONFIG.load(deps.storage)?;
+        let health = position_health(&deps.as_ref(), &config, &position, now)?;
+        if health.liquidatable {
+            return Err(StdError::generic_err(
+                "Refinanced loan would be undercollateralized",
+            ));
         }
+        if health.ltv > offer.max_ltv {
+            return Err(StdError::generic_err(format!(
+                "LTV {} above the offer's max {}",
+                health.ltv, offer.max_ltv
+            )));
+        }
+
+        add_isolated_debt(deps.storage, &position, interest)?;
+        update_market_stats(deps.storage, &position.borrow_token, |stats| {
+            stats.total_deposited = stats.total_deposited.checked_sub(payoff)?;
+            stats.total_lent += interest;
+            stats.total_borrowed += interest;
+            Ok(())
+        })?;
+        messages.extend(mint_lender_notes(
+            deps.storage,
+            position_id.u128(),
+            &mut position,
+        )?);
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
 
         Ok(Response::new()
-            .add_message(withdraw_msg)
-            .add_attribute("action", "withdraw")
-            .add_attribute("user", info.sender.to_string())
-            .add_attribute("amount", amount.to_string()))
+            .add_messages(messages)
+            .add_attribute("action", "refinance")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", lender.to_string())
+            .add_attribute("nonce", offer.nonce.to_string())
+            .add_attribute("amount", payoff.to_string())
+            .add_attribute("interest_rate", offer.interest_rate.to_string()))
     }
 
-    /// Liquidate an undercollateralized position
-    pub fn execute_liquidate(
+    /// Pay off the lenders of a running loan with the sender's funds and become its only
+    /// lender at a lower rate. The accrued interest is capitalized and the maturity is kept,
+    /// so the borrower's terms only improve and their consent is not needed.
+    pub fn execute_take_over_loan(
         deps: DepsMut,
         env: Env,
         info: MessageInfo,
         position_id: Uint128,
+        interest_rate: Uint128,
     ) -> StdResult<Response> {
-        let config = CONFIG.load(deps.storage)?;
-        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
         if !position.filled {
             return Err(StdError::generic_err("Position not filled"));
         }
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        if let Some(allowed_lenders) = &position.allowed_lenders {
+            if !allowed_lenders.contains(&info.sender) {
+                return Err(StdError::generic_err("Not an allowed lender"));
+            }
+        }
+        if interest_rate >= position.interest_rate {
+            return Err(StdError::generic_err(
+                "Refinance rate must be below the current rate",
+            ));
+        }
 
-        // Query collateral price from oracle
-        let token_str = match &position.collateral_token {
-            Token::Native(denom) => denom.clone(),
-            Token::Cw20(addr) => addr.to_string(),
-        };
-        let price: Uint128 = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-            contract_addr: config.mock_oracle.to_string(),
-            msg: to_json_binary(&encke_oracle::QueryMsg::GetPrice {
-                token: token_str.clone(),
-            })?,
-        }))?;
-        let collateral_value = position.collateral * price;
-        let time_elapsed = env.block.time.seconds() - position.start_time;
-        let interest = position.amount * position.interest_rate * Uint128::from(time_elapsed)
-            / Uint128::from(31_536_000u64 * 100u64);
-        let total_debt = position.amount + interest;
+        let now = env.block.time.seconds();
+        let interest = accrued_interest(&position, now);
+        let payoff = position.amount + interest;
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &token_to_string(&position.borrow_token))?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token_to_string(&position.borrow_token))?
+            .unwrap_or_default();
+        check_cap("Borrow", caps.borrow_cap, stats.total_borrowed, interest)?;
 
-        // Check liquidation condition
-        if collateral_value >= total_debt * config.liquidation_threshold / Uint128::from(100u128) {
-            return Err(StdError::generic_err("Position not undercollateralized"));
+        // The new lender pays the current lenders directly, native excess is refunded
+        let mut messages = lender_payments(
+            &deps.as_ref(),
+            position_id.u128(),
+            &position,
+            payoff,
+            Some(&info.sender),
+        )?;
+        if let Token::Native(denom) = &position.borrow_token {
+            verify_funds(&info.funds, denom, payoff)?;
+            let sent = info
+                .funds
+                .iter()
+                .find(|c| c.denom == *denom)
+                .map(|c| c.amount)
+                .unwrap_or(Uint128::zero());
+            if sent > payoff {
+                messages.push(transfer_msg(
+                    &position.borrow_token,
+                    &info.sender,
+                    sent - payoff,
+                )?);
+            }
         }
 
-        // Calculate shares
-        let liquidator_share = total_debt * Uint128::from(90u128) / Uint128::from(100u128);
-        let lender_share = total_debt - liquidator_share;
-        let collateral_to_liquidator =
-            position.collateral * Uint128::from(95u128) / Uint128::from(100u128);
+        // The accrued interest is capitalized, so the new principal must stay healthy
+        position.lenders = vec![(info.sender.clone(), payoff)];
+        position.amount = payoff;
+        position.interest_rate = interest_rate;
+        position.start_time = now;
+        position.refinance_count += 1;
+        EXTENSION_PROPOSALS.remove(deps.storage, position_id.u128());
+        let config = CONFIG.load(deps.storage)?;
+        if position_health(&deps.as_ref(), &config, &position, now)?.liquidatable {
+            return Err(StdError::generic_err(
+                "Refinanced loan would be undercollateralized",
+            ));
+        }
 
-        // Transfer debt repayment and collateral
-        let (liquidator_payment_msg, lender_repayment_msg) = match &position.borrow_token {
-            Token::Native(denom) => {
-                verify_funds(&info.funds, denom, total_debt)?;
-                (
-                    BankMsg::Send {
-                        to_address: env.contract.address.to_string(),
-                        amount: vec![Coin {
-                            denom: denom.clone(),
-                            amount: total_debt,
-                        }],
-                    }
-                    .into(),
-                    BankMsg::Send {
-                        to_address: position.lender.clone().unwrap().to_string(),
-                        amount: vec![Coin {
-                            denom: denom.clone(),
-                            amount: lender_share,
-                        }],
-                    }
-                    .into(),
-                )
-            }
-            Token::Cw20(addr) => (
-                CosmosMsg::Wasm(WasmMsg::Execute {
-                    contract_addr: addr.to_string(),
-                    msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
-                        owner: info.sender.to_string(),
-                        recipient: env.contract.address.to_string(),
-                        amount: total_debt,
-                    })?,
-                    funds: vec![],
-                }),
-                CosmosMsg::Wasm(WasmMsg::Execute {
-                    contract_addr: addr.to_string(),
-                    msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
-                        recipient: position.lender.clone().unwrap().to_string(),
-                        amount: lender_share,
-                    })?,
-                    funds: vec![],
-                }),
-            ),
+        add_isolated_debt(deps.storage, &position, interest)?;
+        update_market_stats(deps.storage, &position.borrow_token, |stats| {
+            stats.total_lent += interest;
+            stats.total_borrowed += interest;
+            Ok(())
+        })?;
+        messages.extend(mint_lender_notes(
+            deps.storage,
+            position_id.u128(),
+            &mut position,
+        )?);
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+
+        Ok(Response::new()
+            .add_messages(messages)
+            .add_attribute("action", "take_over_loan")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("amount", payoff.to_string())
+            .add_attribute("interest_rate", interest_rate.to_string()))
+    }
+
+    /// Propose new terms for a term loan, or approve the pending proposal when the terms
+    /// match. A loan with several lenders is extended once all of them approved, and a
+    /// proposal cannot be replaced once another lender approved it.
+    pub fn execute_propose_extension(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        maturity: u64,
+        interest_rate: Option<Uint128>,
+        capitalize_interest: bool,
+    ) -> StdResult<Response> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        let Some(current_maturity) = position.maturity else {
+            return Err(StdError::generic_err("Loan has no maturity"));
         };
+        let holders = lender_holders(&deps.as_ref(), position_id.u128(), &position)?;
+        if !holders.iter().any(|(holder, _)| *holder == info.sender) {
+            return Err(StdError::generic_err("Not lender"));
+        }
+        if maturity <= current_maturity.max(env.block.time.seconds()) {
+            return Err(StdError::generic_err(
+                "Maturity must be after the current maturity",
+            ));
+        }
 
-        let liquidator_collateral_msg = match &position.collateral_token {
-            Token::Native(denom) => BankMsg::Send {
-                to_address: info.sender.to_string(),
-                amount: vec![Coin {
-                    denom: denom.clone(),
-                    amount: collateral_to_liquidator,
-                }],
+        let proposal = match EXTENSION_PROPOSALS.may_load(deps.storage, position_id.u128())? {
+            Some(mut proposal)
+                if proposal.maturity == maturity
+                    && proposal.interest_rate == interest_rate
+                    && proposal.capitalize_interest == capitalize_interest =>
+            {
+                if !proposal.approvals.contains(&info.sender) {
+                    proposal.approvals.push(info.sender.clone());
+                }
+                proposal
             }
-            .into(),
-            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
-                contract_addr: addr.to_string(),
-                msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
-                    recipient: info.sender.to_string(),
-                    amount: collateral_to_liquidator,
-                })?,
-                funds: vec![],
-            }),
+            // Only the lone approver may revise a proposal, so others' approvals are never lost
+            Some(proposal) if proposal.approvals != [info.sender.clone()] => {
+                return Err(StdError::generic_err(
+                    "Another extension proposal is pending",
+                ));
+            }
+            _ => ExtensionProposal {
+                maturity,
+                interest_rate,
+                capitalize_interest,
+                approvals: vec![info.sender.clone()],
+            },
         };
+        EXTENSION_PROPOSALS.save(deps.storage, position_id.u128(), &proposal)?;
 
-        POSITIONS.remove(deps.storage, position_id.u128());
         Ok(Response::new()
-            .add_messages(vec![
-                liquidator_payment_msg,
-                lender_repayment_msg,
-                liquidator_collateral_msg,
-            ])
-            .add_attribute("action", "liquidate")
-            .add_attribute("position_id", position_id.to_string()))
+            .add_attribute("action", "propose_extension")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("maturity", maturity.to_string())
+            .add_attribute("approvals", proposal.approvals.len().to_string()))
     }
 
-    // Helper functions
-
-    /// Determine token type from string
-    pub fn determine_token_type(deps: &Deps, token: &str) -> StdResult<Token> {
-        if deps.api.addr_validate(token).is_ok() {
-            Ok(Token::Cw20(deps.api.addr_validate(token)?))
-        } else {
-            Ok(Token::Native(token.to_string()))
+    /// Extend a term loan on the terms all its lenders approved. Accrued interest is paid
+    /// to the lenders, or added to the principal when the proposal capitalizes it.
+    pub fn execute_accept_extension(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+    ) -> StdResult<Response> {
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if position.borrower != info.sender {
+            return Err(StdError::generic_err("Not borrower"));
         }
-    }
-
-    /// Verify sufficient funds for native token transfers
-    fn verify_funds(funds: &[Coin], denom: &str, amount: Uint128) -> StdResult<()> {
-        let sent = funds
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        let proposal = EXTENSION_PROPOSALS
+            .may_load(deps.storage, position_id.u128())?
+            .ok_or_else(|| StdError::generic_err("No extension proposed"))?;
+        let holders = lender_holders(&deps.as_ref(), position_id.u128(), &position)?;
+        if !holders
             .iter()
-            .find(|c| c.denom == denom)
-            .map(|c| c.amount)
-            .unwrap_or(Uint128::zero());
-        if sent < amount {
-            Err(StdError::generic_err("Insufficient funds"))
-        } else {
-            Ok(())
+            .all(|(holder, _)| proposal.approvals.contains(holder))
+        {
+            return Err(StdError::generic_err(
+                "Extension not approved by all lenders",
+            ));
         }
-    }
-}
+        let previous_maturity = position
+            .maturity
+            .ok_or_else(|| StdError::generic_err("Loan has no maturity"))?;
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetTokenConfigs {} => to_json_binary(&query_token_configs(deps)?),
-        QueryMsg::GetUserInfo { address } => to_json_binary(&query_user_info(deps, address)?),
-        QueryMsg::GetPosition { position_id } => {
-            to_json_binary(&query_position(deps, position_id)?)
+        let interest = accrued_interest(&position, env.block.time.seconds());
+        let mut messages = vec![];
+        if proposal.capitalize_interest {
+            add_isolated_debt(deps.storage, &position, interest)?;
+            update_market_stats(deps.storage, &position.borrow_token, |stats| {
+                stats.total_lent += interest;
+                stats.total_borrowed += interest;
+                Ok(())
+            })?;
+            let amount = position.amount;
+            for (_, share) in position.lenders.iter_mut() {
+                *share += interest.multiply_ratio(*share, amount);
+            }
+            position.amount += interest;
+            // Rounding remainder goes to the last lender
+            let funded = funded_amount(&position);
+            if let Some((_, share)) = position.lenders.last_mut() {
+                *share += position.amount.checked_sub(funded)?;
+            }
+        } else if !interest.is_zero() {
+            if let Token::Native(denom) = &position.borrow_token {
+                verify_funds(&info.funds, denom, interest)?;
+            }
+            messages = lender_payments(
+                &deps.as_ref(),
+                position_id.u128(),
+                &position,
+                interest,
+                Some(&info.sender),
+            )?;
         }
-        QueryMsg::GetAllPositions { start_after, limit } => {
-            to_json_binary(&query_all_positions(deps, start_after, limit)?)
+        position.start_time = env.block.time.seconds();
+        position.maturity = Some(proposal.maturity);
+        if let Some(interest_rate) = proposal.interest_rate {
+            position.interest_rate = interest_rate;
         }
-        QueryMsg::GetConfig {} => to_json_binary(&query_config(deps)?),
-    }
-}
-
-pub mod query {
-    use cosmwasm_std::StdError;
-    use cw_storage_plus::Bound;
-
-    use crate::{
-        msg::{
-            ConfigResponse, PositionResponse, PositionsResponse, TokenConfig, TokenConfigsResponse,
-            UserInfo, UserInfoResponse,
-        },
-        state::{Deposit, Position, DEPOSITS, POSITIONS},
-    };
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+        EXTENSION_PROPOSALS.remove(deps.storage, position_id.u128());
 
-    use super::{execute::determine_token_type, *};
+        let mut history = POSITION_HISTORY
+            .may_load(deps.storage, position_id.u128())?
+            .unwrap_or_default();
+        history.push(PositionEvent::Extended {
+            timestamp: env.block.time.seconds(),
+            previous_maturity,
+            maturity: proposal.maturity,
+            interest_rate: position.interest_rate,
+            interest,
+            capitalized: proposal.capitalize_interest,
+        });
+        POSITION_HISTORY.save(deps.storage, position_id.u128(), &history)?;
 
-    /// Query supported tokens
-    pub fn query_token_configs(deps: Deps) -> StdResult<TokenConfigsResponse> {
-        let tokens: Vec<TokenConfig> = SUPPORTED_TOKENS
-            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
-            .map(|item: Result<(String, bool), StdError>| {
-                let (token_str, is_supported) = item?;
-                let token = determine_token_type(&deps, &token_str)?;
-                Ok(TokenConfig { token, is_supported })
-            })
-            .collect::<StdResult<Vec<_>>>()?;
-        Ok(TokenConfigsResponse { tokens })
+        Ok(Response::new()
+            .add_messages(messages)
+            .add_attribute("action", "accept_extension")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("maturity", proposal.maturity.to_string())
+            .add_attribute("interest", interest.to_string())
+            .add_attribute("capitalized", proposal.capitalize_interest.to_string()))
     }
 
-    /// Query user deposits and positions
-    pub fn query_user_info(deps: Deps, address: String) -> StdResult<UserInfoResponse> {
-        let addr = deps.api.addr_validate(&address)?;
-        let deposits: Vec<Deposit> = DEPOSITS
-            .prefix(&addr)
-            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
-            .map(|item: Result<(String, Uint128), StdError>| {
-                let (token_str, amount) = item?;
-                let token = determine_token_type(&deps, &token_str)?;
-                Ok(Deposit { token, amount })
-            })
-            .collect::<StdResult<Vec<_>>>()?;
-        let positions: Vec<(Uint128, Position)> = POSITIONS
-            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
-            .filter_map(|item| {
-                let (id, pos) = item.ok()?;
-                if pos.borrower == addr || pos.lender == Some(addr.clone()) {
-                    Some(Ok((id, pos)))
-                } else {
-                    None
-                }
-            })
-            .collect()?;
-    
-        Ok(UserInfoResponse {
-            user_info: if deposits.is_empty() && positions.is_empty() {
-                None
-            } else {
-                Some(UserInfo { deposits, positions })
-            },
-        })
+    /// Register the public key the sender signs lend offers with
+    pub fn execute_register_offer_key(
+        deps: DepsMut,
+        info: MessageInfo,
+        public_key: Binary,
+    ) -> StdResult<Response> {
+        if public_key.len() != 33 {
+            return Err(StdError::generic_err(
+                "Public key must be a compressed secp256k1 key",
+            ));
+        }
+        OFFER_KEYS.save(deps.storage, &info.sender, &public_key)?;
+        Ok(Response::new()
+            .add_attribute("action", "register_offer_key")
+            .add_attribute("lender", info.sender.to_string()))
     }
 
-    /// Query a specific position
-    pub fn query_position(deps: Deps, position_id: Uint128) -> StdResult<PositionResponse> {
-        let position = POSITIONS.load(deps.storage, position_id.u128())?;
-        Ok(PositionResponse { position })
+    /// Revoke signed lend offers of the sender
+    pub fn execute_cancel_offers(
+        deps: DepsMut,
+        info: MessageInfo,
+        nonces: Vec<u64>,
+    ) -> StdResult<Response> {
+        for nonce in &nonces {
+            CANCELLED_NONCES.save(deps.storage, (&info.sender, *nonce), &Empty {})?;
+        }
+        Ok(Response::new()
+            .add_attribute("action", "cancel_offers")
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("count", nonces.len().to_string()))
     }
 
-    /// Query all positions with pagination
-    pub fn query_all_positions(
-        deps: Deps,
-        start_after: Option<Uint128>,
-        limit: Option<u32>,
-    ) -> StdResult<PositionsResponse> {
-        let limit = limit.unwrap_or(100).min(100) as usize;
-        let start = start_after.map(Bound::exclusive);
+    /// Open a loan from a lend offer signed off-chain. The principal is taken from the
+    /// lender's deposit and the loan starts right away.
+    pub fn execute_take_signed_offer(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        offer: LendOffer,
+        signature: Binary,
+        collateral: Vec<(String, Uint128)>,
+    ) -> StdResult<Response> {
+        let lender = consume_signed_offer(deps.branch(), &env, &offer, &signature)?;
 
-        let positions = POSITIONS
-            .range(deps.storage, start, None, cosmwasm_std::Order::Ascending)
-            .take(limit)
-            .collect::<StdResult<Vec<_>>>()?;
-        Ok(PositionsResponse { positions })
-    }
+        // Debit the lender's deposit
+        let deposit = deposit_balance(deps.storage, &lender, &offer.token)?;
+        if deposit < offer.amount {
+            return Err(StdError::generic_err("Insufficient lender deposit"));
+        }
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &offer.token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &offer.token)?
+            .unwrap_or_default();
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < offer.amount {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+        check_cap(
+            "Borrow",
+            caps.borrow_cap,
+            stats.total_borrowed,
+            offer.amount,
+        )?;
+        save_deposit(deps.storage, &lender, &offer.token, deposit - offer.amount)?;
 
-    /// Query a protocol config
-    pub fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+        // Open the request on the offer's terms and fill it with the deposit
+        let response = execute_borrow(
+            deps.branch(),
+            env.clone(),
+            info,
+            offer.token.clone(),
+            offer.amount,
+            offer.interest_rate,
+            collateral,
+            offer.term,
+            None,
+            None,
+            None,
+        )?;
+        let position_id = POSITION_COUNTER.load(deps.storage)?.u128();
+        let mut position = POSITIONS.load(deps.storage, position_id)?;
         let config = CONFIG.load(deps.storage)?;
-        Ok(ConfigResponse { config })
+        let health = position_health(&deps.as_ref(), &config, &position, env.block.time.seconds())?;
+        if health.ltv > offer.max_ltv {
+            return Err(StdError::generic_err(format!(
+                "LTV {} above the offer's max {}",
+                health.ltv, offer.max_ltv
+            )));
+        }
+        update_market_stats(deps.storage, &position.borrow_token, |stats| {
+            stats.total_deposited = stats.total_deposited.checked_sub(offer.amount)?;
+            Ok(())
+        })?;
+        position.lenders = vec![(lender.clone(), offer.amount)];
+        let messages = activate_position(deps.branch(), &env, position_id, &mut position)?;
+        POSITIONS.save(deps.storage, position_id, &position)?;
+
+        Ok(response
+            .add_messages(messages)
+            .add_attribute("signed_offer", "true")
+            .add_attribute("lender", lender.to_string())
+            .add_attribute("nonce", offer.nonce.to_string()))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Check a signed lend offer is valid here and now and mark its nonce used, returning
+    /// the lender
+    fn consume_signed_offer(
+        deps: DepsMut,
+        env: &Env,
+        offer: &LendOffer,
+        signature: &Binary,
+    ) -> StdResult<Addr> {
+        let lender = deps.api.addr_validate(&offer.lender)?;
+        if offer.chain_id != env.block.chain_id || offer.contract != env.contract.address.as_str() {
+            return Err(StdError::generic_err("Offer not valid on this contract"));
+        }
+        if env.block.time.seconds() >= offer.expiry {
+            return Err(StdError::generic_err("Offer expired"));
+        }
+        if USED_NONCES.has(deps.storage, (&lender, offer.nonce))
+            || CANCELLED_NONCES.has(deps.storage, (&lender, offer.nonce))
+        {
+            return Err(StdError::generic_err("Offer already taken or cancelled"));
+        }
+        let public_key = OFFER_KEYS
+            .may_load(deps.storage, &lender)?
+            .ok_or_else(|| StdError::generic_err("Lender has no offer key"))?;
+        let hash = Sha256::digest(to_json_vec(offer)?);
+        if !deps
+            .api
+            .secp256k1_verify(&hash, signature, &public_key)
+            .map_err(|e| StdError::generic_err(e.to_string()))?
+        {
+            return Err(StdError::generic_err("Invalid offer signature"));
+        }
+        USED_NONCES.save(deps.storage, (&lender, offer.nonce), &Empty {})?;
+        Ok(lender)
+    }
+
+    /// Take back the sender's funding of a request that has not started yet
+    pub fn execute_cancel_fill(
+        deps: DepsMut,
+        info: MessageInfo,
+        position_id: Uint128,
+    ) -> StdResult<Response> {
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if position.filled {
+            return Err(StdError::generic_err("Position already filled"));
+        }
+        let index = position
+            .lenders
+            .iter()
+            .position(|(lender, _)| *lender == info.sender)
+            .ok_or_else(|| StdError::generic_err("No funding to cancel"))?;
+        let (_, amount) = position.lenders.remove(index);
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+
+        Ok(Response::new()
+            .add_message(transfer_msg(&position.borrow_token, &info.sender, amount)?)
+            .add_attribute("action", "cancel_fill")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Offer other terms on an open request, escrowing the offered principal
+    pub fn execute_counter_offer(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        amount: Uint128,
+        interest_rate: Uint128,
+        term: Option<u64>,
+    ) -> StdResult<Response> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if position.filled {
+            return Err(StdError::generic_err("Position already filled"));
+        }
+        if let Some(allowed_lenders) = &position.allowed_lenders {
+            if !allowed_lenders.contains(&info.sender) {
+                return Err(StdError::generic_err("Not an allowed lender"));
+            }
+        }
+        // Collateral was checked against the requested amount
+        if amount.is_zero() || amount > position.amount {
+            return Err(StdError::generic_err(format!(
+                "Amount mismatch: at most {} can be offered",
+                position.amount
+            )));
+        }
+        if term == Some(0) {
+            return Err(StdError::generic_err("Term must be positive"));
+        }
+        let key = (position_id.u128(), &info.sender);
+        if COUNTER_OFFERS.has(deps.storage, key) {
+            return Err(StdError::generic_err("Counter-offer already posted"));
+        }
+        COUNTER_OFFERS.save(
+            deps.storage,
+            key,
+            &CounterOffer {
+                token: position.borrow_token.clone(),
+                amount,
+                interest_rate,
+                term,
+            },
+        )?;
+
+        Ok(Response::new()
+            .add_messages(receive_msg(&env, &info, &position.borrow_token, amount)?)
+            .add_attribute("action", "counter_offer")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("amount", amount.to_string())
+            .add_attribute("interest_rate", interest_rate.to_string()))
+    }
+
+    /// Take back the sender's counter-offer, at any time before it is accepted
+    pub fn execute_withdraw_counter_offer(
+        deps: DepsMut,
+        info: MessageInfo,
+        position_id: Uint128,
+    ) -> StdResult<Response> {
+        let key = (position_id.u128(), &info.sender);
+        let offer = COUNTER_OFFERS
+            .may_load(deps.storage, key)?
+            .ok_or_else(|| StdError::generic_err("No counter-offer to withdraw"))?;
+        COUNTER_OFFERS.remove(deps.storage, key);
+
+        Ok(Response::new()
+            .add_message(transfer_msg(&offer.token, &info.sender, offer.amount)?)
+            .add_attribute("action", "withdraw_counter_offer")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", info.sender.to_string())
+            .add_attribute("amount", offer.amount.to_string()))
+    }
+
+    /// Start a loan on a lender's counter-offer. Fills and every other counter-offer on
+    /// the request are refunded.
+    pub fn execute_accept_counter_offer(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        lender: String,
+    ) -> StdResult<Response> {
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if position.borrower != info.sender {
+            return Err(StdError::generic_err("Not borrower"));
+        }
+        if position.filled {
+            return Err(StdError::generic_err("Position already filled"));
+        }
+        let lender = deps.api.addr_validate(&lender)?;
+        let offer = COUNTER_OFFERS
+            .may_load(deps.storage, (position_id.u128(), &lender))?
+            .ok_or_else(|| StdError::generic_err("No counter-offer from this lender"))?;
+        let key = token_to_string(&position.borrow_token);
+        let caps = TOKEN_CAPS.may_load(deps.storage, &key)?.unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &key)?
+            .unwrap_or_default();
+        check_cap(
+            "Borrow",
+            caps.borrow_cap,
+            stats.total_borrowed,
+            offer.amount,
+        )?;
+        check_cap(
+            "Supply",
+            caps.supply_cap,
+            stats.total_deposited + stats.total_lent,
+            offer.amount,
+        )?;
+
+        // Refund fills and the other counter-offers
+        let mut messages = vec![];
+        for (funder, amount) in &position.lenders {
+            messages.push(transfer_msg(&position.borrow_token, funder, *amount)?);
+        }
+        let others = COUNTER_OFFERS
+            .prefix(position_id.u128())
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        for (other, other_offer) in others {
+            COUNTER_OFFERS.remove(deps.storage, (position_id.u128(), &other));
+            if other != lender {
+                messages.push(transfer_msg(
+                    &position.borrow_token,
+                    &other,
+                    other_offer.amount,
+                )?);
+            }
+        }
+
+        // The order book is keyed by the requested rate
+        unindex_open_request(deps.storage, position_id.u128())?;
+        position.lenders = vec![(lender.clone(), offer.amount)];
+        position.interest_rate = offer.interest_rate;
+        position.term = offer.term;
+        messages.extend(activate_position(
+            deps.branch(),
+            &env,
+            position_id.u128(),
+            &mut position,
+        )?);
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+
+        Ok(Response::new()
+            .add_messages(messages)
+            .add_attribute("action", "accept_counter_offer")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("lender", lender.to_string())
+            .add_attribute("amount", offer.amount.to_string())
+            .add_attribute("interest_rate", offer.interest_rate.to_string()))
+    }
+
+    /// Let an address manage the collateral of the sender's positions
+    pub fn execute_approve_operator(
+        deps: DepsMut,
+        info: MessageInfo,
+        operator: String,
+    ) -> StdResult<Response> {
+        let operator = deps.api.addr_validate(&operator)?;
+        OPERATORS.save(deps.storage, (&info.sender, &operator), &Empty {})?;
+        Ok(Response::new()
+            .add_attribute("action", "approve_operator")
+            .add_attribute("borrower", info.sender.to_string())
+            .add_attribute("operator", operator.to_string()))
+    }
+
+    /// Remove an operator of the sender's positions
+    pub fn execute_revoke_operator(
+        deps: DepsMut,
+        info: MessageInfo,
+        operator: String,
+    ) -> StdResult<Response> {
+        let operator = deps.api.addr_validate(&operator)?;
+        OPERATORS.remove(deps.storage, (&info.sender, &operator));
+        Ok(Response::new()
+            .add_attribute("action", "revoke_operator")
+            .add_attribute("borrower", info.sender.to_string())
+            .add_attribute("operator", operator.to_string()))
+    }
+
+    /// Add collateral to a position, as a new basket entry or on top of an existing one
+    pub fn execute_add_collateral(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        token: String,
+        amount: Uint128,
+        source: Option<CollateralSource>,
+    ) -> StdResult<Response> {
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        ensure_position_manager(deps.storage, &position, &info.sender)?;
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        if amount.is_zero() {
+            return Err(StdError::generic_err("Amount must be positive"));
+        }
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        check_cap(
+            "Collateral",
+            caps.collateral_cap,
+            stats.total_collateral,
+            amount,
+        )?;
+
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let index = position
+            .collateral
+            .iter()
+            .position(|(t, _)| *t == token_type);
+        if index.is_none() {
+            // Isolated collateral must back the position alone
+            let isolated = ISOLATED_TOKENS.has(deps.storage, &token)
+                || position
+                    .collateral
+                    .iter()
+                    .any(|(t, _)| ISOLATED_TOKENS.has(deps.storage, &token_to_string(t)));
+            if isolated {
+                return Err(StdError::generic_err(
+                    "Isolated collateral cannot be combined with other collateral",
+                ));
+            }
+        }
+
+        let public_request = !position.filled && position.allowed_lenders.is_none();
+        if public_request {
+            unindex_open_request(deps.storage, position_id.u128())?;
+        }
+        // A loan moving onto a basket stops counting against its single token's debt
+        if position.filled {
+            release_isolated_debt(deps.storage, &position, position.amount)?;
+        }
+        match index {
+            Some(index) => position.collateral[index].1 += amount,
+            None => position.collateral.push((token_type.clone(), amount)),
+        }
+        if position.filled {
+            add_isolated_debt(deps.storage, &position, position.amount)?;
+        }
+        if public_request {
+            index_open_request(deps.branch(), position_id.u128(), &position)?;
+        }
+        update_market_stats(deps.storage, &token_type, |stats| {
+            stats.total_collateral += amount;
+            Ok(())
+        })?;
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+        let receive_msg = receive_collateral(
+            deps.storage,
+            &env,
+            &info,
+            &token_type,
+            amount,
+            &source.unwrap_or(CollateralSource::Wallet),
+        )?;
+
+        Ok(Response::new()
+            .add_messages(receive_msg)
+            .add_attribute("action", "add_collateral")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("token", token)
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Return collateral of a position to its borrower, keeping the LTV under its max
+    pub fn execute_withdraw_collateral(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        ensure_position_manager(deps.storage, &position, &info.sender)?;
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let index = collateral_index(&position, &token_type)?;
+        if amount.is_zero() || amount > position.collateral[index].1 {
+            return Err(StdError::generic_err("Invalid collateral amount"));
+        }
+
+        let public_request = !position.filled && position.allowed_lenders.is_none();
+        if public_request {
+            unindex_open_request(deps.storage, position_id.u128())?;
+        }
+        if amount == position.collateral[index].1 {
+            take_collateral(deps.storage, &mut position, index)?;
+        } else {
+            position.collateral[index].1 -= amount;
+            update_market_stats(deps.storage, &token_type, |stats| {
+                stats.total_collateral = stats.total_collateral.checked_sub(amount)?;
+                Ok(())
+            })?;
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let health = position_health(&deps.as_ref(), &config, &position, env.block.time.seconds())?;
+        if health.ltv > health.max_ltv {
+            return Err(StdError::generic_err(format!(
+                "Insufficient collateral: LTV {} above max {}",
+                health.ltv, health.max_ltv
+            )));
+        }
+        if public_request {
+            index_open_request(deps.branch(), position_id.u128(), &position)?;
+        }
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+
+        Ok(Response::new()
+            .add_message(transfer_msg(&token_type, &position.borrower, amount)?)
+            .add_attribute("action", "withdraw_collateral")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("token", token)
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Repay a filled position on behalf of its borrower, who gets the collateral back
+    pub fn execute_repay(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+    ) -> StdResult<Response> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+
+        let interest = accrued_interest(&position, env.block.time.seconds());
+        let total_repayment = position.amount + interest;
+
+        // Transfer repayment to the lenders
+        if let Token::Native(denom) = &position.borrow_token {
+            verify_funds(&info.funds, denom, total_repayment)?;
+        }
+        let repay_msgs = lender_payments(
+            &deps.as_ref(),
+            position_id.u128(),
+            &position,
+            total_repayment,
+            Some(&info.sender),
+        )?;
+
+        // Return collateral to borrower
+        let mut collateral_msgs = vec![];
+        for (token, amount) in &position.collateral {
+            collateral_msgs.extend(return_collateral(deps.storage, &position, token, *amount)?);
+        }
+
+        POSITIONS.remove(deps.storage, position_id.u128());
+        EXTENSION_PROPOSALS.remove(deps.storage, position_id.u128());
+        close_position_stats(deps.storage, &position)?;
+        Ok(Response::new()
+            .add_messages(repay_msgs)
+            .add_messages(collateral_msgs)
+            .add_attribute("action", "repay")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("payer", info.sender.to_string()))
+    }
+
+    /// Withdraw deposited tokens from the contract
+    pub fn execute_withdraw(
+        deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let current = deposit_balance(deps.storage, &info.sender, &token)?;
+        if current.is_zero() || current < amount {
+            return Err(StdError::generic_err("Insufficient deposit"));
+        }
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < amount {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+
+        // Transfer tokens back to user
+        let withdraw_msg = match token_type.clone() {
+            Token::Native(denom) => BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin { denom, amount }],
+            }
+            .into(),
+            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: addr.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: info.sender.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            }),
+        };
+
+        // Update deposit balance
+        save_deposit(deps.storage, &info.sender, &token, current - amount)?;
+        update_market_stats(deps.storage, &token_type, |stats| {
+            stats.total_deposited = stats.total_deposited.checked_sub(amount)?;
+            Ok(())
+        })?;
+
+        Ok(Response::new()
+            .add_message(withdraw_msg)
+            .add_attribute("action", "withdraw")
+            .add_attribute("user", info.sender.to_string())
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Liquidate an undercollateralized position, receiving one of its collateral tokens.
+    /// The liquidator covers the share of the debt backed by that token and the position
+    /// stays open on the remaining collateral, if any.
+    pub fn execute_liquidate(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        collateral_token: String,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if config.auction.is_some() {
+            return Err(StdError::generic_err(
+                "Liquidations run as auctions, use StartAuction",
+            ));
+        }
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        // An auction started before they were disabled runs to the end
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        let collateral_token = determine_token_type(&deps.as_ref(), &collateral_token)?;
+        let index = collateral_index(&position, &collateral_token)?;
+
+        // Check liquidation condition against oracle prices
+        let now = env.block.time.seconds();
+        let health = position_health(&deps.as_ref(), &config, &position, now)?;
+        if !health.liquidatable {
+            return Err(StdError::generic_err("Position not undercollateralized"));
+        }
+        let amounts = liquidation_amounts(&position, &health, index, config.liquidation_fee)?;
+        let total_debt = amounts.repay_amount;
+        let collateral_to_liquidator = amounts.collateral_payout;
+
+        // Keep the liquidation fee and cover any shortfall from the insurance fund
+        add_reserves(deps.storage, &position.borrow_token, amounts.protocol_share)?;
+        let covered = cover_shortfall(
+            deps.storage,
+            &env,
+            position_id.u128(),
+            &position.borrow_token,
+            amounts.shortfall,
+        )?;
+        let lender_share = amounts.lender_share + covered;
+
+        // Transfer debt repayment and collateral, refunding native funds sent above the debt
+        let liquidator_payment_msg: Option<CosmosMsg> = match &position.borrow_token {
+            Token::Native(denom) => {
+                verify_funds(&info.funds, denom, total_debt)?;
+                let sent = info
+                    .funds
+                    .iter()
+                    .find(|c| c.denom == *denom)
+                    .map(|c| c.amount)
+                    .unwrap_or(Uint128::zero());
+                if sent > total_debt {
+                    Some(transfer_msg(
+                        &position.borrow_token,
+                        &info.sender,
+                        sent - total_debt,
+                    )?)
+                } else {
+                    None
+                }
+            }
+            Token::Cw20(addr) => Some(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: addr.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+                    owner: info.sender.to_string(),
+                    recipient: env.contract.address.to_string(),
+                    amount: total_debt,
+                })?,
+                funds: vec![],
+            })),
+        };
+        let lender_repayment_msgs = lender_payments(
+            &deps.as_ref(),
+            position_id.u128(),
+            &position,
+            lender_share,
+            None,
+        )?;
+        let liquidator_collateral_msg =
+            transfer_msg(&collateral_token, &info.sender, collateral_to_liquidator)?;
+        let borrower_collateral_msg = if amounts.collateral_returned.is_zero() {
+            None
+        } else {
+            Some(transfer_msg(
+                &collateral_token,
+                &position.borrower,
+                amounts.collateral_returned,
+            )?)
+        };
+
+        if position.collateral.len() == 1 {
+            POSITIONS.remove(deps.storage, position_id.u128());
+            EXTENSION_PROPOSALS.remove(deps.storage, position_id.u128());
+            close_position_stats(deps.storage, &position)?;
+        } else {
+            take_collateral(deps.storage, &mut position, index)?;
+            reduce_debt(
+                deps.storage,
+                &mut position,
+                health.total_debt - amounts.debt_covered,
+                now,
+            )?;
+            POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+        }
+        Ok(Response::new()
+            .add_messages(liquidator_payment_msg)
+            .add_messages(lender_repayment_msgs)
+            .add_message(liquidator_collateral_msg)
+            .add_messages(borrower_collateral_msg)
+            .add_attribute("action", "liquidate")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("collateral_token", token_to_string(&collateral_token))
+            .add_attribute("shortfall", amounts.shortfall.to_string())
+            .add_attribute("insurance_cover", covered.to_string()))
+    }
+
+    /// Lend contract-held tokens to the sender for the duration of its callback
+    pub fn execute_flash_loan(
+        deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+        callback: Binary,
+    ) -> StdResult<Response> {
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        if FLASH_LOAN.exists(deps.storage) {
+            return Err(StdError::generic_err("Flash loan already in progress"));
+        }
+
+        // Only idle deposits can be lent, never collateral, reserves or insurance funds
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < amount {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+
+        let config = CONFIG.load(deps.storage)?;
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let fee = amount * config.flash_loan_fee / Uint128::from(10_000u128);
+        FLASH_LOAN.save(
+            deps.storage,
+            &FlashLoan {
+                receiver: info.sender.clone(),
+                token: token_type.clone(),
+                amount,
+                fee,
+                repaid: false,
+            },
+        )?;
+
+        // Send the loan along with the callback, then check repayment in the reply
+        let mut response = Response::new();
+        let funds = match &token_type {
+            Token::Native(denom) => vec![Coin {
+                denom: denom.clone(),
+                amount,
+            }],
+            Token::Cw20(_) => {
+                response = response.add_message(transfer_msg(&token_type, &info.sender, amount)?);
+                vec![]
+            }
+        };
+        let callback_msg = WasmMsg::Execute {
+            contract_addr: info.sender.to_string(),
+            msg: callback,
+            funds,
+        };
+
+        Ok(response
+            .add_submessage(SubMsg::reply_on_success(callback_msg, FLASH_LOAN_REPLY_ID))
+            .add_attribute("action", "flash_loan")
+            .add_attribute("receiver", info.sender.to_string())
+            .add_attribute("amount", amount.to_string())
+            .add_attribute("fee", fee.to_string()))
+    }
+
+    /// Return a flash loan plus its fee from within the callback, refunding any native excess
+    pub fn execute_repay_flash_loan(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+    ) -> StdResult<Response> {
+        let mut flash_loan = FLASH_LOAN
+            .may_load(deps.storage)?
+            .ok_or_else(|| StdError::generic_err("No flash loan in progress"))?;
+        if flash_loan.repaid {
+            return Err(StdError::generic_err("Flash loan already repaid"));
+        }
+
+        let owed = flash_loan.amount + flash_loan.fee;
+        let mut response = Response::new();
+        match &flash_loan.token {
+            Token::Native(denom) => {
+                verify_funds(&info.funds, denom, owed)?;
+                let sent = info
+                    .funds
+                    .iter()
+                    .find(|c| c.denom == *denom)
+                    .map(|c| c.amount)
+                    .unwrap_or(Uint128::zero());
+                if sent > owed {
+                    response = response.add_message(transfer_msg(
+                        &flash_loan.token,
+                        &info.sender,
+                        sent - owed,
+                    )?);
+                }
+            }
+            Token::Cw20(addr) => {
+                response = response.add_message(WasmMsg::Execute {
+                    contract_addr: addr.to_string(),
+                    msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+                        owner: info.sender.to_string(),
+                        recipient: env.contract.address.to_string(),
+                        amount: owed,
+                    })?,
+                    funds: vec![],
+                })
+            }
+        }
+
+        flash_loan.repaid = true;
+        FLASH_LOAN.save(deps.storage, &flash_loan)?;
+
+        Ok(response
+            .add_attribute("action", "repay_flash_loan")
+            .add_attribute("amount", owed.to_string()))
+    }
+
+    /// Settle a flash loan once its callback has run, reverting if it was not repaid
+    pub fn reply_flash_loan(deps: DepsMut, _env: Env) -> StdResult<Response> {
+        let flash_loan = FLASH_LOAN.load(deps.storage)?;
+        if !flash_loan.repaid {
+            return Err(StdError::generic_err("Flash loan not repaid"));
+        }
+        FLASH_LOAN.remove(deps.storage);
+
+        add_reserves(deps.storage, &flash_loan.token, flash_loan.fee)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "flash_loan_repaid")
+            .add_attribute("receiver", flash_loan.receiver.to_string())
+            .add_attribute("fee", flash_loan.fee.to_string()))
+    }
+
+    /// Set or clear the auction parameters (admin only)
+    pub fn execute_update_auction_config(
+        deps: DepsMut,
+        info: MessageInfo,
+        auction: Option<AuctionConfig>,
+    ) -> StdResult<Response> {
+        let mut config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        if let Some(auction) = &auction {
+            validate_auction_config(auction)?;
+        }
+        config.auction = auction;
+        CONFIG.save(deps.storage, &config)?;
+        Ok(Response::new()
+            .add_attribute("action", "update_auction_config")
+            .add_attribute("auction_mode", config.auction.is_some().to_string()))
+    }
+
+    /// Set the protocol's fee on liquidation repayments (admin only)
+    pub fn execute_update_liquidation_fee(
+        deps: DepsMut,
+        info: MessageInfo,
+        fee: Uint128,
+    ) -> StdResult<Response> {
+        let mut config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        validate_liquidation_fee(fee)?;
+        config.liquidation_fee = fee;
+        CONFIG.save(deps.storage, &config)?;
+        Ok(Response::new()
+            .add_attribute("action", "update_liquidation_fee")
+            .add_attribute("fee", fee.to_string()))
+    }
+
+    /// Set the DEX router used to swap position collateral (admin only)
+    pub fn execute_update_swap_router(
+        deps: DepsMut,
+        info: MessageInfo,
+        address: Option<String>,
+    ) -> StdResult<Response> {
+        let mut config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        config.swap_router = address
+            .map(|address| deps.api.addr_validate(&address))
+            .transpose()?;
+        CONFIG.save(deps.storage, &config)?;
+        Ok(Response::new()
+            .add_attribute("action", "update_swap_router")
+            .add_attribute(
+                "swap_router",
+                config
+                    .swap_router
+                    .map(|router| router.to_string())
+                    .unwrap_or_default(),
+            ))
+    }
+
+    /// Swap collateral of a running loan into another token through the DEX router. The
+    /// amount received and the position's health are checked in the reply.
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute_swap_collateral(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        from_token: String,
+        amount: Uint128,
+        to_token: String,
+        min_receive: Uint128,
+    ) -> StdResult<Response> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        ensure_position_manager(deps.storage, &position, &info.sender)?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Position is being auctioned"));
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let router = config
+            .swap_router
+            .ok_or_else(|| StdError::generic_err("No swap router configured"))?;
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &to_token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        if from_token == to_token {
+            return Err(StdError::generic_err("Cannot swap a token into itself"));
+        }
+        // Isolated collateral carries debt ceiling accounting tied to its token
+        if ISOLATED_TOKENS.has(deps.storage, &from_token)
+            || ISOLATED_TOKENS.has(deps.storage, &to_token)
+        {
+            return Err(StdError::generic_err(
+                "Isolated collateral cannot be swapped",
+            ));
+        }
+        if swap_in_progress(deps.storage) {
+            return Err(StdError::generic_err("Swap already in progress"));
+        }
+        let from_token = determine_token_type(&deps.as_ref(), &from_token)?;
+        let to_token = determine_token_type(&deps.as_ref(), &to_token)?;
+        let index = collateral_index(&position, &from_token)?;
+        if amount.is_zero() || amount > position.collateral[index].1 {
+            return Err(StdError::generic_err("Invalid collateral amount"));
+        }
+
+        let balance_before = contract_balance(&deps.as_ref(), &env, &to_token)?;
+        PENDING_SWAP.save(
+            deps.storage,
+            &PendingSwap {
+                position_id: position_id.u128(),
+                from_token: from_token.clone(),
+                amount,
+                to_token: to_token.clone(),
+                min_receive,
+                balance_before,
+            },
+        )?;
+        let swap_msg = router_swap_msg(&router, &from_token, &to_token, amount, min_receive)?;
+
+        Ok(Response::new()
+            .add_submessage(SubMsg::reply_on_success(swap_msg, SWAP_REPLY_ID))
+            .add_attribute("action", "swap_collateral")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("from_token", token_to_string(&from_token))
+            .add_attribute("to_token", token_to_string(&to_token))
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Credit the output of a collateral swap to its position, once at least the minimum
+    /// was received and the position stays under its max LTV
+    pub fn reply_swap_collateral(deps: DepsMut, env: Env) -> StdResult<Response> {
+        let swap = PENDING_SWAP.load(deps.storage)?;
+        PENDING_SWAP.remove(deps.storage);
+        let received = contract_balance(&deps.as_ref(), &env, &swap.to_token)?
+            .checked_sub(swap.balance_before)?;
+        if received < swap.min_receive {
+            return Err(StdError::generic_err(format!(
+                "Swap returned {}, below the minimum {}",
+                received, swap.min_receive
+            )));
+        }
+        let key = token_to_string(&swap.to_token);
+        let caps = TOKEN_CAPS.may_load(deps.storage, &key)?.unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &key)?
+            .unwrap_or_default();
+        check_cap(
+            "Collateral",
+            caps.collateral_cap,
+            stats.total_collateral,
+            received,
+        )?;
+
+        let mut position = POSITIONS.load(deps.storage, swap.position_id)?;
+        let index = collateral_index(&position, &swap.from_token)?;
+        if swap.amount == position.collateral[index].1 {
+            take_collateral(deps.storage, &mut position, index)?;
+        } else {
+            position.collateral[index].1 -= swap.amount;
+            update_market_stats(deps.storage, &swap.from_token, |stats| {
+                stats.total_collateral = stats.total_collateral.checked_sub(swap.amount)?;
+                Ok(())
+            })?;
+        }
+        release_isolated_debt(deps.storage, &position, position.amount)?;
+        match position
+            .collateral
+            .iter_mut()
+            .find(|(token, _)| *token == swap.to_token)
+        {
+            Some((_, amount)) => *amount += received,
+            None => position.collateral.push((swap.to_token.clone(), received)),
+        }
+        add_isolated_debt(deps.storage, &position, position.amount)?;
+        update_market_stats(deps.storage, &swap.to_token, |stats| {
+            stats.total_collateral += received;
+            Ok(())
+        })?;
+
+        let config = CONFIG.load(deps.storage)?;
+        let health = position_health(&deps.as_ref(), &config, &position, env.block.time.seconds())?;
+        if health.ltv > health.max_ltv {
+            return Err(StdError::generic_err(format!(
+                "Insufficient collateral after swap: LTV {} above max {}",
+                health.ltv, health.max_ltv
+            )));
+        }
+        POSITIONS.save(deps.storage, swap.position_id, &position)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "swap_collateral_settled")
+            .add_attribute("position_id", swap.position_id.to_string())
+            .add_attribute("sold", swap.amount.to_string())
+            .add_attribute("received", received.to_string()))
+    }
+
+    /// Set the cw721 contract minting lender notes for loans started from now on
+    pub fn execute_update_lender_nft(
+        deps: DepsMut,
+        info: MessageInfo,
+        address: Option<String>,
+    ) -> StdResult<Response> {
+        let mut config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        config.lender_nft = address
+            .map(|address| deps.api.addr_validate(&address))
+            .transpose()?;
+        CONFIG.save(deps.storage, &config)?;
+        Ok(Response::new()
+            .add_attribute("action", "update_lender_nft")
+            .add_attribute(
+                "lender_nft",
+                config
+                    .lender_nft
+                    .map(|nft| nft.to_string())
+                    .unwrap_or_default(),
+            ))
+    }
+
+    /// Start a Dutch auction for the collateral of an undercollateralized position
+    pub fn execute_start_auction(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        let auction_config = config
+            .auction
+            .clone()
+            .ok_or_else(|| StdError::generic_err("Auctions disabled"))?;
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        if AUCTIONS.has(deps.storage, position_id.u128()) {
+            return Err(StdError::generic_err("Auction already started"));
+        }
+
+        let health = position_health(&deps.as_ref(), &config, &position, env.block.time.seconds())?;
+        if !health.liquidatable {
+            return Err(StdError::generic_err("Position not undercollateralized"));
+        }
+
+        // Interest stops accruing once the debt is up for auction, which runs to the end on
+        // the parameters it started with
+        AUCTIONS.save(
+            deps.storage,
+            position_id.u128(),
+            &Auction {
+                start_time: env.block.time.seconds(),
+                debt_remaining: health.total_debt,
+                config: auction_config,
+            },
+        )?;
+
+        Ok(Response::new()
+            .add_attribute("action", "start_auction")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("debt", health.total_debt.to_string())
+            .add_attribute("starter", info.sender.to_string()))
+    }
+
+    /// Repay part of an auctioned debt in exchange for collateral at the current discount
+    pub fn execute_bid(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+        amount: Uint128,
+        collateral_token: String,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        let mut auction = AUCTIONS
+            .may_load(deps.storage, position_id.u128())?
+            .ok_or_else(|| StdError::generic_err("No auction for position"))?;
+        let now = env.block.time.seconds();
+        if !auctioned_health(&deps.as_ref(), &config, &position, &auction, now)?.liquidatable {
+            return Err(StdError::generic_err(
+                "Position no longer undercollateralized",
+            ));
+        }
+        let collateral_token = determine_token_type(&deps.as_ref(), &collateral_token)?;
+        let index = collateral_index(&position, &collateral_token)?;
+        let available = position.collateral[index].1;
+
+        // Price the collateral at the current discount
+        let discount = auction_discount(&auction, now);
+        let borrow_price =
+            query_price(&deps.as_ref(), &config.mock_oracle, &position.borrow_token)?;
+        let collateral_price = query_price(&deps.as_ref(), &config.mock_oracle, &collateral_token)?;
+        let discounted_price = collateral_price.multiply_ratio(
+            Uint128::from(10_000u128) - discount,
+            Uint128::from(10_000u128),
+        );
+        if discounted_price.is_zero() || borrow_price.is_zero() {
+            return Err(StdError::generic_err("Missing oracle price"));
+        }
+
+        let mut repay = amount.min(auction.debt_remaining);
+        let mut collateral_out = repay.multiply_ratio(borrow_price, discounted_price);
+        if collateral_out > available {
+            // Not enough of the token left, sell all of it for less debt
+            collateral_out = available;
+            repay = collateral_out
+                .multiply_ratio(discounted_price, borrow_price)
+                .min(repay);
+        }
+        if repay.is_zero() || collateral_out.is_zero() {
+            return Err(StdError::generic_err("Bid too small"));
+        }
+
+        // Bidder pays the lenders and receives the collateral
+        let mut messages = lender_payments(
+            &deps.as_ref(),
+            position_id.u128(),
+            &position,
+            repay,
+            Some(&info.sender),
+        )?;
+        if let Token::Native(denom) = &position.borrow_token {
+            verify_funds(&info.funds, denom, amount)?;
+            if amount > repay {
+                messages.push(transfer_msg(
+                    &position.borrow_token,
+                    &info.sender,
+                    amount - repay,
+                )?);
+            }
+        }
+        messages.push(transfer_msg(
+            &collateral_token,
+            &info.sender,
+            collateral_out,
+        )?);
+
+        auction.debt_remaining -= repay;
+        if collateral_out == available {
+            take_collateral(deps.storage, &mut position, index)?;
+        } else {
+            position.collateral[index].1 -= collateral_out;
+            update_market_stats(deps.storage, &collateral_token, |stats| {
+                stats.total_collateral = stats.total_collateral.checked_sub(collateral_out)?;
+                Ok(())
+            })?;
+        }
+
+        let mut response = Response::new()
+            .add_attribute("action", "bid")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("bidder", info.sender.to_string())
+            .add_attribute("repaid", repay.to_string())
+            .add_attribute("collateral", collateral_out.to_string())
+            .add_attribute("discount", discount.to_string());
+
+        if auction.debt_remaining.is_zero() || position.collateral.is_empty() {
+            // Auction over, unpaid debt is a shortfall for the lenders
+            let covered = cover_shortfall(
+                deps.storage,
+                &env,
+                position_id.u128(),
+                &position.borrow_token,
+                auction.debt_remaining,
+            )?;
+            if !covered.is_zero() {
+                messages.extend(lender_payments(
+                    &deps.as_ref(),
+                    position_id.u128(),
+                    &position,
+                    covered,
+                    None,
+                )?);
+            }
+
+            // Leftover collateral goes back to the borrower
+            for (token, amount) in &position.collateral {
+                messages.extend(return_collateral(deps.storage, &position, token, *amount)?);
+            }
+            AUCTIONS.remove(deps.storage, position_id.u128());
+            POSITIONS.remove(deps.storage, position_id.u128());
+            EXTENSION_PROPOSALS.remove(deps.storage, position_id.u128());
+            close_position_stats(deps.storage, &position)?;
+            response = response
+                .add_attribute("auction_closed", "true")
+                .add_attribute(
+                    "collateral_returned",
+                    collateral_to_string(&position.collateral),
+                )
+                .add_attribute("shortfall", auction.debt_remaining.to_string())
+                .add_attribute("insurance_cover", covered.to_string());
+        } else {
+            AUCTIONS.save(deps.storage, position_id.u128(), &auction)?;
+            POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+        }
+
+        Ok(response.add_messages(messages))
+    }
+
+    /// Stop the auction of a position whose collateral covers the debt left by bidders again,
+    /// restarting its loan on that debt
+    pub fn execute_cancel_auction(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        position_id: Uint128,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        let mut position = POSITIONS.load(deps.storage, position_id.u128())?;
+        let auction = AUCTIONS
+            .may_load(deps.storage, position_id.u128())?
+            .ok_or_else(|| StdError::generic_err("No auction for position"))?;
+        let now = env.block.time.seconds();
+        if auctioned_health(&deps.as_ref(), &config, &position, &auction, now)?.liquidatable {
+            return Err(StdError::generic_err("Position still undercollateralized"));
+        }
+
+        reduce_debt(deps.storage, &mut position, auction.debt_remaining, now)?;
+        AUCTIONS.remove(deps.storage, position_id.u128());
+        POSITIONS.save(deps.storage, position_id.u128(), &position)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "cancel_auction")
+            .add_attribute("position_id", position_id.to_string())
+            .add_attribute("debt", auction.debt_remaining.to_string())
+            .add_attribute("sender", info.sender.to_string()))
+    }
+
+    /// Add funds to the insurance fund of a token
+    pub fn execute_deposit_insurance(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let mut response = Response::new();
+        match &token_type {
+            Token::Native(denom) => verify_funds(&info.funds, denom, amount)?,
+            Token::Cw20(addr) => {
+                response = response.add_message(WasmMsg::Execute {
+                    contract_addr: addr.to_string(),
+                    msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+                        owner: info.sender.to_string(),
+                        recipient: env.contract.address.to_string(),
+                        amount,
+                    })?,
+                    funds: vec![],
+                })
+            }
+        }
+
+        let key = token_to_string(&token_type);
+        let mut fund = INSURANCE_FUNDS
+            .may_load(deps.storage, &key)?
+            .unwrap_or_default();
+        fund.balance += amount;
+        INSURANCE_FUNDS.save(deps.storage, &key, &fund)?;
+
+        Ok(response
+            .add_attribute("action", "deposit_insurance")
+            .add_attribute("user", info.sender.to_string())
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Move protocol reserves into the insurance fund (admin only)
+    pub fn execute_fund_insurance(
+        deps: DepsMut,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+
+        let key = token_to_string(&determine_token_type(&deps.as_ref(), &token)?);
+        let reserves = RESERVES
+            .may_load(deps.storage, &key)?
+            .unwrap_or(Uint128::zero());
+        if reserves < amount {
+            return Err(StdError::generic_err("Insufficient reserves"));
+        }
+        RESERVES.save(deps.storage, &key, &(reserves - amount))?;
+
+        let mut fund = INSURANCE_FUNDS
+            .may_load(deps.storage, &key)?
+            .unwrap_or_default();
+        fund.balance += amount;
+        INSURANCE_FUNDS.save(deps.storage, &key, &fund)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "fund_insurance")
+            .add_attribute("token", key)
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Set the market caps of a token (admin only)
+    pub fn execute_update_token_caps(
+        deps: DepsMut,
+        info: MessageInfo,
+        token: String,
+        caps: TokenCaps,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        TOKEN_CAPS.save(deps.storage, &token, &caps)?;
+        Ok(Response::new()
+            .add_attribute("action", "update_token_caps")
+            .add_attribute("token", token))
+    }
+
+    /// Set or lift isolation of a collateral token (admin only)
+    pub fn execute_update_isolation(
+        deps: DepsMut,
+        info: MessageInfo,
+        token: String,
+        isolation: Option<Isolation>,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        match &isolation {
+            Some(isolation) => ISOLATED_TOKENS.save(deps.storage, &token, isolation)?,
+            // Debt stays tracked, so re-isolating counts the loans still open against it
+            None => ISOLATED_TOKENS.remove(deps.storage, &token),
+        }
+        Ok(Response::new()
+            .add_attribute("action", "update_isolation")
+            .add_attribute("token", token)
+            .add_attribute("isolated", isolation.is_some().to_string()))
+    }
+
+    /// Create, replace or remove an e-mode category (admin only)
+    pub fn execute_update_e_mode_category(
+        deps: DepsMut,
+        info: MessageInfo,
+        id: u8,
+        category: Option<EModeCategory>,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+
+        // Drop the previous membership of the category
+        if let Some(previous) = E_MODE_CATEGORIES.may_load(deps.storage, id)? {
+            for token in &previous.tokens {
+                TOKEN_E_MODE.remove(deps.storage, token);
+            }
+        }
+
+        match &category {
+            Some(category) => {
+                if category.liquidation_threshold < Uint128::from(100u128) {
+                    return Err(StdError::generic_err(
+                        "Liquidation threshold must be at least 100",
+                    ));
+                }
+                if category.max_ltv > Decimal::from_ratio(100u128, category.liquidation_threshold) {
+                    return Err(StdError::generic_err(
+                        "Max LTV must not open positions below the liquidation threshold",
+                    ));
+                }
+                if category.liquidation_bonus >= Uint128::from(10_000u128) {
+                    return Err(StdError::generic_err(
+                        "Liquidation bonus must be below 10000",
+                    ));
+                }
+                for token in &category.tokens {
+                    if !SUPPORTED_TOKENS
+                        .may_load(deps.storage, token)?
+                        .unwrap_or(false)
+                    {
+                        return Err(StdError::generic_err("Unsupported token"));
+                    }
+                    if TOKEN_E_MODE.has(deps.storage, token) {
+                        return Err(StdError::generic_err(format!(
+                            "Token {} already in an e-mode category",
+                            token
+                        )));
+                    }
+                    TOKEN_E_MODE.save(deps.storage, token, &id)?;
+                }
+                E_MODE_CATEGORIES.save(deps.storage, id, category)?;
+            }
+            None => E_MODE_CATEGORIES.remove(deps.storage, id),
+        }
+
+        Ok(Response::new()
+            .add_attribute("action", "update_e_mode_category")
+            .add_attribute("id", id.to_string()))
+    }
+
+    /// Set the risk weight of a token as position collateral (admin only)
+    pub fn execute_update_collateral_weight(
+        deps: DepsMut,
+        info: MessageInfo,
+        token: String,
+        weight: Option<Decimal>,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        match weight {
+            Some(weight) if weight > Decimal::one() => {
+                return Err(StdError::generic_err("Collateral weight must not exceed 1"))
+            }
+            Some(weight) => COLLATERAL_WEIGHTS.save(deps.storage, &token, &weight)?,
+            None => COLLATERAL_WEIGHTS.remove(deps.storage, &token),
+        }
+        Ok(Response::new()
+            .add_attribute("action", "update_collateral_weight")
+            .add_attribute("token", token))
+    }
+
+    /// Set or remove cross-margin parameters of a token (admin only)
+    pub fn execute_update_margin_params(
+        deps: DepsMut,
+        info: MessageInfo,
+        token: String,
+        params: Option<MarginParams>,
+    ) -> StdResult<Response> {
+        let config = CONFIG.load(deps.storage)?;
+        if info.sender != config.admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        match &params {
+            Some(params) => {
+                if params.collateral_weight > Decimal::one() {
+                    return Err(StdError::generic_err("Collateral weight must not exceed 1"));
+                }
+                // Borrowing stops short of the LTV at which the account becomes liquidatable
+                let liquidation_ltv = params.collateral_weight
+                    * Decimal::from_ratio(100u128, config.liquidation_threshold);
+                if !params.max_ltv.is_zero() && params.max_ltv >= liquidation_ltv {
+                    return Err(StdError::generic_err(format!(
+                        "Max LTV must be below the liquidation LTV of {}",
+                        liquidation_ltv
+                    )));
+                }
+                MARGIN_PARAMS.save(deps.storage, &token, params)?
+            }
+            None => {
+                if margin_token_in_use(deps.storage, &token)? {
+                    return Err(StdError::generic_err(
+                        "Token still held or owed by margin accounts",
+                    ));
+                }
+                MARGIN_PARAMS.remove(deps.storage, &token)
+            }
+        }
+        Ok(Response::new()
+            .add_attribute("action", "update_margin_params")
+            .add_attribute("token", token))
+    }
+
+    /// Add collateral to the sender's cross-margin account
+    pub fn execute_account_deposit(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        if !MARGIN_PARAMS.has(deps.storage, &token) {
+            return Err(StdError::generic_err(
+                "Token not enabled for margin accounts",
+            ));
+        }
+        if ISOLATED_TOKENS.has(deps.storage, &token) {
+            return Err(StdError::generic_err(
+                "Isolated collateral cannot be used in margin accounts",
+            ));
+        }
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        check_cap(
+            "Collateral",
+            caps.collateral_cap,
+            stats.total_collateral,
+            amount,
+        )?;
+
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let receive_msg = receive_msg(&env, &info, &token_type, amount)?;
+
+        let key = (&info.sender, token.as_str());
+        let balance = ACCOUNT_COLLATERAL
+            .may_load(deps.storage, key)?
+            .unwrap_or(Uint128::zero());
+        ACCOUNT_COLLATERAL.save(deps.storage, key, &(balance + amount))?;
+        update_market_stats(deps.storage, &token_type, |stats| {
+            stats.total_collateral += amount;
+            Ok(())
+        })?;
+
+        Ok(Response::new()
+            .add_messages(receive_msg)
+            .add_attribute("action", "account_deposit")
+            .add_attribute("user", info.sender.to_string())
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Take collateral out of the sender's account if it stays healthy
+    pub fn execute_account_withdraw(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        let key = (&info.sender, token.as_str());
+        let balance = ACCOUNT_COLLATERAL
+            .may_load(deps.storage, key)?
+            .unwrap_or(Uint128::zero());
+        if balance < amount {
+            return Err(StdError::generic_err("Insufficient account collateral"));
+        }
+        if balance == amount {
+            ACCOUNT_COLLATERAL.remove(deps.storage, key);
+        } else {
+            ACCOUNT_COLLATERAL.save(deps.storage, key, &(balance - amount))?;
+        }
+
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        update_market_stats(deps.storage, &token_type, |stats| {
+            stats.total_collateral = stats.total_collateral.checked_sub(amount)?;
+            Ok(())
+        })?;
+        ensure_within_borrow_limit(&deps.as_ref(), &info.sender, env.block.time.seconds())?;
+
+        Ok(Response::new()
+            .add_message(transfer_msg(&token_type, &info.sender, amount)?)
+            .add_attribute("action", "account_withdraw")
+            .add_attribute("user", info.sender.to_string())
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Borrow deposited liquidity against the sender's account
+    pub fn execute_account_borrow(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+    ) -> StdResult<Response> {
+        let params = MARGIN_PARAMS
+            .may_load(deps.storage, &token)?
+            .filter(|params| params.borrowable)
+            .ok_or_else(|| StdError::generic_err("Token not borrowable from margin accounts"))?;
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        check_cap("Borrow", caps.borrow_cap, stats.total_borrowed, amount)?;
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < amount {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+
+        let now = env.block.time.seconds();
+        let key = (&info.sender, token.as_str());
+        let mut debt = ACCOUNT_DEBTS
+            .may_load(deps.storage, key)?
+            .unwrap_or_default();
+        accrue_account_debt(&mut debt, params.borrow_rate, now);
+        debt.principal += amount;
+        ACCOUNT_DEBTS.save(deps.storage, key, &debt)?;
+
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        update_market_stats(deps.storage, &token_type, |stats| {
+            stats.total_borrowed += amount;
+            stats.pool_borrowed += amount;
+            Ok(())
+        })?;
+        ensure_within_borrow_limit(&deps.as_ref(), &info.sender, now)?;
+
+        Ok(Response::new()
+            .add_message(transfer_msg(&token_type, &info.sender, amount)?)
+            .add_attribute("action", "account_borrow")
+            .add_attribute("borrower", info.sender.to_string())
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Repay account debt, interest first, refunding any native excess
+    pub fn execute_account_repay(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token: String,
+        amount: Uint128,
+        borrower: Option<String>,
+    ) -> StdResult<Response> {
+        let borrower = match borrower {
+            Some(borrower) => deps.api.addr_validate(&borrower)?,
+            None => info.sender.clone(),
+        };
+        let token_type = determine_token_type(&deps.as_ref(), &token)?;
+        let repaid = repay_account_debt(
+            deps.storage,
+            &borrower,
+            &token_type,
+            amount,
+            env.block.time.seconds(),
+        )?;
+
+        let mut response =
+            Response::new().add_messages(receive_msg(&env, &info, &token_type, repaid)?);
+        if let Token::Native(_) = token_type {
+            verify_funds(&info.funds, &token, amount)?;
+            if amount > repaid {
+                response =
+                    response.add_message(transfer_msg(&token_type, &info.sender, amount - repaid)?);
+            }
+        }
+
+        Ok(response
+            .add_attribute("action", "account_repay")
+            .add_attribute("borrower", borrower.to_string())
+            .add_attribute("amount", repaid.to_string()))
+    }
+
+    /// Repay debt of an unhealthy account in exchange for collateral at the liquidation bonus
+    pub fn execute_liquidate_account(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        borrower: String,
+        debt_token: String,
+        amount: Uint128,
+        collateral_token: String,
+    ) -> StdResult<Response> {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        let config = CONFIG.load(deps.storage)?;
+        let now = env.block.time.seconds();
+        let (_, debts, health) = margin_account(&deps.as_ref(), &config, &borrower, now)?;
+        if !health.liquidatable {
+            return Err(StdError::generic_err("Account not undercollateralized"));
+        }
+
+        let debt_token_type = determine_token_type(&deps.as_ref(), &debt_token)?;
+        let collateral_token_type = determine_token_type(&deps.as_ref(), &collateral_token)?;
+        let owed = debts
+            .iter()
+            .find(|(token, _)| *token == debt_token_type)
+            .map(|(_, debt)| debt.principal + debt.interest)
+            .ok_or_else(|| StdError::generic_err("No debt in token"))?;
+        let collateral_key = (&borrower, collateral_token.as_str());
+        let collateral = ACCOUNT_COLLATERAL
+            .may_load(deps.storage, collateral_key)?
+            .unwrap_or(Uint128::zero());
+
+        // Collateral is sold at the liquidation bonus discount, limited by what the account holds
+        let debt_price = query_price(&deps.as_ref(), &config.mock_oracle, &debt_token_type)?;
+        let collateral_price =
+            query_price(&deps.as_ref(), &config.mock_oracle, &collateral_token_type)?;
+        if debt_price.is_zero() || collateral_price.is_zero() {
+            return Err(StdError::generic_err("Missing oracle price"));
+        }
+        let bonus = Uint128::from(10_000u128 + DEFAULT_LIQUIDATION_BONUS);
+        let mut repay = amount.min(owed);
+        let mut seized = repay.multiply_ratio(
+            debt_price * bonus,
+            collateral_price * Uint128::from(10_000u128),
+        );
+        if seized > collateral {
+            seized = collateral;
+            repay = collateral.multiply_ratio(
+                collateral_price * Uint128::from(10_000u128),
+                debt_price * bonus,
+            );
+        }
+        if repay.is_zero() || seized.is_zero() {
+            return Err(StdError::generic_err("Nothing to liquidate"));
+        }
+
+        repay_account_debt(deps.storage, &borrower, &debt_token_type, repay, now)?;
+        if seized == collateral {
+            ACCOUNT_COLLATERAL.remove(deps.storage, collateral_key);
+        } else {
+            ACCOUNT_COLLATERAL.save(deps.storage, collateral_key, &(collateral - seized))?;
+        }
+        update_market_stats(deps.storage, &collateral_token_type, |stats| {
+            stats.total_collateral = stats.total_collateral.checked_sub(seized)?;
+            Ok(())
+        })?;
+        let written_off = write_off_account(deps.branch(), &borrower, now)?;
+
+        let mut response = Response::new()
+            .add_messages(receive_msg(&env, &info, &debt_token_type, repay)?)
+            .add_message(transfer_msg(&collateral_token_type, &info.sender, seized)?);
+        if let Token::Native(_) = debt_token_type {
+            verify_funds(&info.funds, &debt_token, amount)?;
+            if amount > repay {
+                response = response.add_message(transfer_msg(
+                    &debt_token_type,
+                    &info.sender,
+                    amount - repay,
+                )?);
+            }
+        }
+
+        Ok(response
+            .add_attribute("action", "liquidate_account")
+            .add_attribute("borrower", borrower.to_string())
+            .add_attribute("repay_amount", repay.to_string())
+            .add_attribute("collateral_seized", seized.to_string())
+            .add_attribute("debts_written_off", written_off.len().to_string()))
+    }
+
+    /// Open a leveraged margin position in one step. The user's collateral is topped up with
+    /// `debt_token` flash-borrowed from the deposit pool and sold through the router, worth
+    /// `leverage - 1` times the deposit at oracle prices. The reply turns the flash loan into
+    /// account debt, which must stay within the account's borrow limit.
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute_open_leveraged(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        collateral_token: String,
+        amount: Uint128,
+        debt_token: String,
+        leverage: Decimal,
+        min_receive: Uint128,
+    ) -> StdResult<Response> {
+        if leverage <= Decimal::one() {
+            return Err(StdError::generic_err("Leverage must be above 1"));
+        }
+        if amount.is_zero() {
+            return Err(StdError::generic_err("Invalid collateral amount"));
+        }
+        if collateral_token == debt_token {
+            return Err(StdError::generic_err(
+                "Collateral and debt tokens must differ",
+            ));
+        }
+        if !MARGIN_PARAMS.has(deps.storage, &collateral_token) {
+            return Err(StdError::generic_err(
+                "Token not enabled for margin accounts",
+            ));
+        }
+        if ISOLATED_TOKENS.has(deps.storage, &collateral_token) {
+            return Err(StdError::generic_err(
+                "Isolated collateral cannot be used in margin accounts",
+            ));
+        }
+        if !MARGIN_PARAMS
+            .may_load(deps.storage, &debt_token)?
+            .is_some_and(|params| params.borrowable)
+        {
+            return Err(StdError::generic_err(
+                "Token not borrowable from margin accounts",
+            ));
+        }
+        if swap_in_progress(deps.storage) {
+            return Err(StdError::generic_err("Swap already in progress"));
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let router = config
+            .swap_router
+            .clone()
+            .ok_or_else(|| StdError::generic_err("No swap router configured"))?;
+        let collateral_type = determine_token_type(&deps.as_ref(), &collateral_token)?;
+        let debt_type = determine_token_type(&deps.as_ref(), &debt_token)?;
+
+        // Borrow the value of the extra exposure at oracle prices
+        let collateral_price = query_price(&deps.as_ref(), &config.mock_oracle, &collateral_type)?;
+        let debt_price = query_price(&deps.as_ref(), &config.mock_oracle, &debt_type)?;
+        let borrowed = (amount.mul_floor(leverage) - amount)
+            .checked_multiply_ratio(collateral_price, debt_price)
+            .map_err(|_| StdError::generic_err("Invalid leverage"))?;
+        if borrowed.is_zero() {
+            return Err(StdError::generic_err("Nothing to borrow"));
+        }
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &debt_token)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &debt_token)?
+            .unwrap_or_default();
+        check_cap("Borrow", caps.borrow_cap, stats.total_borrowed, borrowed)?;
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < borrowed {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+        let fee = borrowed * config.flash_loan_fee / Uint128::from(10_000u128);
+
+        // Native collateral is already in the balance, while a cw20 deposit is only pulled
+        // after the swap has settled
+        let balance_before = contract_balance(&deps.as_ref(), &env, &collateral_type)?;
+        PENDING_LEVERAGE.save(
+            deps.storage,
+            &PendingLeverage {
+                user: info.sender.clone(),
+                collateral_token: collateral_type.clone(),
+                deposit: amount,
+                debt_token: debt_type.clone(),
+                borrowed,
+                fee,
+                min_receive,
+                balance_before,
+            },
+        )?;
+        let swap_msg =
+            router_swap_msg(&router, &debt_type, &collateral_type, borrowed, min_receive)?;
+
+        Ok(Response::new()
+            .add_submessage(SubMsg::reply_on_success(swap_msg, LEVERAGE_REPLY_ID))
+            .add_messages(receive_msg(&env, &info, &collateral_type, amount)?)
+            .add_attribute("action", "open_leveraged")
+            .add_attribute("user", info.sender.to_string())
+            .add_attribute("collateral_token", collateral_token)
+            .add_attribute("debt_token", debt_token)
+            .add_attribute("deposit", amount.to_string())
+            .add_attribute("borrowed", borrowed.to_string())
+            .add_attribute("fee", fee.to_string()))
+    }
+
+    /// Post the deposit and the swap output as account collateral and settle the flash loan
+    /// as account debt, its fee accruing as interest
+    pub fn reply_open_leveraged(deps: DepsMut, env: Env) -> StdResult<Response> {
+        let pending = PENDING_LEVERAGE.load(deps.storage)?;
+        PENDING_LEVERAGE.remove(deps.storage);
+        let received = contract_balance(&deps.as_ref(), &env, &pending.collateral_token)?
+            .checked_sub(pending.balance_before)?;
+        if received < pending.min_receive {
+            return Err(StdError::generic_err(format!(
+                "Swap returned {}, below the minimum {}",
+                received, pending.min_receive
+            )));
+        }
+
+        let collateral = pending.deposit + received;
+        let collateral_key = token_to_string(&pending.collateral_token);
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &collateral_key)?
+            .unwrap_or_default();
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &collateral_key)?
+            .unwrap_or_default();
+        check_cap(
+            "Collateral",
+            caps.collateral_cap,
+            stats.total_collateral,
+            collateral,
+        )?;
+        let key = (&pending.user, collateral_key.as_str());
+        let balance = ACCOUNT_COLLATERAL
+            .may_load(deps.storage, key)?
+            .unwrap_or(Uint128::zero());
+        ACCOUNT_COLLATERAL.save(deps.storage, key, &(balance + collateral))?;
+        update_market_stats(deps.storage, &pending.collateral_token, |stats| {
+            stats.total_collateral += collateral;
+            Ok(())
+        })?;
+
+        let now = env.block.time.seconds();
+        let debt_key = token_to_string(&pending.debt_token);
+        let rate = MARGIN_PARAMS
+            .may_load(deps.storage, &debt_key)?
+            .map(|params| params.borrow_rate)
+            .unwrap_or(Uint128::zero());
+        let key = (&pending.user, debt_key.as_str());
+        let mut debt = ACCOUNT_DEBTS
+            .may_load(deps.storage, key)?
+            .unwrap_or_default();
+        accrue_account_debt(&mut debt, rate, now);
+        debt.principal += pending.borrowed;
+        debt.interest += pending.fee;
+        ACCOUNT_DEBTS.save(deps.storage, key, &debt)?;
+        update_market_stats(deps.storage, &pending.debt_token, |stats| {
+            stats.total_borrowed += pending.borrowed;
+            stats.pool_borrowed += pending.borrowed;
+            Ok(())
+        })?;
+        ensure_within_borrow_limit(&deps.as_ref(), &pending.user, now)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "open_leveraged_settled")
+            .add_attribute("user", pending.user.to_string())
+            .add_attribute("collateral", collateral.to_string())
+            .add_attribute("debt", (pending.borrowed + pending.fee).to_string()))
+    }
+
+    /// Unwind a margin position by selling account collateral through the router. The
+    /// collateral is released before the debt it pays off, which is repaid in the reply.
+    pub fn execute_deleverage(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        collateral_token: String,
+        amount: Uint128,
+        debt_token: String,
+        min_receive: Uint128,
+    ) -> StdResult<Response> {
+        if collateral_token == debt_token {
+            return Err(StdError::generic_err(
+                "Collateral and debt tokens must differ",
+            ));
+        }
+        if !ACCOUNT_DEBTS.has(deps.storage, (&info.sender, debt_token.as_str())) {
+            return Err(StdError::generic_err("No debt in token"));
+        }
+        if swap_in_progress(deps.storage) {
+            return Err(StdError::generic_err("Swap already in progress"));
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let router = config
+            .swap_router
+            .ok_or_else(|| StdError::generic_err("No swap router configured"))?;
+
+        let key = (&info.sender, collateral_token.as_str());
+        let balance = ACCOUNT_COLLATERAL
+            .may_load(deps.storage, key)?
+            .unwrap_or(Uint128::zero());
+        if amount.is_zero() || amount > balance {
+            return Err(StdError::generic_err("Invalid collateral amount"));
+        }
+        if balance == amount {
+            ACCOUNT_COLLATERAL.remove(deps.storage, key);
+        } else {
+            ACCOUNT_COLLATERAL.save(deps.storage, key, &(balance - amount))?;
+        }
+        let collateral_type = determine_token_type(&deps.as_ref(), &collateral_token)?;
+        let debt_type = determine_token_type(&deps.as_ref(), &debt_token)?;
+        update_market_stats(deps.storage, &collateral_type, |stats| {
+            stats.total_collateral = stats.total_collateral.checked_sub(amount)?;
+            Ok(())
+        })?;
+
+        let balance_before = contract_balance(&deps.as_ref(), &env, &debt_type)?;
+        PENDING_DELEVERAGE.save(
+            deps.storage,
+            &PendingDeleverage {
+                user: info.sender.clone(),
+                collateral_token: collateral_type.clone(),
+                amount,
+                debt_token: debt_type.clone(),
+                min_receive,
+                balance_before,
+            },
+        )?;
+        let swap_msg = router_swap_msg(&router, &collateral_type, &debt_type, amount, min_receive)?;
+
+        Ok(Response::new()
+            .add_submessage(SubMsg::reply_on_success(swap_msg, DELEVERAGE_REPLY_ID))
+            .add_attribute("action", "deleverage")
+            .add_attribute("user", info.sender.to_string())
+            .add_attribute("collateral_token", collateral_token)
+            .add_attribute("debt_token", debt_token)
+            .add_attribute("amount", amount.to_string()))
+    }
+
+    /// Repay account debt with the output of an unwind and send any excess to the user, as
+    /// long as the account is left healthy
+    pub fn reply_deleverage(deps: DepsMut, env: Env) -> StdResult<Response> {
+        let pending = PENDING_DELEVERAGE.load(deps.storage)?;
+        PENDING_DELEVERAGE.remove(deps.storage);
+        let received = contract_balance(&deps.as_ref(), &env, &pending.debt_token)?
+            .checked_sub(pending.balance_before)?;
+        if received < pending.min_receive {
+            return Err(StdError::generic_err(format!(
+                "Swap returned {}, below the minimum {}",
+                received, pending.min_receive
+            )));
+        }
+
+        let now = env.block.time.seconds();
+        let repaid = repay_account_debt(
+            deps.storage,
+            &pending.user,
+            &pending.debt_token,
+            received,
+            now,
+        )?;
+        ensure_account_healthy(&deps.as_ref(), &pending.user, now)?;
+
+        let mut response = Response::new();
+        if received > repaid {
+            response = response.add_message(transfer_msg(
+                &pending.debt_token,
+                &pending.user,
+                received - repaid,
+            )?);
+        }
+        Ok(response
+            .add_attribute("action", "deleverage_settled")
+            .add_attribute("user", pending.user.to_string())
+            .add_attribute("sold", pending.amount.to_string())
+            .add_attribute("repaid", repaid.to_string()))
+    }
+
+    // Helper functions
+
+    /// Whether a router swap is waiting for its reply
+    fn swap_in_progress(storage: &dyn Storage) -> bool {
+        PENDING_SWAP.exists(storage)
+            || PENDING_LEVERAGE.exists(storage)
+            || PENDING_DELEVERAGE.exists(storage)
+    }
+
+    /// Check auction discounts stay below 100% and only grow
+    pub fn validate_auction_config(auction: &AuctionConfig) -> StdResult<()> {
+        if auction.start_discount > auction.max_discount
+            || auction.max_discount >= Uint128::from(10_000u128)
+        {
+            return Err(StdError::generic_err("Invalid auction discounts"));
+        }
+        Ok(())
+    }
+
+    /// Check the liquidation fee stays under its cap
+    pub fn validate_liquidation_fee(fee: Uint128) -> StdResult<()> {
+        if fee > Uint128::from(MAX_LIQUIDATION_FEE) {
+            return Err(StdError::generic_err(format!(
+                "Liquidation fee must not exceed {} basis points",
+                MAX_LIQUIDATION_FEE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Current collateral discount of an auction in basis points
+    pub fn auction_discount(auction: &Auction, now: u64) -> Uint128 {
+        let config = &auction.config;
+        if config.duration == 0 {
+            return config.max_discount;
+        }
+        let elapsed = now.saturating_sub(auction.start_time).min(config.duration);
+        config.start_discount
+            + (config.max_discount - config.start_discount).multiply_ratio(elapsed, config.duration)
+    }
+
+    /// Health of an auctioned position, its debt being what bidders have left to repay
+    fn auctioned_health(
+        deps: &Deps,
+        config: &Config,
+        position: &Position,
+        auction: &Auction,
+        now: u64,
+    ) -> StdResult<PositionHealth> {
+        let mut position = position.clone();
+        position.amount = auction.debt_remaining;
+        position.start_time = now;
+        position_health(deps, config, &position, now)
+    }
+
+    /// Send tokens held by the contract to a recipient
+    pub fn transfer_msg(token: &Token, recipient: &Addr, amount: Uint128) -> StdResult<CosmosMsg> {
+        Ok(match token {
+            Token::Native(denom) => BankMsg::Send {
+                to_address: recipient.to_string(),
+                amount: vec![Coin {
+                    denom: denom.clone(),
+                    amount,
+                }],
+            }
+            .into(),
+            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: addr.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: recipient.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            }),
+        })
+    }
+
+    /// Determine token type from string
+    pub fn determine_token_type(deps: &Deps, token: &str) -> StdResult<Token> {
+        if deps.api.addr_validate(token).is_ok() {
+            Ok(Token::Cw20(deps.api.addr_validate(token)?))
+        } else {
+            Ok(Token::Native(token.to_string()))
+        }
+    }
+
+    /// Storage key and oracle symbol of a token
+    pub fn token_to_string(token: &Token) -> String {
+        match token {
+            Token::Native(denom) => denom.clone(),
+            Token::Cw20(addr) => addr.to_string(),
+        }
+    }
+
+    /// Query the price of a token from the oracle
+    pub fn query_price(deps: &Deps, oracle: &Addr, token: &Token) -> StdResult<Uint128> {
+        let res: PriceResponse = deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr: oracle.to_string(),
+            msg: to_json_binary(&OracleQueryMsg::GetPrice {
+                token: token_to_string(token),
+            })?,
+        }))?;
+        Ok(res.price)
+    }
+
+    /// Interest accrued on a filled position up to `now`
+    pub fn accrued_interest(position: &Position, now: u64) -> Uint128 {
+        if !position.filled {
+            return Uint128::zero();
+        }
+        let time_elapsed = now.saturating_sub(position.start_time);
+        position.amount * position.interest_rate * Uint128::from(time_elapsed)
+            / Uint128::from(31_536_000u64 * 100u64)
+    }
+
+    /// E-mode category holding both tokens of a position, if any
+    pub fn e_mode_category(
+        storage: &dyn Storage,
+        position: &Position,
+    ) -> StdResult<Option<(u8, EModeCategory)>> {
+        let Some(id) = TOKEN_E_MODE.may_load(storage, &token_to_string(&position.borrow_token))?
+        else {
+            return Ok(None);
+        };
+        for (token, _) in &position.collateral {
+            if TOKEN_E_MODE.may_load(storage, &token_to_string(token))? != Some(id) {
+                return Ok(None);
+            }
+        }
+        Ok(Some((id, E_MODE_CATEGORIES.load(storage, id)?)))
+    }
+
+    /// E-mode category a position is valued in. Loans keep the parameters they started with,
+    /// as long as their tokens all stay in the category.
+    pub fn position_e_mode(
+        storage: &dyn Storage,
+        position: &Position,
+    ) -> StdResult<Option<(u8, EModeCategory)>> {
+        if !position.filled {
+            return e_mode_category(storage, position);
+        }
+        Ok(position.e_mode.clone().filter(|(_, category)| {
+            std::iter::once(&position.borrow_token)
+                .chain(position.collateral.iter().map(|(token, _)| token))
+                .all(|token| category.tokens.contains(&token_to_string(token)))
+        }))
+    }
+
+    /// Risk weight of a token as position collateral, 1 unless set by the admin
+    pub fn collateral_weight(storage: &dyn Storage, token: &Token) -> StdResult<Decimal> {
+        Ok(COLLATERAL_WEIGHTS
+            .may_load(storage, &token_to_string(token))?
+            .unwrap_or(Decimal::one()))
+    }
+
+    /// Value a position with oracle prices at time `now`
+    pub fn position_health(
+        deps: &Deps,
+        config: &Config,
+        position: &Position,
+        now: u64,
+    ) -> StdResult<PositionHealth> {
+        let borrow_price = query_price(deps, &config.mock_oracle, &position.borrow_token)?;
+        let interest = accrued_interest(position, now);
+        let total_debt = position.amount + interest;
+        let debt_value = total_debt * borrow_price;
+        let category = position_e_mode(deps.storage, position)?;
+
+        // E-mode positions value their collateral without risk weights
+        let mut collateral_prices = vec![];
+        let mut collateral_value = Uint128::zero();
+        let mut collateral_market_value = Uint128::zero();
+        let mut collateral_weighted_amount = Uint128::zero();
+        for (token, amount) in &position.collateral {
+            let price = query_price(deps, &config.mock_oracle, token)?;
+            let weight = match category {
+                Some(_) => Decimal::one(),
+                None => collateral_weight(deps.storage, token)?,
+            };
+            collateral_market_value += *amount * price;
+            collateral_value += (*amount * price).mul_floor(weight);
+            collateral_weighted_amount = amount.mul_floor(weight);
+            collateral_prices.push((token.clone(), price));
+        }
+        let (e_mode_category, max_ltv, liquidation_threshold, liquidation_bonus) = match category {
+            Some((id, category)) => (
+                Some(id),
+                category.max_ltv,
+                category.liquidation_threshold,
+                category.liquidation_bonus,
+            ),
+            None => (
+                None,
+                Decimal::checked_from_ratio(100u128, config.liquidation_threshold)
+                    .unwrap_or(Decimal::MAX),
+                config.liquidation_threshold,
+                Uint128::from(DEFAULT_LIQUIDATION_BONUS),
+            ),
+        };
+        let threshold_value = debt_value * liquidation_threshold / Uint128::from(100u128);
+
+        Ok(PositionHealth {
+            collateral_value,
+            debt_value,
+            total_debt,
+            interest,
+            ltv: Decimal::checked_from_ratio(debt_value, collateral_value).unwrap_or(Decimal::MAX),
+            health_factor: health_factor(collateral_value, threshold_value),
+            liquidation_price: match position.collateral.len() {
+                1 => Some(
+                    Decimal::checked_from_ratio(
+                        debt_value * liquidation_threshold,
+                        collateral_weighted_amount * Uint128::from(100u128),
+                    )
+                    .unwrap_or(Decimal::MAX),
+                ),
+                _ => None,
+            },
+            // Overdue term loans are liquidatable whatever their collateral
+            liquidatable: position.filled
+                && (collateral_value < threshold_value
+                    || position.maturity.is_some_and(|maturity| now >= maturity)),
+            collateral_market_value,
+            collateral_prices,
+            borrow_price,
+            e_mode_category,
+            max_ltv,
+            liquidation_threshold,
+            liquidation_bonus,
+        })
+    }
+
+    /// Split repaid debt into the protocol's liquidation fee and the lender's share
+    pub fn liquidation_shares(total_debt: Uint128, fee: Uint128) -> (Uint128, Uint128) {
+        let protocol_share = total_debt.multiply_ratio(fee, 10_000u128);
+        (protocol_share, total_debt - protocol_share)
+    }
+
+    /// Room left under a cap, None if uncapped
+    pub fn cap_headroom(cap: Option<Uint128>, current: Uint128) -> Option<Uint128> {
+        cap.map(|cap| cap.saturating_sub(current))
+    }
+
+    /// Reject adding `amount` on top of `current` if it would exceed `cap`
+    fn check_cap(
+        name: &str,
+        cap: Option<Uint128>,
+        current: Uint128,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        match cap_headroom(cap, current) {
+            Some(headroom) if amount > headroom => Err(StdError::generic_err(format!(
+                "{} cap exceeded: {} available",
+                name, headroom
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Principal backed by an isolated collateral per borrow token, and its total value
+    pub fn isolated_debt(
+        deps: &Deps,
+        config: &Config,
+        collateral_token: &str,
+    ) -> StdResult<(Vec<(Token, Uint128)>, Uint128)> {
+        let mut debt = vec![];
+        let mut value = Uint128::zero();
+        for item in ISOLATED_DEBT.prefix(collateral_token).range(
+            deps.storage,
+            None,
+            None,
+            cosmwasm_std::Order::Ascending,
+        ) {
+            let (borrow_token, amount) = item?;
+            let borrow_token = determine_token_type(deps, &borrow_token)?;
+            value += query_price(deps, &config.mock_oracle, &borrow_token)? * amount;
+            debt.push((borrow_token, amount));
+        }
+        Ok((debt, value))
+    }
+
+    /// Reject borrowing `amount` against an isolated collateral beyond its debt ceiling
+    fn check_debt_ceiling(
+        deps: &Deps,
+        config: &Config,
+        collateral_token: &str,
+        isolation: &Isolation,
+        borrow_token: &Token,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        let (_, debt_value) = isolated_debt(deps, config, collateral_token)?;
+        let amount_value = query_price(deps, &config.mock_oracle, borrow_token)? * amount;
+        if debt_value + amount_value > isolation.debt_ceiling {
+            return Err(StdError::generic_err(format!(
+                "Debt ceiling exceeded: {} available",
+                isolation.debt_ceiling.saturating_sub(debt_value)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Amounts moved by a fixed liquidation of one collateral token
+    pub struct LiquidationAmounts {
+        pub repay_amount: Uint128,        // Paid by the liquidator, in borrow token
+        pub lender_share: Uint128,        // Sent to the lender, before insurance cover
+        pub protocol_share: Uint128,      // Liquidation fee kept in reserves
+        pub collateral_payout: Uint128,   // Sent to the liquidator
+        pub collateral_returned: Uint128, // Rest of the token, sent back to the borrower
+        pub shortfall: Uint128,           // Debt the liquidation leaves unpaid
+        pub debt_covered: Uint128,        // Debt settled, the token's share of the total debt
+    }
+
+    /// Work out a fixed liquidation of the collateral at `index`, settling the share of
+    /// the debt backed by its market value. The liquidator receives collateral worth the
+    /// repaid debt plus the liquidation bonus and the rest goes back to the borrower. When
+    /// the position is underwater the liquidator takes all of the token, paying its value
+    /// less the bonus. Fails when an oracle price involved is missing.
+    pub fn liquidation_amounts(
+        position: &Position,
+        health: &PositionHealth,
+        index: usize,
+        fee: Uint128,
+    ) -> StdResult<LiquidationAmounts> {
+        let amount = position.collateral[index].1;
+        let price = health.collateral_prices[index].1;
+        if price.is_zero()
+            || health.borrow_price.is_zero()
+            || health.collateral_market_value.is_zero()
+        {
+            return Err(StdError::generic_err("Missing oracle price"));
+        }
+        let bonus_factor = Uint128::from(10_000u128) + health.liquidation_bonus;
+        let debt_covered = if position.collateral.len() == 1 {
+            health.total_debt
+        } else {
+            health
+                .total_debt
+                .multiply_ratio(amount * price, health.collateral_market_value)
+        };
+        if health.collateral_market_value >= health.debt_value {
+            let (protocol_share, lender_share) = liquidation_shares(debt_covered, fee);
+            let collateral_payout = (debt_covered * health.borrow_price)
+                .checked_multiply_ratio(bonus_factor, price * Uint128::from(10_000u128))
+                .unwrap_or(amount)
+                .min(amount);
+            return Ok(LiquidationAmounts {
+                repay_amount: debt_covered,
+                lender_share,
+                protocol_share,
+                collateral_payout,
+                collateral_returned: amount - collateral_payout,
+                shortfall: Uint128::zero(),
+                debt_covered,
+            });
+        }
+
+        // Underwater: the liquidator buys all of the token at the liquidation bonus
+        // discount and the whole payment goes to the lender
+        let repay_amount = (amount * price)
+            .multiply_ratio(
+                Uint128::from(10_000u128),
+                health.borrow_price * bonus_factor,
+            )
+            .min(debt_covered);
+        Ok(LiquidationAmounts {
+            repay_amount,
+            lender_share: repay_amount,
+            protocol_share: Uint128::zero(),
+            collateral_payout: amount,
+            collateral_returned: Uint128::zero(),
+            shortfall: debt_covered - repay_amount,
+            debt_covered,
+        })
+    }
+
+    /// Position of `token` in a collateral basket
+    pub fn collateral_index(position: &Position, token: &Token) -> StdResult<usize> {
+        position
+            .collateral
+            .iter()
+            .position(|(collateral, _)| collateral == token)
+            .ok_or_else(|| StdError::generic_err("Collateral token not in position"))
+    }
+
+    /// Remove a token from a collateral basket, returning its entry. A loan left on a single
+    /// token starts counting against that token's debt.
+    fn take_collateral(
+        storage: &mut dyn Storage,
+        position: &mut Position,
+        index: usize,
+    ) -> StdResult<(Token, Uint128)> {
+        if position.filled {
+            release_isolated_debt(storage, position, position.amount)?;
+        }
+        let (token, amount) = position.collateral.remove(index);
+        if position.filled {
+            add_isolated_debt(storage, position, position.amount)?;
+        }
+        update_market_stats(storage, &token, |stats| {
+            stats.total_collateral = stats.total_collateral.checked_sub(amount)?;
+            Ok(())
+        })?;
+        Ok((token, amount))
+    }
+
+    /// Restart a position with `remaining` debt as principal, settling interest accrued so far.
+    /// Lender shares are rescaled to the new principal.
+    fn reduce_debt(
+        storage: &mut dyn Storage,
+        position: &mut Position,
+        remaining: Uint128,
+        now: u64,
+    ) -> StdResult<()> {
+        let previous = position.amount;
+        update_market_stats(storage, &position.borrow_token, |stats| {
+            stats.total_lent = stats.total_lent.checked_sub(previous)? + remaining;
+            stats.total_borrowed = stats.total_borrowed.checked_sub(previous)? + remaining;
+            Ok(())
+        })?;
+        release_isolated_debt(storage, position, previous)?;
+        add_isolated_debt(storage, position, remaining)?;
+        for (_, share) in position.lenders.iter_mut() {
+            *share = share.multiply_ratio(remaining, previous);
+        }
+        position.amount = remaining;
+        // Rounding remainder goes to the last lender
+        let funded = funded_amount(position);
+        if let Some((_, share)) = position.lenders.last_mut() {
+            *share += remaining.checked_sub(funded)?;
+        }
+        position.start_time = now;
+        Ok(())
+    }
+
+    /// Render a collateral basket as `amount token` entries separated by commas
+    pub fn collateral_to_string(collateral: &[(Token, Uint128)]) -> String {
+        collateral
+            .iter()
+            .map(|(token, amount)| format!("{}{}", amount, token_to_string(token)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Message pulling `amount` of a cw20 token from the sender, native funds are only verified
+    pub fn receive_msg(
+        env: &Env,
+        info: &MessageInfo,
+        token: &Token,
+        amount: Uint128,
+    ) -> StdResult<Option<CosmosMsg>> {
+        match token {
+            Token::Native(denom) => {
+                verify_funds(&info.funds, denom, amount)?;
+                Ok(None)
+            }
+            Token::Cw20(addr) => Ok(Some(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: addr.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+                    owner: info.sender.to_string(),
+                    recipient: env.contract.address.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            }))),
+        }
+    }
+
+    /// Take collateral from the sender, either transferred with the message or moved
+    /// out of their deposit balance
+    fn receive_collateral(
+        storage: &mut dyn Storage,
+        env: &Env,
+        info: &MessageInfo,
+        token: &Token,
+        amount: Uint128,
+        source: &CollateralSource,
+    ) -> StdResult<Option<CosmosMsg>> {
+        if *source == CollateralSource::Wallet {
+            return receive_msg(env, info, token, amount);
+        }
+        let denom = token_to_string(token);
+        let deposit = deposit_balance(storage, &info.sender, &denom)?;
+        if deposit < amount {
+            return Err(StdError::generic_err("Insufficient deposit"));
+        }
+        let stats = MARKET_STATS.may_load(storage, &denom)?.unwrap_or_default();
+        if stats.total_deposited.saturating_sub(stats.pool_borrowed) < amount {
+            return Err(StdError::generic_err("Insufficient liquidity"));
+        }
+        save_deposit(storage, &info.sender, &denom, deposit - amount)?;
+        update_market_stats(storage, token, |stats| {
+            stats.total_deposited = stats.total_deposited.checked_sub(amount)?;
+            Ok(())
+        })?;
+        Ok(None)
+    }
+
+    /// Give collateral of a closing position back to its borrower, as a transfer or as a
+    /// deposit credit when the position asked for it
+    fn return_collateral(
+        storage: &mut dyn Storage,
+        position: &Position,
+        token: &Token,
+        amount: Uint128,
+    ) -> StdResult<Option<CosmosMsg>> {
+        if !position.return_to_deposits {
+            return Ok(Some(transfer_msg(token, &position.borrower, amount)?));
+        }
+        let denom = token_to_string(token);
+        let deposit = deposit_balance(storage, &position.borrower, &denom)?;
+        save_deposit(storage, &position.borrower, &denom, deposit + amount)?;
+        update_market_stats(storage, token, |stats| {
+            stats.total_deposited += amount;
+            Ok(())
+        })?;
+        Ok(None)
+    }
+
+    /// Balance of a token held by the contract
+    pub fn contract_balance(deps: &Deps, env: &Env, token: &Token) -> StdResult<Uint128> {
+        match token {
+            Token::Native(denom) => Ok(deps
+                .querier
+                .query_balance(&env.contract.address, denom)?
+                .amount),
+            Token::Cw20(addr) => {
+                let balance: BalanceResponse =
+                    deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                        contract_addr: addr.to_string(),
+                        msg: to_json_binary(&Cw20QueryMsg::Balance {
+                            address: env.contract.address.to_string(),
+                        })?,
+                    }))?;
+                Ok(balance.balance)
+            }
+        }
+    }
+
+    /// Router asset of a token
+    fn asset_info(token: &Token) -> AssetInfo {
+        match token {
+            Token::Native(denom) => AssetInfo::NativeToken {
+                denom: denom.clone(),
+            },
+            Token::Cw20(addr) => AssetInfo::Token {
+                contract_addr: addr.clone(),
+            },
+        }
+    }
+
+    /// Message selling `amount` of `from` for `to` through the router, the output coming
+    /// back to the contract
+    fn router_swap_msg(
+        router: &Addr,
+        from: &Token,
+        to: &Token,
+        amount: Uint128,
+        min_receive: Uint128,
+    ) -> StdResult<CosmosMsg> {
+        let operations = vec![SwapOperation::AstroSwap {
+            offer_asset_info: asset_info(from),
+            ask_asset_info: asset_info(to),
+        }];
+        Ok(match from {
+            Token::Native(denom) => CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: router.to_string(),
+                msg: to_json_binary(&RouterExecuteMsg::ExecuteSwapOperations {
+                    operations,
+                    minimum_receive: Some(min_receive),
+                    to: None,
+                    max_spread: None,
+                })?,
+                funds: vec![Coin {
+                    denom: denom.clone(),
+                    amount,
+                }],
+            }),
+            Token::Cw20(addr) => CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: addr.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::Send {
+                    contract: router.to_string(),
+                    amount,
+                    msg: to_json_binary(&RouterCw20HookMsg::ExecuteSwapOperations {
+                        operations,
+                        minimum_receive: Some(min_receive),
+                        to: None,
+                        max_spread: None,
+                    })?,
+                })?,
+                funds: vec![],
+            }),
+        })
+    }
+
+    /// Accrue interest on account debt up to `now`
+    pub fn accrue_account_debt(debt: &mut AccountDebt, borrow_rate: Uint128, now: u64) {
+        let elapsed = now.saturating_sub(debt.last_accrual);
+        debt.interest += debt.principal * borrow_rate * Uint128::from(elapsed)
+            / Uint128::from(31_536_000u64 * 100u64);
+        debt.last_accrual = now;
+    }
+
+    /// Apply a repayment of up to `amount` to account debt, interest first, returning the
+    /// amount used. Interest is shared between reserves and depositors, and repaid principal
+    /// goes back to the deposit pool.
+    fn repay_account_debt(
+        storage: &mut dyn Storage,
+        borrower: &Addr,
+        token: &Token,
+        amount: Uint128,
+        now: u64,
+    ) -> StdResult<Uint128> {
+        let token_key = token_to_string(token);
+        let key = (borrower, token_key.as_str());
+        let mut debt = ACCOUNT_DEBTS
+            .may_load(storage, key)?
+            .ok_or_else(|| StdError::generic_err("No debt in token"))?;
+        let rate = MARGIN_PARAMS
+            .may_load(storage, &token_key)?
+            .map(|params| params.borrow_rate)
+            .unwrap_or(Uint128::zero());
+        accrue_account_debt(&mut debt, rate, now);
+
+        let interest_paid = amount.min(debt.interest);
+        let principal_paid = (amount - interest_paid).min(debt.principal);
+        debt.interest -= interest_paid;
+        debt.principal -= principal_paid;
+        if debt.principal.is_zero() && debt.interest.is_zero() {
+            ACCOUNT_DEBTS.remove(storage, key);
+        } else {
+            ACCOUNT_DEBTS.save(storage, key, &debt)?;
+        }
+
+        distribute_interest(storage, token, interest_paid)?;
+        update_market_stats(storage, token, |stats| {
+            stats.total_borrowed = stats.total_borrowed.checked_sub(principal_paid)?;
+            stats.pool_borrowed = stats.pool_borrowed.checked_sub(principal_paid)?;
+            Ok(())
+        })?;
+        Ok(interest_paid + principal_paid)
+    }
+
+    /// Collateral, debts with interest up to `now`, and health of a cross-margin account
+    #[allow(clippy::type_complexity)]
+    pub fn margin_account(
+        deps: &Deps,
+        config: &Config,
+        address: &Addr,
+        now: u64,
+    ) -> StdResult<(
+        Vec<(Token, Uint128)>,
+        Vec<(Token, AccountDebt)>,
+        MarginAccountHealth,
+    )> {
+        let mut collateral = vec![];
+        let mut collateral_value = Uint128::zero();
+        let mut weighted_collateral_value = Uint128::zero();
+        let mut borrow_limit_value = Uint128::zero();
+        for item in ACCOUNT_COLLATERAL.prefix(address).range(
+            deps.storage,
+            None,
+            None,
+            cosmwasm_std::Order::Ascending,
+        ) {
+            let (token_key, amount) = item?;
+            let token = determine_token_type(deps, &token_key)?;
+            let value = amount * query_price(deps, &config.mock_oracle, &token)?;
+            let (weight, max_ltv) = MARGIN_PARAMS
+                .may_load(deps.storage, &token_key)?
+                .map(|params| (params.collateral_weight, params.max_ltv))
+                .unwrap_or_default();
+            collateral_value += value;
+            weighted_collateral_value += value.mul_floor(weight);
+            borrow_limit_value += value.mul_floor(max_ltv);
+            collateral.push((token, amount));
+        }
+
+        let mut debts = vec![];
+        let mut debt_value = Uint128::zero();
+        for item in ACCOUNT_DEBTS.prefix(address).range(
+            deps.storage,
+            None,
+            None,
+            cosmwasm_std::Order::Ascending,
+        ) {
+            let (token_key, mut debt) = item?;
+            let token = determine_token_type(deps, &token_key)?;
+            let rate = MARGIN_PARAMS
+                .may_load(deps.storage, &token_key)?
+                .map(|params| params.borrow_rate)
+                .unwrap_or(Uint128::zero());
+            accrue_account_debt(&mut debt, rate, now);
+            debt_value +=
+                (debt.principal + debt.interest) * query_price(deps, &config.mock_oracle, &token)?;
+            debts.push((token, debt));
+        }
+
+        let threshold_value = debt_value * config.liquidation_threshold / Uint128::from(100u128);
+        let health = MarginAccountHealth {
+            collateral_value,
+            weighted_collateral_value,
+            borrow_limit_value,
+            debt_value,
+            health_factor: health_factor(weighted_collateral_value, threshold_value),
+            borrow_capacity: borrow_limit_value.saturating_sub(debt_value),
+            liquidatable: weighted_collateral_value < threshold_value,
+        };
+        Ok((collateral, debts, health))
+    }
+
+    /// Reject a change leaving a cross-margin account liquidatable
+    fn ensure_account_healthy(deps: &Deps, address: &Addr, now: u64) -> StdResult<()> {
+        let config = CONFIG.load(deps.storage)?;
+        let (_, _, health) = margin_account(deps, &config, address, now)?;
+        if health.liquidatable {
+            return Err(StdError::generic_err(
+                "Account would be undercollateralized",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reject a change taking the debt of a cross-margin account beyond its borrow limit
+    fn ensure_within_borrow_limit(deps: &Deps, address: &Addr, now: u64) -> StdResult<()> {
+        let config = CONFIG.load(deps.storage)?;
+        let (_, _, health) = margin_account(deps, &config, address, now)?;
+        if health.debt_value > health.borrow_limit_value {
+            return Err(StdError::generic_err(
+                "Account would exceed its borrow limit",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether any cross-margin account holds or owes a token
+    fn margin_token_in_use(storage: &dyn Storage, token: &str) -> StdResult<bool> {
+        for key in ACCOUNT_COLLATERAL.keys(storage, None, None, cosmwasm_std::Order::Ascending) {
+            if key?.1 == token {
+                return Ok(true);
+            }
+        }
+        for key in ACCOUNT_DEBTS.keys(storage, None, None, cosmwasm_std::Order::Ascending) {
+            if key?.1 == token {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write off the debt of an account left without collateral. The insurance fund repays
+    /// what it can, and the rest of the principal is a loss spread over depositors.
+    fn write_off_account(deps: DepsMut, borrower: &Addr, now: u64) -> StdResult<Vec<Shortfall>> {
+        if ACCOUNT_COLLATERAL
+            .prefix(borrower)
+            .keys(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .next()
+            .is_some()
+        {
+            return Ok(vec![]);
+        }
+        let debts = ACCOUNT_DEBTS
+            .prefix(borrower)
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        let mut shortfalls = vec![];
+        for (token_key, mut debt) in debts {
+            let token = determine_token_type(&deps.as_ref(), &token_key)?;
+            let rate = MARGIN_PARAMS
+                .may_load(deps.storage, &token_key)?
+                .map(|params| params.borrow_rate)
+                .unwrap_or(Uint128::zero());
+            accrue_account_debt(&mut debt, rate, now);
+            let owed = debt.principal + debt.interest;
+            let covered = draw_insurance(deps.storage, &token, owed)?;
+            repay_account_debt(deps.storage, borrower, &token, covered, now)?;
+
+            let key = (borrower, token_key.as_str());
+            let remaining = ACCOUNT_DEBTS
+                .may_load(deps.storage, key)?
+                .unwrap_or_default();
+            ACCOUNT_DEBTS.remove(deps.storage, key);
+            update_market_stats(deps.storage, &token, |stats| {
+                stats.total_borrowed = stats.total_borrowed.saturating_sub(remaining.principal);
+                stats.pool_borrowed = stats.pool_borrowed.saturating_sub(remaining.principal);
+                Ok(())
+            })?;
+            debit_depositors(deps.storage, &token, remaining.principal)?;
+
+            let mut record = ACCOUNT_SHORTFALLS
+                .may_load(deps.storage, key)?
+                .unwrap_or(Shortfall {
+                    token: token.clone(),
+                    shortfall: Uint128::zero(),
+                    covered: Uint128::zero(),
+                    bad_debt: Uint128::zero(),
+                    timestamp: 0,
+                });
+            record.shortfall += owed;
+            record.covered += covered;
+            record.bad_debt += owed - covered;
+            record.timestamp = now;
+            ACCOUNT_SHORTFALLS.save(deps.storage, key, &record)?;
+            shortfalls.push(record);
+        }
+        Ok(shortfalls)
+    }
+
+    /// Deposit of a user in a token, with pool interest and losses since it last changed
+    pub fn deposit_balance(storage: &dyn Storage, user: &Addr, token: &str) -> StdResult<Uint128> {
+        let key = (user, token);
+        let deposit = DEPOSITS.may_load(storage, key)?.unwrap_or(Uint128::zero());
+        let index = DEPOSIT_INDEX
+            .may_load(storage, token)?
+            .unwrap_or(Decimal::one());
+        let checkpoint = DEPOSIT_CHECKPOINTS
+            .may_load(storage, key)?
+            .unwrap_or(Decimal::one());
+        if index == checkpoint {
+            return Ok(deposit);
+        }
+        Ok(deposit.multiply_ratio(index.atomics(), checkpoint.atomics()))
+    }
+
+    /// Store a user's deposit as of the current deposit index
+    fn save_deposit(
+        storage: &mut dyn Storage,
+        user: &Addr,
+        token: &str,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        let key = (user, token);
+        if amount.is_zero() {
+            DEPOSITS.remove(storage, key);
+            DEPOSIT_CHECKPOINTS.remove(storage, key);
+            return Ok(());
+        }
+        let index = DEPOSIT_INDEX
+            .may_load(storage, token)?
+            .unwrap_or(Decimal::one());
+        DEPOSITS.save(storage, key, &amount)?;
+        DEPOSIT_CHECKPOINTS.save(storage, key, &index)
+    }
+
+    /// Scale every deposit in a token so that they add up to `new_total` instead of `total`
+    fn rescale_deposits(
+        storage: &mut dyn Storage,
+        token: &Token,
+        total: Uint128,
+        new_total: Uint128,
+    ) -> StdResult<()> {
+        let key = token_to_string(token);
+        let index = DEPOSIT_INDEX
+            .may_load(storage, &key)?
+            .unwrap_or(Decimal::one())
+            .checked_mul(Decimal::from_ratio(new_total, total))?;
+        DEPOSIT_INDEX.save(storage, &key, &index)?;
+        update_market_stats(storage, token, |stats| {
+            stats.total_deposited = new_total;
+            Ok(())
+        })
+    }
+
+    /// Share interest paid by margin accounts between reserves and the depositors who lent
+    /// it, all of it going to reserves when the pool is empty
+    fn distribute_interest(
+        storage: &mut dyn Storage,
+        token: &Token,
+        interest: Uint128,
+    ) -> StdResult<()> {
+        let total = MARKET_STATS
+            .may_load(storage, &token_to_string(token))?
+            .unwrap_or_default()
+            .total_deposited;
+        if interest.is_zero() || total.is_zero() {
+            return add_reserves(storage, token, interest);
+        }
+        let reserve_share = interest.multiply_ratio(MARGIN_RESERVE_FACTOR, 10_000u128);
+        add_reserves(storage, token, reserve_share)?;
+        rescale_deposits(storage, token, total, total + interest - reserve_share)
+    }
+
+    /// Spread a loss of the deposit pool over all deposits in the token
+    fn debit_depositors(storage: &mut dyn Storage, token: &Token, loss: Uint128) -> StdResult<()> {
+        let total = MARKET_STATS
+            .may_load(storage, &token_to_string(token))?
+            .unwrap_or_default()
+            .total_deposited;
+        if loss.is_zero() || total.is_zero() {
+            return Ok(());
+        }
+        rescale_deposits(storage, token, total, total.saturating_sub(loss))
+    }
+
+    /// Credit protocol reserves of a token
+    pub fn add_reserves(
+        storage: &mut dyn Storage,
+        token: &Token,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        if amount.is_zero() {
+            return Ok(());
+        }
+        let key = token_to_string(token);
+        let reserves = RESERVES.may_load(storage, &key)?.unwrap_or(Uint128::zero());
+        RESERVES.save(storage, &key, &(reserves + amount))
+    }
+
+    /// Pay what the insurance fund can towards a shortfall and record the rest as bad debt
+    fn cover_shortfall(
+        storage: &mut dyn Storage,
+        env: &Env,
+        position_id: u128,
+        token: &Token,
+        shortfall: Uint128,
+    ) -> StdResult<Uint128> {
+        if shortfall.is_zero() {
+            return Ok(Uint128::zero());
+        }
+        let covered = draw_insurance(storage, token, shortfall)?;
+
+        // Partial liquidations of a basket add up into one record per position
+        let mut record = SHORTFALLS
+            .may_load(storage, position_id)?
+            .unwrap_or(Shortfall {
+                token: token.clone(),
+                shortfall: Uint128::zero(),
+                covered: Uint128::zero(),
+                bad_debt: Uint128::zero(),
+                timestamp: 0,
+            });
+        record.shortfall += shortfall;
+        record.covered += covered;
+        record.bad_debt += shortfall - covered;
+        record.timestamp = env.block.time.seconds();
+        SHORTFALLS.save(storage, position_id, &record)?;
+        Ok(covered)
+    }
+
+    /// Take what the insurance fund of a token can pay towards a shortfall, returning it
+    fn draw_insurance(
+        storage: &mut dyn Storage,
+        token: &Token,
+        shortfall: Uint128,
+    ) -> StdResult<Uint128> {
+        let key = token_to_string(token);
+        let mut fund = INSURANCE_FUNDS.may_load(storage, &key)?.unwrap_or_default();
+        let covered = shortfall.min(fund.balance);
+        fund.balance -= covered;
+        fund.total_covered += covered;
+        fund.total_bad_debt += shortfall - covered;
+        INSURANCE_FUNDS.save(storage, &key, &fund)?;
+        Ok(covered)
+    }
+
+    /// Collateral value over the debt value scaled by the liquidation threshold
+    pub fn health_factor(collateral_value: Uint128, threshold_value: Uint128) -> Option<Decimal> {
+        if threshold_value.is_zero() {
+            return None;
+        }
+        Some(Decimal::checked_from_ratio(collateral_value, threshold_value).unwrap_or(Decimal::MAX))
+    }
+
+    /// Apply a change to the aggregate statistics of a token
+    pub fn update_market_stats(
+        storage: &mut dyn Storage,
+        token: &Token,
+        action: impl FnOnce(&mut MarketStats) -> StdResult<()>,
+    ) -> StdResult<()> {
+        let key = token_to_string(token);
+        let mut stats = MARKET_STATS.may_load(storage, &key)?.unwrap_or_default();
+        action(&mut stats)?;
+        MARKET_STATS.save(storage, &key, &stats)
+    }
+
+    /// Collateral value per unit of requested value at oracle prices, orders open requests.
+    /// The whole basket counts, so requests are comparable across their collateral tokens.
+    pub fn collateralization_key(
+        deps: &Deps,
+        config: &Config,
+        position: &Position,
+    ) -> StdResult<u128> {
+        let borrow_value =
+            query_price(deps, &config.mock_oracle, &position.borrow_token)? * position.amount;
+        let mut collateral_value = Uint128::zero();
+        for (token, amount) in &position.collateral {
+            collateral_value += query_price(deps, &config.mock_oracle, token)? * *amount;
+        }
+        Ok(Decimal::checked_from_ratio(collateral_value, borrow_value)
+            .unwrap_or(Decimal::MAX)
+            .atomics()
+            .u128())
+    }
+
+    /// Convert positions stored with a single lender and collateral token, returning how
+    /// many were converted. Requests still open join the order book indexes.
+    pub fn migrate_positions(deps: DepsMut) -> StdResult<u64> {
+        let ids = POSITIONS
+            .keys(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut migrated = 0;
+        for id in ids {
+            if POSITIONS.load(deps.storage, id).is_ok() {
+                continue;
+            }
+            let legacy = LEGACY_POSITIONS.load(deps.storage, id)?;
+            let mut position = Position {
+                borrower: legacy.borrower,
+                lenders: legacy
+                    .lender
+                    .map(|lender| vec![(lender, legacy.amount)])
+                    .unwrap_or_default(),
+                borrow_token: legacy.borrow_token,
+                amount: legacy.amount,
+                interest_rate: legacy.interest_rate,
+                collateral: vec![(legacy.collateral_token, legacy.collateral)],
+                start_time: legacy.start_time,
+                filled: legacy.filled,
+                lender_nft: None,
+                refinance_count: 0,
+                term: None,
+                maturity: None,
+                allowed_lenders: None,
+                return_to_deposits: false,
+                e_mode: None,
+            };
+            if position.filled {
+                position.e_mode = e_mode_category(deps.storage, &position)?;
+            }
+            POSITIONS.save(deps.storage, id, &position)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+
+    /// Rebuild the order book indexes from the public unfilled positions, replacing entries
+    /// written by earlier versions without their sort keys
+    pub fn rebuild_order_book(mut deps: DepsMut) -> StdResult<()> {
+        OPEN_REQUESTS.clear(deps.storage);
+        OPEN_REQUESTS_BY_RATE.clear(deps.storage);
+        OPEN_REQUESTS_BY_COLLATERALIZATION.clear(deps.storage);
+        let requests = POSITIONS
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .filter(|item| {
+                item.as_ref().map_or(true, |(_, position)| {
+                    !position.filled && position.allowed_lenders.is_none()
+                })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        for (id, position) in requests {
+            index_open_request(deps.branch(), id, &position)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the statistics of every token from positions, deposits and margin accounts,
+    /// and the debt backed by each single collateral token, replacing whatever was tracked
+    /// before
+    pub fn backfill_market_stats(deps: DepsMut) -> StdResult<()> {
+        let mut markets: BTreeMap<String, MarketStats> = BTreeMap::new();
+        let mut loans = vec![];
+        for item in POSITIONS.range(deps.storage, None, None, cosmwasm_std::Order::Ascending) {
+            let (_, position) = item?;
+            for (token, amount) in &position.collateral {
+                markets
+                    .entry(token_to_string(token))
+                    .or_default()
+                    .total_collateral += *amount;
+            }
+            if position.filled {
+                let stats = markets
+                    .entry(token_to_string(&position.borrow_token))
+                    .or_default();
+                stats.total_lent += position.amount;
+                stats.total_borrowed += position.amount;
+                stats.active_positions += 1;
+                loans.push(position);
+            }
+        }
+        for item in DEPOSITS.keys(deps.storage, None, None, cosmwasm_std::Order::Ascending) {
+            let (user, token) = item?;
+            let amount = deposit_balance(deps.storage, &user, &token)?;
+            markets.entry(token).or_default().total_deposited += amount;
+        }
+        for item in
+            ACCOUNT_COLLATERAL.range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+        {
+            let ((_, token), amount) = item?;
+            markets.entry(token).or_default().total_collateral += amount;
+        }
+        for item in ACCOUNT_DEBTS.range(deps.storage, None, None, cosmwasm_std::Order::Ascending) {
+            let ((_, token), debt) = item?;
+            let stats = markets.entry(token).or_default();
+            stats.total_borrowed += debt.principal;
+            stats.pool_borrowed += debt.principal;
+        }
+
+        MARKET_STATS.clear(deps.storage);
+        for (token, stats) in markets {
+            MARKET_STATS.save(deps.storage, &token, &stats)?;
+        }
+        ISOLATED_DEBT.clear(deps.storage);
+        for position in loans {
+            add_isolated_debt(deps.storage, &position, position.amount)?;
+        }
+        Ok(())
+    }
+
+    /// Add a public borrow request to the order book indexes, keyed by its rate and its
+    /// collateralization at current prices
+    pub fn index_open_request(
+        deps: DepsMut,
+        position_id: u128,
+        position: &Position,
+    ) -> StdResult<()> {
+        let config = CONFIG.load(deps.storage)?;
+        let keys = OpenRequestKeys {
+            interest_rate: position.interest_rate,
+            collateralization: Uint128::new(collateralization_key(
+                &deps.as_ref(),
+                &config,
+                position,
+            )?),
+        };
+        let borrow = token_to_string(&position.borrow_token);
+        OPEN_REQUESTS_BY_RATE.save(
+            deps.storage,
+            (keys.interest_rate.u128(), position_id),
+            &Empty {},
+        )?;
+        for (token, _) in &position.collateral {
+            let collateral = token_to_string(token);
+            OPEN_REQUESTS_BY_COLLATERALIZATION.save(
+                deps.storage,
+                (
+                    (&borrow, &collateral),
+                    keys.collateralization.u128(),
+                    position_id,
+                ),
+                &Empty {},
+            )?;
+        }
+        OPEN_REQUESTS.save(deps.storage, position_id, &keys)
+    }
+
+    /// Remove a borrow request from the order book indexes, under the keys it was added with
+    pub fn unindex_open_request(storage: &mut dyn Storage, position_id: u128) -> StdResult<()> {
+        let Some(keys) = OPEN_REQUESTS.may_load(storage, position_id)? else {
+            return Ok(());
+        };
+        let position = POSITIONS.load(storage, position_id)?;
+        let borrow = token_to_string(&position.borrow_token);
+        OPEN_REQUESTS.remove(storage, position_id);
+        OPEN_REQUESTS_BY_RATE.remove(storage, (keys.interest_rate.u128(), position_id));
+        for (token, _) in &position.collateral {
+            let collateral = token_to_string(token);
+            OPEN_REQUESTS_BY_COLLATERALIZATION.remove(
+                storage,
+                (
+                    (&borrow, &collateral),
+                    keys.collateralization.u128(),
+                    position_id,
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    /// Reject a sender other than the borrower of a position or one of their operators
+    fn ensure_position_manager(
+        storage: &dyn Storage,
+        position: &Position,
+        sender: &Addr,
+    ) -> StdResult<()> {
+        if position.borrower != *sender && !OPERATORS.has(storage, (&position.borrower, sender)) {
+            return Err(StdError::generic_err("Not borrower or operator"));
+        }
+        Ok(())
+    }
+
+    /// Principal funded so far by the lenders of a position
+    pub fn funded_amount(position: &Position) -> Uint128 {
+        position.lenders.iter().map(|(_, share)| *share).sum()
+    }
+
+    /// Start a loan for the funded principal, sending it to the borrower and minting a
+    /// lender note per lender when lender notes are enabled. Isolated collateral takes on
+    /// the debt here, within its ceiling.
+    fn activate_position(
+        deps: DepsMut,
+        env: &Env,
+        position_id: u128,
+        position: &mut Position,
+    ) -> StdResult<Vec<CosmosMsg>> {
+        let funded = funded_amount(position);
+        if let [(collateral_token, _)] = position.collateral.as_slice() {
+            let key = token_to_string(collateral_token);
+            if let Some(isolation) = ISOLATED_TOKENS.may_load(deps.storage, &key)? {
+                let config = CONFIG.load(deps.storage)?;
+                check_debt_ceiling(
+                    &deps.as_ref(),
+                    &config,
+                    &key,
+                    &isolation,
+                    &position.borrow_token,
+                    funded,
+                )?;
+            }
+        }
+        let storage = deps.storage;
+        unindex_open_request(storage, position_id)?;
+        add_isolated_debt(storage, position, funded)?;
+        position.e_mode = e_mode_category(storage, position)?;
+        position.amount = funded;
+        position.filled = true;
+        position.start_time = env.block.time.seconds();
+        position.maturity = position.term.map(|term| position.start_time + term);
+        update_market_stats(storage, &position.borrow_token, |stats| {
+            stats.total_lent += funded;
+            stats.total_borrowed += funded;
+            stats.active_positions += 1;
+            Ok(())
+        })?;
+
+        let mut messages = vec![transfer_msg(
+            &position.borrow_token,
+            &position.borrower,
+            funded,
+        )?];
+        messages.extend(mint_lender_notes(storage, position_id, position)?);
+        Ok(messages)
+    }
+
+    /// Messages minting a note per lender of a position when lender notes are enabled
+    fn mint_lender_notes(
+        storage: &dyn Storage,
+        position_id: u128,
+        position: &mut Position,
+    ) -> StdResult<Vec<CosmosMsg>> {
+        position.lender_nft = CONFIG.load(storage)?.lender_nft;
+        let Some(nft) = &position.lender_nft else {
+            return Ok(vec![]);
+        };
+        position
+            .lenders
+            .iter()
+            .enumerate()
+            .map(|(i, (lender, _))| {
+                Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: nft.to_string(),
+                    msg: to_json_binary(&Cw721ExecuteMsg::Mint {
+                        token_id: lender_note_id(position_id, position, i),
+                        owner: lender.to_string(),
+                        token_uri: None,
+                        extension: None,
+                    })?,
+                    funds: vec![],
+                }))
+            })
+            .collect()
+    }
+
+    /// Cw721 token id of the note for the `index`-th lender of a position, unique across
+    /// refinancings
+    pub fn lender_note_id(position_id: u128, position: &Position, index: usize) -> String {
+        format!("{}-{}-{}", position_id, position.refinance_count, index)
+    }
+
+    /// Lenders of a position with their shares, replaced by the current note holders when
+    /// the lender side is tokenized
+    pub fn lender_holders(
+        deps: &Deps,
+        position_id: u128,
+        position: &Position,
+    ) -> StdResult<Vec<(Addr, Uint128)>> {
+        let Some(nft) = &position.lender_nft else {
+            return Ok(position.lenders.clone());
+        };
+        position
+            .lenders
+            .iter()
+            .enumerate()
+            .map(|(i, (_, share))| {
+                let owner: OwnerOfResponse =
+                    deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                        contract_addr: nft.to_string(),
+                        msg: to_json_binary(&Cw721QueryMsg::OwnerOf {
+                            token_id: lender_note_id(position_id, position, i),
+                            include_expired: None,
+                        })?,
+                    }))?;
+                Ok((deps.api.addr_validate(&owner.owner)?, *share))
+            })
+            .collect()
+    }
+
+    /// Messages paying `amount` to the lenders of a position (or the holders of their notes)
+    /// pro-rata to their shares, the rounding remainder going to the last lender. Cw20
+    /// payments are pulled from `payer` when given, and sent from the contract otherwise.
+    pub fn lender_payments(
+        deps: &Deps,
+        position_id: u128,
+        position: &Position,
+        amount: Uint128,
+        payer: Option<&Addr>,
+    ) -> StdResult<Vec<CosmosMsg>> {
+        let holders = lender_holders(deps, position_id, position)?;
+        let total = funded_amount(position);
+        let mut remaining = amount;
+        let mut messages = vec![];
+        for (i, (lender, share)) in holders.iter().enumerate() {
+            let payment = if i + 1 == holders.len() {
+                remaining
+            } else {
+                amount.multiply_ratio(*share, total)
+            };
+            remaining -= payment;
+            if payment.is_zero() {
+                continue;
+            }
+            messages.push(match (&position.borrow_token, payer) {
+                (Token::Cw20(addr), Some(payer)) => CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: addr.to_string(),
+                    msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+                        owner: payer.to_string(),
+                        recipient: lender.to_string(),
+                        amount: payment,
+                    })?,
+                    funds: vec![],
+                }),
+                _ => transfer_msg(&position.borrow_token, lender, payment)?,
+            });
+        }
+        Ok(messages)
+    }
+
+    /// Add `amount` to the debt a single collateral token backs for a position. It is
+    /// tracked whether or not the token is isolated, so isolating it later counts the loans
+    /// already open against it.
+    fn add_isolated_debt(
+        storage: &mut dyn Storage,
+        position: &Position,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        if let [(collateral_token, _)] = position.collateral.as_slice() {
+            let key = (
+                token_to_string(collateral_token),
+                token_to_string(&position.borrow_token),
+            );
+            let debt = ISOLATED_DEBT
+                .may_load(storage, (&key.0, &key.1))?
+                .unwrap_or(Uint128::zero());
+            ISOLATED_DEBT.save(storage, (&key.0, &key.1), &(debt + amount))?;
+        }
+        Ok(())
+    }
+
+    /// Release `amount` of the debt a single collateral token backs for a position
+    fn release_isolated_debt(
+        storage: &mut dyn Storage,
+        position: &Position,
+        amount: Uint128,
+    ) -> StdResult<()> {
+        // Isolated collateral always backs a position alone
+        if let [(collateral_token, _)] = position.collateral.as_slice() {
+            let key = (
+                token_to_string(collateral_token),
+                token_to_string(&position.borrow_token),
+            );
+            if let Some(debt) = ISOLATED_DEBT.may_load(storage, (&key.0, &key.1))? {
+                ISOLATED_DEBT.save(storage, (&key.0, &key.1), &debt.saturating_sub(amount))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a closed filled position from the market statistics. Positions opened before
+    /// the statistics existed are counted in them by migrate.
+    fn close_position_stats(storage: &mut dyn Storage, position: &Position) -> StdResult<()> {
+        release_isolated_debt(storage, position, position.amount)?;
+        update_market_stats(storage, &position.borrow_token, |stats| {
+            stats.total_lent = stats.total_lent.checked_sub(position.amount)?;
+            stats.total_borrowed = stats.total_borrowed.checked_sub(position.amount)?;
+            stats.active_positions = stats.active_positions.saturating_sub(1);
+            Ok(())
+        })?;
+        for (token, amount) in &position.collateral {
+            update_market_stats(storage, token, |stats| {
+                stats.total_collateral = stats.total_collateral.checked_sub(*amount)?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Verify sufficient funds for native token transfers
+    fn verify_funds(funds: &[Coin], denom: &str, amount: Uint128) -> StdResult<()> {
+        let sent = funds
+            .iter()
+            .find(|c| c.denom == denom)
+            .map(|c| c.amount)
+            .unwrap_or(Uint128::zero());
+        if sent < amount {
+            Err(StdError::generic_err("Insufficient funds"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::GetTokenConfigs {} => to_json_binary(&query_token_configs(deps)?),
+        QueryMsg::GetUserInfo { address } => to_json_binary(&query_user_info(deps, address)?),
+        QueryMsg::GetPosition { position_id } => {
+            to_json_binary(&query_position(deps, position_id)?)
+        }
+        QueryMsg::GetAllPositions { start_after, limit } => {
+            to_json_binary(&query_all_positions(deps, start_after, limit)?)
+        }
+        QueryMsg::GetConfig {} => to_json_binary(&query_config(deps)?),
+        QueryMsg::GetPositionHealth { position_id } => {
+            to_json_binary(&query_position_health(deps, env, position_id)?)
+        }
+        QueryMsg::GetAccountHealth { address } => {
+            to_json_binary(&query_account_health(deps, env, address)?)
+        }
+        QueryMsg::GetLiquidatablePositions { start_after, limit } => to_json_binary(
+            &query_liquidatable_positions(deps, env, start_after, limit)?,
+        ),
+        QueryMsg::SimulateRepay {
+            position_id,
+            at_time,
+        } => to_json_binary(&query_simulate_repay(deps, env, position_id, at_time)?),
+        QueryMsg::SimulateLiquidation {
+            position_id,
+            collateral_token,
+        } => to_json_binary(&query_simulate_liquidation(
+            deps,
+            env,
+            position_id,
+            collateral_token,
+        )?),
+        QueryMsg::SimulateBorrow {
+            borrow_token,
+            amount,
+            interest_rate,
+            collateral,
+        } => to_json_binary(&query_simulate_borrow(
+            deps,
+            env,
+            borrow_token,
+            amount,
+            interest_rate,
+            collateral,
+        )?),
+        QueryMsg::GetMarketStats { token } => to_json_binary(&query_market_stats(deps, token)?),
+        QueryMsg::GetProtocolStats {} => to_json_binary(&query_protocol_stats(deps)?),
+        QueryMsg::GetInsuranceFunds {} => to_json_binary(&query_insurance_funds(deps)?),
+        QueryMsg::GetShortfalls { start_after, limit } => {
+            to_json_binary(&query_shortfalls(deps, start_after, limit)?)
+        }
+        QueryMsg::GetIsolation { token } => to_json_binary(&query_isolation(deps, token)?),
+        QueryMsg::GetEModeCategories {} => to_json_binary(&query_e_mode_categories(deps)?),
+        QueryMsg::GetMarginAccount { address } => {
+            to_json_binary(&query_margin_account(deps, env, address)?)
+        }
+        QueryMsg::GetMarginParams {} => to_json_binary(&query_margin_params(deps)?),
+        QueryMsg::GetLenderNotes { position_id } => {
+            to_json_binary(&query_lender_notes(deps, position_id)?)
+        }
+        QueryMsg::GetExtensionProposal { position_id } => {
+            to_json_binary(&query_extension_proposal(deps, position_id)?)
+        }
+        QueryMsg::GetPositionHistory { position_id } => {
+            to_json_binary(&query_position_history(deps, position_id)?)
+        }
+        QueryMsg::GetOperators { borrower } => to_json_binary(&query_operators(deps, borrower)?),
+        QueryMsg::GetCounterOffers { position_id } => {
+            to_json_binary(&query_counter_offers(deps, position_id)?)
+        }
+        QueryMsg::GetOfferKey { lender } => to_json_binary(&query_offer_key(deps, lender)?),
+        QueryMsg::GetOfferNonce { lender, nonce } => {
+            to_json_binary(&query_offer_nonce(deps, lender, nonce)?)
+        }
+        QueryMsg::GetAuction { position_id } => {
+            to_json_binary(&query_auction(deps, env, position_id)?)
+        }
+        QueryMsg::GetAuctions { start_after, limit } => {
+            to_json_binary(&query_auctions(deps, env, start_after, limit)?)
+        }
+        QueryMsg::GetOpenRequests {
+            filter,
+            sort,
+            start_after,
+            limit,
+        } => to_json_binary(&query_open_requests(
+            deps,
+            filter,
+            sort,
+            start_after,
+            limit,
+        )?),
+    }
+}
+
+pub mod query {
+    use std::collections::BTreeMap;
+
+    use cosmwasm_std::{Decimal, Order, StdError};
+    use cw_storage_plus::Bound;
+
+    use crate::{
+        msg::{
+            AccountHealthResponse, AuctionInfo, AuctionResponse, AuctionsResponse, ConfigResponse,
+            CounterOffersResponse, EModeCategoriesResponse, ExtensionProposalResponse,
+            InsuranceFundInfo, InsuranceFundsResponse, IsolationResponse, LenderNote,
+            LenderNotesResponse, LiquidatablePosition, LiquidatablePositionsResponse,
+            LiquidationMode, MarginAccountResponse, MarginParamsResponse, MarketStatsResponse,
+            OfferKeyResponse, OfferNonceResponse, OpenRequest, OpenRequestsCursor,
+            OpenRequestsFilter, OpenRequestsResponse, OpenRequestsSort, OperatorsResponse,
+            PositionHealthResponse, PositionHistoryResponse, PositionResponse, PositionsResponse,
+            ProtocolStatsResponse, ShortfallsResponse, SimulateBorrowResponse,
+            SimulateLiquidationResponse, SimulateRepayResponse, TokenConfig, TokenConfigsResponse,
+            UserInfo, UserInfoResponse,
+        },
+        state::{
+            Auction, Config, Deposit, MarketStats, Position, Token, ACCOUNT_SHORTFALLS, AUCTIONS,
+            CANCELLED_NONCES, COUNTER_OFFERS, DEPOSITS, EXTENSION_PROPOSALS, E_MODE_CATEGORIES,
+            INSURANCE_FUNDS, ISOLATED_TOKENS, MARGIN_PARAMS, MARKET_STATS, OFFER_KEYS,
+            OPEN_REQUESTS, OPEN_REQUESTS_BY_COLLATERALIZATION, OPEN_REQUESTS_BY_RATE, OPERATORS,
+            POSITIONS, POSITION_HISTORY, RESERVES, SHORTFALLS, TOKEN_CAPS, USED_NONCES,
+        },
+    };
+
+    use super::{
+        execute::{
+            accrued_interest, auction_discount, cap_headroom, collateral_index, collateral_weight,
+            deposit_balance, determine_token_type, e_mode_category, health_factor, isolated_debt,
+            lender_holders, lender_note_id, liquidation_amounts, margin_account, position_health,
+            query_price, token_to_string,
+        },
+        *,
+    };
+
+    /// Query supported tokens
+    pub fn query_token_configs(deps: Deps) -> StdResult<TokenConfigsResponse> {
+        let tokens: Vec<TokenConfig> = SUPPORTED_TOKENS
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .map(|item: Result<(String, bool), StdError>| {
+                let (token_str, is_supported) = item?;
+                let token = determine_token_type(&deps, &token_str)?;
+                Ok(TokenConfig {
+                    isolated: ISOLATED_TOKENS.has(deps.storage, &token_str),
+                    collateral_weight: collateral_weight(deps.storage, &token)?,
+                    token,
+                    is_supported,
+                })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(TokenConfigsResponse { tokens })
+    }
+
+    /// Query user deposits and positions
+    pub fn query_user_info(deps: Deps, address: String) -> StdResult<UserInfoResponse> {
+        let addr = deps.api.addr_validate(&address)?;
+        let deposits: Vec<Deposit> = DEPOSITS
+            .prefix(&addr)
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .map(|item: Result<(String, Uint128), StdError>| {
+                let (token_str, _) = item?;
+                let amount = deposit_balance(deps.storage, &addr, &token_str)?;
+                let token = determine_token_type(&deps, &token_str)?;
+                Ok(Deposit { token, amount })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        let positions: Vec<(Uint128, Position)> = POSITIONS
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .filter_map(|item| {
+                let (id, pos) = item.ok()?;
+                if pos.borrower == addr || pos.lenders.iter().any(|(lender, _)| *lender == addr) {
+                    Some((Uint128::new(id), pos))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(UserInfoResponse {
+            user_info: if deposits.is_empty() && positions.is_empty() {
+                None
+            } else {
+                Some(UserInfo {
+                    deposits,
+                    positions,
+                })
+            },
+        })
+    }
+
+    /// Query a specific position
+    pub fn query_position(deps: Deps, position_id: Uint128) -> StdResult<PositionResponse> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        Ok(PositionResponse { position })
+    }
+
+    /// Query all positions with pagination
+    pub fn query_all_positions(
+        deps: Deps,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    ) -> StdResult<PositionsResponse> {
+        let limit = limit.unwrap_or(100).min(100) as usize;
+        let start = start_after.map(|id| Bound::exclusive(id.u128()));
+
+        let positions = POSITIONS
+            .range(deps.storage, start, None, cosmwasm_std::Order::Ascending)
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(PositionsResponse { positions })
+    }
+
+    /// Query a protocol config
+    pub fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        Ok(ConfigResponse { config })
+    }
+
+    /// Query health metrics of a position at the current block time
+    pub fn query_position_health(
+        deps: Deps,
+        env: Env,
+        position_id: Uint128,
+    ) -> StdResult<PositionHealthResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        let health = position_health(&deps, &config, &position, env.block.time.seconds())?;
+        Ok(PositionHealthResponse { health })
+    }
+
+    /// Query aggregate health over all filled positions of a borrower
+    pub fn query_account_health(
+        deps: Deps,
+        env: Env,
+        address: String,
+    ) -> StdResult<AccountHealthResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let addr = deps.api.addr_validate(&address)?;
+
+        let mut collateral_value = Uint128::zero();
+        let mut debt_value = Uint128::zero();
+        let mut threshold_value = Uint128::zero();
+        let mut liquidatable = false;
+        let mut positions = vec![];
+        for item in POSITIONS.range(deps.storage, None, None, cosmwasm_std::Order::Ascending) {
+            let (id, position) = item?;
+            if position.borrower != addr || !position.filled {
+                continue;
+            }
+            let health = position_health(&deps, &config, &position, env.block.time.seconds())?;
+            collateral_value += health.collateral_value;
+            debt_value += health.debt_value;
+            threshold_value +=
+                health.debt_value * health.liquidation_threshold / Uint128::from(100u128);
+            liquidatable |= health.liquidatable;
+            positions.push((Uint128::new(id), health));
+        }
+
+        Ok(AccountHealthResponse {
+            collateral_value,
+            debt_value,
+            ltv: Decimal::checked_from_ratio(debt_value, collateral_value).unwrap_or(Decimal::MAX),
+            health_factor: health_factor(collateral_value, threshold_value),
+            liquidatable,
+            positions,
+        })
+    }
+
+    /// Query filled positions that can be liquidated at the current block time
+    pub fn query_liquidatable_positions(
+        deps: Deps,
+        env: Env,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    ) -> StdResult<LiquidatablePositionsResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let limit = limit.unwrap_or(100).min(100) as usize;
+        let start = start_after.map(|id| Bound::exclusive(id.u128()));
+        let mode = if config.auction.is_some() {
+            LiquidationMode::Auction
+        } else {
+            LiquidationMode::FixedPrice
+        };
+
+        // The limit bounds the positions scanned, each costing oracle queries, rather than
+        // the positions returned. Positions already under auction are left to their bidders.
+        let mut positions = vec![];
+        let mut scanned = 0;
+        let mut last_scanned = None;
+        for item in POSITIONS
+            .range(deps.storage, start, None, cosmwasm_std::Order::Ascending)
+            .take(limit)
+        {
+            let (id, position) = item?;
+            scanned += 1;
+            last_scanned = Some(Uint128::new(id));
+            if !position.filled || AUCTIONS.has(deps.storage, id) {
+                continue;
+            }
+            let health = position_health(&deps, &config, &position, env.block.time.seconds())?;
+            if !health.liquidatable {
+                continue;
+            }
+            // Price the liquidation of the most valuable collateral token
+            let index = (0..position.collateral.len())
+                .max_by_key(|&i| position.collateral[i].1 * health.collateral_prices[i].1)
+                .unwrap_or(0);
+            // Without a price for it the position can't be liquidated at a fixed price yet
+            let Ok(amounts) =
+                liquidation_amounts(&position, &health, index, config.liquidation_fee)
+            else {
+                continue;
+            };
+            positions.push(LiquidatablePosition {
+                position_id: Uint128::new(id),
+                mode: mode.clone(),
+                collateral_token: position.collateral[index].0.clone(),
+                repay_amount: amounts.repay_amount,
+                collateral_payout: amounts.collateral_payout,
+                position,
+                health,
+            });
+        }
+        Ok(LiquidatablePositionsResponse {
+            positions,
+            next_start_after: if scanned == limit { last_scanned } else { None },
+        })
+    }
+
+    /// Simulate repaying a position at `at_time` (defaults to the current block time)
+    pub fn query_simulate_repay(
+        deps: Deps,
+        env: Env,
+        position_id: Uint128,
+        at_time: Option<u64>,
+    ) -> StdResult<SimulateRepayResponse> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+
+        let interest = accrued_interest(&position, at_time.unwrap_or(env.block.time.seconds()));
+        Ok(SimulateRepayResponse {
+            borrow_token: position.borrow_token,
+            principal: position.amount,
+            interest,
+            total_repayment: position.amount + interest,
+            collateral_released: position.collateral,
+        })
+    }
+
+    /// Simulate liquidating a position at the current block time
+    pub fn query_simulate_liquidation(
+        deps: Deps,
+        env: Env,
+        position_id: Uint128,
+        collateral_token: String,
+    ) -> StdResult<SimulateLiquidationResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        if !position.filled {
+            return Err(StdError::generic_err("Position not filled"));
+        }
+        let collateral_token = determine_token_type(&deps, &collateral_token)?;
+        let index = collateral_index(&position, &collateral_token)?;
+
+        let health = position_health(&deps, &config, &position, env.block.time.seconds())?;
+        let amounts = liquidation_amounts(&position, &health, index, config.liquidation_fee)?;
+        let insurance = INSURANCE_FUNDS
+            .may_load(deps.storage, &token_to_string(&position.borrow_token))?
+            .unwrap_or_default();
+        Ok(SimulateLiquidationResponse {
+            repay_amount: amounts.repay_amount,
+            interest: health.interest,
+            lender_share: amounts.lender_share,
+            protocol_share: amounts.protocol_share,
+            collateral_seized: amounts.collateral_payout,
+            collateral_returned: amounts.collateral_returned,
+            shortfall: amounts.shortfall,
+            insurance_cover: amounts.shortfall.min(insurance.balance),
+            borrow_token: position.borrow_token,
+            collateral_token,
+            health,
+        })
+    }
+
+    /// Simulate a borrow request as if it were filled at the current block time
+    pub fn query_simulate_borrow(
+        deps: Deps,
+        env: Env,
+        borrow_token: String,
+        amount: Uint128,
+        interest_rate: Uint128,
+        collateral: Vec<(String, Uint128)>,
+    ) -> StdResult<SimulateBorrowResponse> {
+        if !SUPPORTED_TOKENS
+            .may_load(deps.storage, &borrow_token)?
+            .unwrap_or(false)
+        {
+            return Err(StdError::generic_err("Unsupported token"));
+        }
+        let mut basket = vec![];
+        for (token, amount) in collateral {
+            if !SUPPORTED_TOKENS
+                .may_load(deps.storage, &token)?
+                .unwrap_or(false)
+            {
+                return Err(StdError::generic_err("Unsupported token"));
+            }
+            basket.push((determine_token_type(&deps, &token)?, amount));
+        }
+
+        let config = CONFIG.load(deps.storage)?;
+        let mut position = Position {
+            // Placeholder borrower, valuation does not depend on it
+            borrower: env.contract.address.clone(),
+            lenders: vec![],
+            borrow_token: determine_token_type(&deps, &borrow_token)?,
+            amount,
+            interest_rate,
+            collateral: basket,
+            start_time: env.block.time.seconds(),
+            filled: true,
+            lender_nft: None,
+            refinance_count: 0,
+            term: None,
+            maturity: None,
+            allowed_lenders: None,
+            return_to_deposits: false,
+            e_mode: None,
+        };
+        position.e_mode = e_mode_category(deps.storage, &position)?;
+        let health = position_health(&deps, &config, &position, env.block.time.seconds())?;
+        Ok(SimulateBorrowResponse {
+            collateral_required: position.collateral,
+            borrow_token: position.borrow_token,
+            amount_received: amount,
+            health,
+        })
+    }
+
+    /// Query aggregate statistics of a token
+    pub fn query_market_stats(deps: Deps, token: String) -> StdResult<MarketStatsResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let stats = MARKET_STATS
+            .may_load(deps.storage, &token)?
+            .unwrap_or_default();
+        market_stats_response(deps, &config, &token, stats)
+    }
+
+    /// Query aggregate statistics over every tracked token
+    pub fn query_protocol_stats(deps: Deps) -> StdResult<ProtocolStatsResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let markets = MARKET_STATS
+            .range(deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .map(|item| {
+                let (token, stats) = item?;
+                market_stats_response(deps, &config, &token, stats)
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+
+        let mut response = ProtocolStatsResponse {
+            total_deposited_value: Uint128::zero(),
+            total_lent_value: Uint128::zero(),
+            total_borrowed_value: Uint128::zero(),
+            total_collateral_value: Uint128::zero(),
+            total_value_locked: Uint128::zero(),
+            active_positions: 0,
+            markets: vec![],
+        };
+        for market in &markets {
+            response.total_deposited_value += market.deposited_value;
+            response.total_lent_value += market.lent_value;
+            response.total_borrowed_value += market.borrowed_value;
+            response.total_collateral_value += market.collateral_value;
+            response.total_value_locked += market.deposited_value + market.collateral_value;
+            response.active_positions += market.stats.active_positions;
+        }
+        response.markets = markets;
+        Ok(response)
+    }
+
+    /// Price the statistics of a token with the oracle
+    fn market_stats_response(
+        deps: Deps,
+        config: &Config,
+        token: &str,
+        stats: MarketStats,
+    ) -> StdResult<MarketStatsResponse> {
+        let token = determine_token_type(&deps, token)?;
+        let price = query_price(&deps, &config.mock_oracle, &token)?;
+        let supplied = stats.total_deposited + stats.total_lent;
+        let caps = TOKEN_CAPS
+            .may_load(deps.storage, &token_to_string(&token))?
+            .unwrap_or_default();
+        Ok(MarketStatsResponse {
+            supply_headroom: cap_headroom(caps.supply_cap, supplied),
+            borrow_headroom: cap_headroom(caps.borrow_cap, stats.total_borrowed),
+            collateral_headroom: cap_headroom(caps.collateral_cap, stats.total_collateral),
+            caps,
+            deposited_value: stats.total_deposited * price,
+            lent_value: stats.total_lent * price,
+            borrowed_value: stats.total_borrowed * price,
+            collateral_value: stats.total_collateral * price,
+            utilization: Decimal::checked_from_ratio(stats.total_lent, supplied)
+                .unwrap_or(Decimal::zero()),
+            token,
+            price,
+            stats,
+        })
+    }
+
+    /// Query open borrow requests matching a filter, in the requested order
+    pub fn query_open_requests(
+        deps: Deps,
+        filter: Option<OpenRequestsFilter>,
+        sort: Option<OpenRequestsSort>,
+        start_after: Option<OpenRequestsCursor>,
+        limit: Option<u32>,
+    ) -> StdResult<OpenRequestsResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let filter = filter.unwrap_or_default();
+        let limit = limit.unwrap_or(100).min(100) as usize;
+        let borrow_token = filter
+            .borrow_token
+            .map(|token| determine_token_type(&deps, &token))
+            .transpose()?;
+        let collateral_token = filter
+            .collateral_token
+            .map(|token| determine_token_type(&deps, &token))
+            .transpose()?;
+        let borrow_str = borrow_token.as_ref().map(token_to_string);
+        let collateral_str = collateral_token.as_ref().map(token_to_string);
+
+        // Walk the index matching the sort order, resuming after the cursor's sort keys so
+        // paging goes on even once the cursor's own request has left the order book
+        let ids: Box<dyn Iterator<Item = StdResult<u128>> + '_> = match sort
+            .unwrap_or(OpenRequestsSort::Newest)
+        {
+            OpenRequestsSort::Newest => {
+                let max = start_after.map(|cursor| Bound::exclusive(cursor.position_id.u128()));
+                Box::new(OPEN_REQUESTS.keys(deps.storage, None, max, Order::Descending))
+            }
+            OpenRequestsSort::RateDesc => {
+                let min = filter
+                    .min_rate
+                    .map(|rate| Bound::inclusive((rate.u128(), 0u128)));
+                let max = start_after.map(|cursor| {
+                    Bound::exclusive((cursor.interest_rate.u128(), cursor.position_id.u128()))
+                });
+                Box::new(
+                    OPEN_REQUESTS_BY_RATE
+                        .keys(deps.storage, min, max, Order::Descending)
+                        .map(|key| key.map(|(_, id)| id)),
+                )
+            }
+            OpenRequestsSort::Collateralization => {
+                let (Some(borrow), Some(collateral)) = (&borrow_str, &collateral_str) else {
+                    return Err(StdError::generic_err(
+                        "Collateralization sort requires borrow and collateral token filters",
+                    ));
+                };
+                let max = start_after.map(|cursor| {
+                    Bound::exclusive((cursor.collateralization.u128(), cursor.position_id.u128()))
+                });
+                Box::new(
+                    OPEN_REQUESTS_BY_COLLATERALIZATION
+                        .sub_prefix((borrow.as_str(), collateral.as_str()))
+                        .keys(deps.storage, None, max, Order::Descending)
+                        .map(|key| key.map(|(_, id)| id)),
+                )
+            }
+        };
+
+        // `limit` caps the entries scanned, matching or not, so a selective filter can't make
+        // the query walk the whole book; the cursor then resumes after the last one scanned
+        let mut prices = BTreeMap::new();
+        let mut requests = vec![];
+        let mut scanned = 0;
+        let mut last = None;
+        for id in ids.take(limit) {
+            let id = id?;
+            let keys = OPEN_REQUESTS.load(deps.storage, id)?;
+            scanned += 1;
+            last = Some(OpenRequestsCursor {
+                position_id: Uint128::new(id),
+                interest_rate: keys.interest_rate,
+                collateralization: keys.collateralization,
+            });
+            if filter
+                .min_rate
+                .is_some_and(|rate| keys.interest_rate < rate)
+            {
+                continue;
+            }
+            let position = POSITIONS.load(deps.storage, id)?;
+            if borrow_token
+                .as_ref()
+                .is_some_and(|token| *token != position.borrow_token)
+                || collateral_token
+                    .as_ref()
+                    .is_some_and(|token| position.collateral.iter().all(|(t, _)| t != token))
+                || filter
+                    .min_amount
+                    .is_some_and(|amount| position.amount < amount)
+                || filter
+                    .max_amount
+                    .is_some_and(|amount| position.amount > amount)
+            {
+                continue;
+            }
+
+            let borrow_price = cached_price(deps, &config, &mut prices, &position.borrow_token)?;
+            let mut collateral_value = Uint128::zero();
+            for (token, amount) in &position.collateral {
+                collateral_value += *amount * cached_price(deps, &config, &mut prices, token)?;
+            }
+            let ltv = Decimal::checked_from_ratio(position.amount * borrow_price, collateral_value)
+                .unwrap_or(Decimal::MAX);
+            if filter.max_ltv.is_some_and(|max_ltv| ltv > max_ltv) {
+                continue;
+            }
+
+            requests.push(OpenRequest {
+                position_id: Uint128::new(id),
+                position,
+                ltv,
+            });
+        }
+        Ok(OpenRequestsResponse {
+            requests,
+            next_start_after: if scanned == limit { last } else { None },
+        })
+    }
+
+    /// Query a token price once per query
+    fn cached_price(
+        deps: Deps,
+        config: &Config,
+        prices: &mut BTreeMap<String, Uint128>,
+        token: &Token,
+    ) -> StdResult<Uint128> {
+        let key = token_to_string(token);
+        if let Some(price) = prices.get(&key) {
+            return Ok(*price);
+        }
+        let price = query_price(&deps, &config.mock_oracle, token)?;
+        prices.insert(key, price);
+        Ok(price)
+    }
+
+    /// Query a running auction
+    pub fn query_auction(deps: Deps, env: Env, position_id: Uint128) -> StdResult<AuctionResponse> {
+        let auction = AUCTIONS.load(deps.storage, position_id.u128())?;
+        Ok(AuctionResponse {
+            auction: auction_info(deps, &env, position_id.u128(), auction)?,
+        })
+    }
+
+    /// Query running auctions with pagination
+    pub fn query_auctions(
+        deps: Deps,
+        env: Env,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    ) -> StdResult<AuctionsResponse> {
+        let limit = limit.unwrap_or(100).min(100) as usize;
+        let start = start_after.map(|id| Bound::exclusive(id.u128()));
+
+        let auctions = AUCTIONS
+            .range(deps.storage, start, None, Order::Ascending)
+            .take(limit)
+            .map(|item| {
+                let (id, auction) = item?;
+                auction_info(deps, &env, id, auction)
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(AuctionsResponse { auctions })
+    }
+
+    /// Attach the position and current discount to an auction
+    fn auction_info(
+        deps: Deps,
+        env: &Env,
+        position_id: u128,
+        auction: Auction,
+    ) -> StdResult<AuctionInfo> {
+        let position = POSITIONS.load(deps.storage, position_id)?;
+        let discount = auction_discount(&auction, env.block.time.seconds());
+        Ok(AuctionInfo {
+            position_id: Uint128::new(position_id),
+            auction,
+            position,
+            discount,
+        })
+    }
+
+    /// Query insurance funds and reserves of every supported token
+    pub fn query_insurance_funds(deps: Deps) -> StdResult<InsuranceFundsResponse> {
+        let funds = SUPPORTED_TOKENS
+            .keys(deps.storage, None, None, Order::Ascending)
+            .map(|item| {
+                let token = item?;
+                Ok(InsuranceFundInfo {
+                    fund: INSURANCE_FUNDS
+                        .may_load(deps.storage, &token)?
+                        .unwrap_or_default(),
+                    reserves: RESERVES
+                        .may_load(deps.storage, &token)?
+                        .unwrap_or(Uint128::zero()),
+                    token: determine_token_type(&deps, &token)?,
+                })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(InsuranceFundsResponse { funds })
+    }
+
+    /// Query recorded liquidation shortfalls with pagination
+    pub fn query_shortfalls(
+        deps: Deps,
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    ) -> StdResult<ShortfallsResponse> {
+        let limit = limit.unwrap_or(100).min(100) as usize;
+        let start = start_after.map(|id| Bound::exclusive(id.u128()));
+
+        let shortfalls = SHORTFALLS
+            .range(deps.storage, start, None, Order::Ascending)
+            .take(limit)
+            .map(|item| item.map(|(id, shortfall)| (Uint128::new(id), shortfall)))
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(ShortfallsResponse { shortfalls })
+    }
+
+    /// Query isolation settings and debt ceiling usage of a collateral token
+    pub fn query_isolation(deps: Deps, token: String) -> StdResult<IsolationResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let isolation = ISOLATED_TOKENS.may_load(deps.storage, &token)?;
+        let (debt, debt_value) = isolated_debt(&deps, &config, &token)?;
+        let ceiling = isolation
+            .as_ref()
+            .map(|isolation| isolation.debt_ceiling)
+            .unwrap_or(Uint128::zero());
+        Ok(IsolationResponse {
+            token: determine_token_type(&deps, &token)?,
+            ceiling_used: Decimal::checked_from_ratio(debt_value, ceiling)
+                .unwrap_or(Decimal::zero()),
+            headroom: ceiling.saturating_sub(debt_value),
+            isolation,
+            debt,
+            debt_value,
+        })
+    }
+
+    /// Query all e-mode categories
+    pub fn query_e_mode_categories(deps: Deps) -> StdResult<EModeCategoriesResponse> {
+        let categories = E_MODE_CATEGORIES
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(EModeCategoriesResponse { categories })
+    }
+
+    /// Query collateral, debts and health of a cross-margin account
+    pub fn query_margin_account(
+        deps: Deps,
+        env: Env,
+        address: String,
+    ) -> StdResult<MarginAccountResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let addr = deps.api.addr_validate(&address)?;
+        let (collateral, debts, health) =
+            margin_account(&deps, &config, &addr, env.block.time.seconds())?;
+        let shortfalls = ACCOUNT_SHORTFALLS
+            .prefix(&addr)
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| Ok(item?.1))
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(MarginAccountResponse {
+            collateral,
+            debts,
+            health,
+            shortfalls,
+        })
+    }
+
+    /// Query cross-margin parameters of all enabled tokens
+    pub fn query_margin_params(deps: Deps) -> StdResult<MarginParamsResponse> {
+        let params = MARGIN_PARAMS
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| {
+                let (token, params) = item?;
+                Ok((determine_token_type(&deps, &token)?, params))
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(MarginParamsResponse { params })
+    }
+
+    /// Query the lender notes of a position and who settlement currently pays
+    pub fn query_lender_notes(deps: Deps, position_id: Uint128) -> StdResult<LenderNotesResponse> {
+        let position = POSITIONS.load(deps.storage, position_id.u128())?;
+        let notes = lender_holders(&deps, position_id.u128(), &position)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, (holder, share))| LenderNote {
+                token_id: position
+                    .lender_nft
+                    .as_ref()
+                    .map(|_| lender_note_id(position_id.u128(), &position, i)),
+                holder,
+                share,
+            })
+            .collect();
+        Ok(LenderNotesResponse {
+            lender_nft: position.lender_nft,
+            notes,
+        })
+    }
+
+    /// Query the pending extension of a loan
+    pub fn query_extension_proposal(
+        deps: Deps,
+        position_id: Uint128,
+    ) -> StdResult<ExtensionProposalResponse> {
+        let proposal = EXTENSION_PROPOSALS.may_load(deps.storage, position_id.u128())?;
+        Ok(ExtensionProposalResponse { proposal })
+    }
+
+    /// Query the term changes of a loan
+    pub fn query_position_history(
+        deps: Deps,
+        position_id: Uint128,
+    ) -> StdResult<PositionHistoryResponse> {
+        let history = POSITION_HISTORY
+            .may_load(deps.storage, position_id.u128())?
+            .unwrap_or_default();
+        Ok(PositionHistoryResponse { history })
+    }
+
+    /// Query the public key signing a lender's offers
+    pub fn query_offer_key(deps: Deps, lender: String) -> StdResult<OfferKeyResponse> {
+        let lender = deps.api.addr_validate(&lender)?;
+        let public_key = OFFER_KEYS.may_load(deps.storage, &lender)?;
+        Ok(OfferKeyResponse { public_key })
+    }
+
+    /// Query whether a signed offer of a lender was taken or cancelled
+    pub fn query_offer_nonce(
+        deps: Deps,
+        lender: String,
+        nonce: u64,
+    ) -> StdResult<OfferNonceResponse> {
+        let lender = deps.api.addr_validate(&lender)?;
+        Ok(OfferNonceResponse {
+            used: USED_NONCES.has(deps.storage, (&lender, nonce)),
+            cancelled: CANCELLED_NONCES.has(deps.storage, (&lender, nonce)),
+        })
+    }
+
+    /// Query the counter-offers on an open request
+    pub fn query_counter_offers(
+        deps: Deps,
+        position_id: Uint128,
+    ) -> StdResult<CounterOffersResponse> {
+        let offers = COUNTER_OFFERS
+            .prefix(position_id.u128())
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(CounterOffersResponse { offers })
+    }
+
+    /// Query the operators managing a borrower's positions
+    pub fn query_operators(deps: Deps, borrower: String) -> StdResult<OperatorsResponse> {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        let operators = OPERATORS
+            .prefix(&borrower)
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(OperatorsResponse { operators })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::testing::MockApi;
+    use cosmwasm_std::{coin, coins, Addr, BankMsg, Coin, Decimal, Empty, WasmMsg};
+    use cw_multi_test::error::AnyResult;
+    use cw_multi_test::{App, AppResponse, ContractWrapper, Executor};
+    use cw_storage_plus::Map;
+    use encke_router::msg::{AssetInfo, ExecuteMsg as RouterExecuteMsg, SwapOperation};
+    use k256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+    use sha2::{Digest, Sha256};
+
+    use crate::msg::{
+        AccountHealthResponse, Action, AuctionResponse, AuctionsResponse, CollateralSource,
+        CounterOffersResponse, EModeCategoriesResponse, ExtensionProposalResponse,
+        InsuranceFundInfo, InsuranceFundsResponse, IsolationResponse, LendOffer, LenderNote,
+        LenderNotesResponse, LiquidatablePositionsResponse, LiquidationMode, MarginAccountResponse,
+        MarketStatsResponse, MigrateMsg, OfferKeyResponse, OfferNonceResponse, OpenRequestsCursor,
+        OpenRequestsFilter, OpenRequestsResponse, OpenRequestsSort, OperatorsResponse,
+        PositionHealth, PositionHealthResponse, PositionHistoryResponse, PositionResponse,
+        ProtocolStatsResponse, ShortfallsResponse, SimulateBorrowResponse,
+        SimulateLiquidationResponse, SimulateRepayResponse, TokenConfigsResponse, UserInfoResponse,
+    };
+    use crate::state::{
+        AuctionConfig, EModeCategory, Isolation, LegacyConfig, LegacyPosition, MarginParams,
+        MarketStats, Position, PositionEvent, Token, TokenCaps, LEGACY_CONFIG, LEGACY_POSITIONS,
+        MARKET_STATS,
+    };
+
+    use super::*;
+
+    const ATOM: &str = "uatom";
+    const USDC: &str = "uusdc";
+    const JUNO: &str = "ujuno";
+    const YEAR: u64 = 31_536_000;
+
+    fn addr(name: &str) -> Addr {
+        MockApi::default().addr_make(name)
+    }
+
+    /// Messages of a contract taking flash loans, repaying `repay` from its callback
+    #[cw_serde]
+    enum ReceiverMsg {
+        Borrow {
+            lender: String,
+            amount: Uint128,
+            repay: Uint128,
+        },
+        Callback {
+            repay: Uint128,
+        },
+    }
+
+    fn receiver_execute(
+        _deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        msg: ReceiverMsg,
+    ) -> StdResult<Response> {
+        match msg {
+            ReceiverMsg::Borrow {
+                lender,
+                amount,
+                repay,
+            } => Ok(Response::new().add_message(WasmMsg::Execute {
+                contract_addr: lender,
+                msg: to_json_binary(&ExecuteMsg::FlashLoan {
+                    token: USDC.to_string(),
+                    amount,
+                    callback: to_json_binary(&ReceiverMsg::Callback { repay })?,
+                })?,
+                funds: vec![],
+            })),
+            ReceiverMsg::Callback { repay } if repay.is_zero() => Ok(Response::new()),
+            ReceiverMsg::Callback { repay } => Ok(Response::new().add_message(WasmMsg::Execute {
+                contract_addr: info.sender.to_string(),
+                msg: to_json_binary(&ExecuteMsg::RepayFlashLoan {})?,
+                funds: coins(repay.u128(), USDC),
+            })),
+        }
+    }
+
+    /// Router paying out a single unit whatever it is sent, ignoring `minimum_receive`
+    fn short_router_execute(
+        _deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        msg: RouterExecuteMsg,
+    ) -> StdResult<Response> {
+        let RouterExecuteMsg::ExecuteSwapOperations { operations, .. } = msg else {
+            return Err(StdError::generic_err("Unsupported"));
+        };
+        let SwapOperation::AstroSwap {
+            ask_asset_info: AssetInfo::NativeToken { denom },
+            ..
+        } = &operations[0]
+        else {
+            return Err(StdError::generic_err("Unsupported"));
+        };
+        Ok(Response::new().add_message(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: coins(1, denom),
+        }))
+    }
+
+    fn empty_instantiate(
+        _deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        _msg: Empty,
+    ) -> StdResult<Response> {
+        Ok(Response::new())
+    }
+
+    fn empty_query(_deps: Deps, _env: Env, _msg: Empty) -> StdResult<Binary> {
+        to_json_binary(&Empty {})
+    }
+
+    struct Suite {
+        app: App,
+        contract: Addr,
+        oracle: Addr,
+        code_id: u64,
+    }
+
+    /// Encke with ATOM at 1000 and USDC at 100, and funded test accounts
+    fn setup() -> Suite {
+        setup_with(|_| {})
+    }
+
+    fn setup_with(configure: impl FnOnce(&mut InstantiateMsg)) -> Suite {
+        let admin = addr("admin");
+        let mut app = App::new(|router, _, storage| {
+            for name in ["admin", "borrower", "lender", "lender2", "liquidator"] {
+                router
+                    .bank
+                    .init_balance(
+                        storage,
+                        &addr(name),
+                        vec![
+                            coin(1_000_000, ATOM),
+                            coin(1_000_000, USDC),
+                            coin(1_000_000, JUNO),
+                        ],
+                    )
+                    .unwrap();
+            }
+        });
+
+        let oracle_code = app.store_code(Box::new(ContractWrapper::new(
+            encke_oracle::contract::execute,
+            encke_oracle::contract::instantiate,
+            encke_oracle::contract::query,
+        )));
+        let oracle = app
+            .instantiate_contract(
+                oracle_code,
+                admin.clone(),
+                &encke_oracle::msg::InstantiateMsg {},
+                &[],
+                "oracle",
+                None,
+            )
+            .unwrap();
+
+        let mut msg = InstantiateMsg {
+            mock_oracle: oracle.to_string(),
+            liquidation_threshold: Uint128::new(150),
+            initial_tokens: vec![ATOM.to_string(), USDC.to_string()],
+            flash_loan_fee: Uint128::zero(),
+            liquidation_fee: Uint128::zero(),
+            auction: None,
+            lender_nft: None,
+            swap_router: None,
+        };
+        configure(&mut msg);
+        let code_id = app.store_code(Box::new(
+            ContractWrapper::new(execute, instantiate, query)
+                .with_reply(reply)
+                .with_migrate(migrate),
+        ));
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                admin.clone(),
+                &msg,
+                &[],
+                "encke",
+                Some(admin.to_string()),
+            )
+            .unwrap();
+
+        let mut suite = Suite {
+            app,
+            contract,
+            oracle,
+            code_id,
+        };
+        suite.set_price(ATOM, 1_000);
+        suite.set_price(USDC, 100);
+        suite
+    }
+
+    impl Suite {
+        fn execute(
+            &mut self,
+            sender: &str,
+            msg: ExecuteMsg,
+            funds: &[Coin],
+        ) -> AnyResult<AppResponse> {
+            self.app
+                .execute_contract(addr(sender), self.contract.clone(), &msg, funds)
+        }
+
+        fn query<T: serde::de::DeserializeOwned>(&self, msg: QueryMsg) -> T {
+            self.app
+                .wrap()
+                .query_wasm_smart(&self.contract, &msg)
+                .unwrap()
+        }
+
+        fn query_error(&self, msg: QueryMsg) -> String {
+            self.app
+                .wrap()
+                .query_wasm_smart::<Empty>(&self.contract, &msg)
+                .unwrap_err()
+                .to_string()
+        }
+
+        fn set_price(&mut self, token: &str, price: u128) {
+            self.app
+                .execute_contract(
+                    addr("admin"),
+                    self.oracle.clone(),
+                    &encke_oracle::msg::ExecuteMsg::SetPrice {
+                        token: token.to_string(),
+                        price: Uint128::new(price),
+                    },
+                    &[],
+                )
+                .unwrap();
+        }
+
+        fn advance(&mut self, seconds: u64) {
+            self.app
+                .update_block(|block| block.time = block.time.plus_seconds(seconds));
+        }
+
+        /// Request `amount` USDC at `rate` percent against `collateral` ATOM
+        fn borrow(&mut self, borrower: &str, amount: u128, rate: u128, collateral: u128) {
+            self.execute(
+                borrower,
+                borrow_msg(USDC, amount, rate, ATOM, collateral),
+                &coins(collateral, ATOM),
+            )
+            .unwrap();
+        }
+
+        fn fill(&mut self, lender: &str, position_id: u128, amount: u128) {
+            self.execute(
+                lender,
+                ExecuteMsg::FillPosition {
+                    position_id: Uint128::new(position_id),
+                    amount: Uint128::new(amount),
+                },
+                &coins(amount, USDC),
+            )
+            .unwrap();
+        }
+
+        /// Borrow 1000 USDC at 10% against 200 ATOM, filled by "lender"
+        fn open_loan(&mut self) {
+            self.borrow("borrower", 1_000, 10, 200);
+            self.fill("lender", 1, 1_000);
+        }
+
+        fn balance(&self, who: &str, denom: &str) -> u128 {
+            self.app
+                .wrap()
+                .query_balance(addr(who), denom)
+                .unwrap()
+                .amount
+                .u128()
+        }
+
+        fn contract_balance(&self, denom: &str) -> u128 {
+            self.app
+                .wrap()
+                .query_balance(&self.contract, denom)
+                .unwrap()
+                .amount
+                .u128()
+        }
+
+        fn deposit(&mut self, user: &str, token: &str, amount: u128) {
+            self.execute(
+                user,
+                ExecuteMsg::Deposit {
+                    token: token.to_string(),
+                    amount: Uint128::new(amount),
+                },
+                &coins(amount, token),
+            )
+            .unwrap();
+        }
+
+        fn liquidatable(
+            &self,
+            start_after: Option<u128>,
+            limit: Option<u32>,
+        ) -> LiquidatablePositionsResponse {
+            self.query(QueryMsg::GetLiquidatablePositions {
+                start_after: start_after.map(Uint128::new),
+                limit,
+            })
+        }
+
+        fn market(&self, token: &str) -> MarketStatsResponse {
+            self.query(QueryMsg::GetMarketStats {
+                token: token.to_string(),
+            })
+        }
+
+        fn insurance(&self, token: &str) -> InsuranceFundInfo {
+            let response: InsuranceFundsResponse = self.query(QueryMsg::GetInsuranceFunds {});
+            response
+                .funds
+                .into_iter()
+                .find(|info| info.token == Token::Native(token.to_string()))
+                .unwrap()
+        }
+
+        fn simulate_liquidation(&self, position_id: u128) -> SimulateLiquidationResponse {
+            self.query(QueryMsg::SimulateLiquidation {
+                position_id: Uint128::new(position_id),
+                collateral_token: ATOM.to_string(),
+            })
+        }
+
+        fn liquidate(&mut self, position_id: u128, amount: u128) -> AnyResult<AppResponse> {
+            self.execute(
+                "liquidator",
+                ExecuteMsg::Liquidate {
+                    position_id: Uint128::new(position_id),
+                    collateral_token: ATOM.to_string(),
+                },
+                &coins(amount, USDC),
+            )
+        }
+
+        fn position_health(&self, position_id: u128) -> PositionHealth {
+            let response: PositionHealthResponse = self.query(QueryMsg::GetPositionHealth {
+                position_id: Uint128::new(position_id),
+            });
+            response.health
+        }
+
+        fn position(&self, position_id: u128) -> Position {
+            let response: PositionResponse = self.query(QueryMsg::GetPosition {
+                position_id: Uint128::new(position_id),
+            });
+            response.position
+        }
+
+        fn deposited(&self, user: &str, token: &str) -> u128 {
+            let response: UserInfoResponse = self.query(QueryMsg::GetUserInfo {
+                address: addr(user).to_string(),
+            });
+            response
+                .user_info
+                .and_then(|info| {
+                    info.deposits
+                        .into_iter()
+                        .find(|deposit| deposit.token == Token::Native(token.to_string()))
+                })
+                .map(|deposit| deposit.amount.u128())
+                .unwrap_or(0)
+        }
+
+        /// A lend offer of "lender" signed with `key`
+        fn signed_offer(
+            &self,
+            key: &SigningKey,
+            nonce: u64,
+            amount: u128,
+            interest_rate: u128,
+        ) -> (LendOffer, Binary) {
+            let block = self.app.block_info();
+            let offer = LendOffer {
+                lender: addr("lender").to_string(),
+                token: USDC.to_string(),
+                amount: Uint128::new(amount),
+                interest_rate: Uint128::new(interest_rate),
+                term: None,
+                max_ltv: Decimal::percent(60),
+                nonce,
+                expiry: block.time.seconds() + 3_600,
+                chain_id: block.chain_id,
+                contract: self.contract.to_string(),
+            };
+            (offer.clone(), sign(key, &offer))
+        }
+
+        /// Point the contract at the mock router, swapping ATOM and USDC at their oracle ratio
+        fn use_router(&mut self) {
+            let code = self.app.store_code(Box::new(ContractWrapper::new(
+                encke_router::contract::execute,
+                encke_router::contract::instantiate,
+                encke_router::contract::query,
+            )));
+            let router = self
+                .app
+                .instantiate_contract(
+                    code,
+                    addr("admin"),
+                    &encke_router::msg::InstantiateMsg {},
+                    &[],
+                    "router",
+                    None,
+                )
+                .unwrap();
+            for (offer, ask, rate) in [(ATOM, USDC, "10"), (USDC, ATOM, "0.1")] {
+                self.app
+                    .execute_contract(
+                        addr("admin"),
+                        router.clone(),
+                        &RouterExecuteMsg::SetRate {
+                            offer_asset_info: AssetInfo::NativeToken {
+                                denom: offer.to_string(),
+                            },
+                            ask_asset_info: AssetInfo::NativeToken {
+                                denom: ask.to_string(),
+                            },
+                            rate: rate.parse().unwrap(),
+                        },
+                        &[],
+                    )
+                    .unwrap();
+            }
+            self.set_router(router);
+        }
+
+        /// Point the contract at a router paying out a single unit whatever it is sent
+        fn use_short_router(&mut self) {
+            let code = self.app.store_code(Box::new(ContractWrapper::new(
+                short_router_execute,
+                empty_instantiate,
+                empty_query,
+            )));
+            let router = self
+                .app
+                .instantiate_contract(code, addr("admin"), &Empty {}, &[], "short", None)
+                .unwrap();
+            self.set_router(router);
+        }
+
+        fn set_router(&mut self, router: Addr) {
+            self.app
+                .send_tokens(
+                    addr("admin"),
+                    router.clone(),
+                    &[coin(100_000, ATOM), coin(100_000, USDC)],
+                )
+                .unwrap();
+            self.execute(
+                "admin",
+                ExecuteMsg::UpdateSwapRouter {
+                    address: Some(router.to_string()),
+                },
+                &[],
+            )
+            .unwrap();
+        }
+
+        fn register_key(&mut self, key: &SigningKey) {
+            let public_key = key.verifying_key().to_encoded_point(true);
+            self.execute(
+                "lender",
+                ExecuteMsg::RegisterOfferKey {
+                    public_key: Binary::from(public_key.as_bytes()),
+                },
+                &[],
+            )
+            .unwrap();
+        }
+    }
+
+    fn sign(key: &SigningKey, offer: &LendOffer) -> Binary {
+        let hash = Sha256::digest(cosmwasm_std::to_json_vec(offer).unwrap());
+        let signature: Signature = key.sign_prehash(&hash).unwrap();
+        Binary::from(signature.to_bytes().as_slice())
+    }
+
+    /// Borrow `amount` of `borrow_token` at `rate` percent against a single collateral token
+    fn borrow_msg(
+        borrow_token: &str,
+        amount: u128,
+        rate: u128,
+        collateral_token: &str,
+        collateral: u128,
+    ) -> ExecuteMsg {
+        ExecuteMsg::Borrow {
+            borrow_token: borrow_token.to_string(),
+            amount: Uint128::new(amount),
+            interest_rate: Uint128::new(rate),
+            collateral: vec![(collateral_token.to_string(), Uint128::new(collateral))],
+            term: None,
+            allowed_lenders: None,
+            collateral_source: None,
+            return_to_deposits: None,
+        }
+    }
+
+    fn attribute(response: &AppResponse, key: &str) -> String {
+        response
+            .events
+            .iter()
+            .flat_map(|event| &event.attributes)
+            .find(|attribute| attribute.key == key)
+            .map(|attribute| attribute.value.clone())
+            .unwrap()
+    }
+
+    fn auctions() -> AuctionConfig {
+        AuctionConfig {
+            start_discount: Uint128::new(500),
+            max_discount: Uint128::new(2_000),
+            duration: 3_600,
+        }
+    }
+
+    fn assert_error<T: std::fmt::Debug>(result: AnyResult<T>, expected: &str) {
+        let error = result.unwrap_err().root_cause().to_string();
+        assert!(error.contains(expected), "unexpected error: {}", error);
+    }
+
+    #[test]
+    fn position_health_values_debt_with_interest() {
+        let mut suite = setup();
+        suite.open_loan();
+
+        let health = suite.position_health(1);
+        assert_eq!(health.collateral_value, Uint128::new(200_000));
+        assert_eq!(health.debt_value, Uint128::new(100_000));
+        assert_eq!(health.total_debt, Uint128::new(1_000));
+        assert_eq!(health.interest, Uint128::zero());
+        assert_eq!(health.ltv, Decimal::percent(50));
+        assert_eq!(
+            health.health_factor,
+            Some(Decimal::from_ratio(200_000u128, 150_000u128))
+        );
+        assert_eq!(
+            health.liquidation_price,
+            Some(Decimal::from_ratio(750u128, 1u128))
+        );
+        assert!(!health.liquidatable);
+
+        // 10% over a year
+        suite.advance(YEAR);
+        let health = suite.position_health(1);
+        assert_eq!(health.interest, Uint128::new(100));
+        assert_eq!(health.total_debt, Uint128::new(1_100));
+        assert_eq!(health.debt_value, Uint128::new(110_000));
+        assert_eq!(
+            health.liquidation_price,
+            Some(Decimal::from_ratio(825u128, 1u128))
+        );
+        assert!(!health.liquidatable);
+
+        suite.set_price(ATOM, 824);
+        let health = suite.position_health(1);
+        assert!(health.health_factor.unwrap() < Decimal::one());
+        assert!(health.liquidatable);
+    }
+
+    #[test]
+    fn position_health_of_unfilled_request() {
+        let mut suite = setup();
+        suite.borrow("borrower", 1_000, 10, 150);
+        suite.advance(YEAR);
+
+        let health = suite.position_health(1);
+        assert_eq!(health.interest, Uint128::zero());
+        assert_eq!(health.debt_value, Uint128::new(100_000));
+        assert!(!health.liquidatable);
+    }
+
+    #[test]
+    fn account_health_sums_filled_positions() {
+        let mut suite = setup();
+        suite.open_loan();
+        suite.borrow("borrower", 500, 10, 80);
+        suite.borrow("lender2", 500, 10, 100);
+        suite.fill("lender", 3, 500);
+
+        let account = |suite: &Suite| -> AccountHealthResponse {
+            suite.query(QueryMsg::GetAccountHealth {
+                address: addr("borrower").to_string(),
+            })
+        };
+        let health = account(&suite);
+        assert_eq!(health.positions.len(), 1);
+        assert_eq!(health.collateral_value, Uint128::new(200_000));
+        assert_eq!(health.debt_value, Uint128::new(100_000));
+
+        suite.fill("lender", 2, 500);
+        let health = account(&suite);
+        assert_eq!(
+            health
+                .positions
+                .iter()
+                .map(|(id, _)| id.u128())
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(health.collateral_value, Uint128::new(280_000));
+        assert_eq!(health.debt_value, Uint128::new(150_000));
+        assert_eq!(health.ltv, Decimal::from_ratio(150_000u128, 280_000u128));
+        assert!(!health.liquidatable);
+
+        // Position 2 is the thinner one and crosses the threshold first
+        suite.set_price(ATOM, 900);
+        let health = account(&suite);
+        assert!(health.liquidatable);
+        assert!(!health.positions[0].1.liquidatable);
+        assert!(health.positions[1].1.liquidatable);
+        assert_eq!(
+            health.health_factor,
+            Some(Decimal::from_ratio(252_000u128, 225_000u128))
+        );
+    }
+
+    #[test]
+    fn liquidatable_positions_match_liquidation() {
+        let mut suite = setup();
+        // Positions 1 and 3 go under at ATOM 900, position 2 holds more collateral
+        suite.borrow("borrower", 1_000, 10, 160);
+        suite.borrow("borrower", 1_000, 10, 200);
+        suite.borrow("lender2", 1_000, 10, 160);
+        suite.borrow("lender2", 1_000, 10, 150);
+        for id in 1..=3 {
+            suite.fill("lender", id, 1_000);
+        }
+        assert!(suite.liquidatable(None, None).positions.is_empty());
+
+        suite.set_price(ATOM, 900);
+        let ids = |response: LiquidatablePositionsResponse| {
+            response
+                .positions
+                .iter()
+                .map(|entry| entry.position_id.u128())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(suite.liquidatable(None, None)), vec![1, 3]);
+        assert_eq!(ids(suite.liquidatable(None, Some(1))), vec![1]);
+        // The limit counts positions scanned, so a page may hold no liquidatable position
+        let page = suite.liquidatable(Some(1), Some(1));
+        assert!(page.positions.is_empty());
+        assert_eq!(page.next_start_after, Some(Uint128::new(2)));
+        assert_eq!(ids(suite.liquidatable(Some(2), Some(1))), vec![3]);
+        let page = suite.liquidatable(Some(3), None);
+        assert!(page.positions.is_empty());
+        assert_eq!(page.next_start_after, None);
+
+        suite.advance(YEAR);
+        let entry = suite.liquidatable(None, Some(1)).positions.remove(0);
+        assert_eq!(entry.mode, LiquidationMode::FixedPrice);
+        assert_eq!(entry.repay_amount, Uint128::new(1_100));
+        assert_eq!(entry.collateral_payout, Uint128::new(128));
+        assert_eq!(entry.health, suite.position_health(1));
+
+        // Funds sent above the debt are refunded
+        let atom_before = suite.balance("liquidator", ATOM);
+        let usdc_before = suite.balance("liquidator", USDC);
+        let borrower_before = suite.balance("borrower", ATOM);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::Liquidate {
+                    position_id: Uint128::one(),
+                    collateral_token: ATOM.to_string(),
+                },
+                &coins(1_200, USDC),
+            )
+            .unwrap();
+        // 110000 of debt value plus the 5% bonus is 128 ATOM at 900, the other 32 go back
+        assert_eq!(suite.balance("liquidator", ATOM), atom_before + 128);
+        assert_eq!(suite.balance("liquidator", USDC), usdc_before - 1_100);
+        assert_eq!(suite.balance("borrower", ATOM), borrower_before + 32);
+        assert_eq!(ids(suite.liquidatable(None, None)), vec![3]);
+    }
+
+    #[test]
+    fn simulate_repay_matches_repay() {
+        let mut suite = setup();
+        suite.borrow("borrower", 1_000, 10, 200);
+        let simulate = |at_time: Option<u64>| QueryMsg::SimulateRepay {
+            position_id: Uint128::one(),
+            at_time,
+        };
+        assert!(suite
+            .query_error(simulate(None))
+            .contains("Position not filled"));
+
+        suite.fill("lender", 1, 1_000);
+        let now = suite.app.block_info().time.seconds();
+        let response: SimulateRepayResponse = suite.query(simulate(Some(now + YEAR)));
+        assert_eq!(response.interest, Uint128::new(100));
+        assert_eq!(response.total_repayment, Uint128::new(1_100));
+
+        suite.advance(YEAR / 2);
+        let response: SimulateRepayResponse = suite.query(simulate(None));
+        assert_eq!(response.principal, Uint128::new(1_000));
+        assert_eq!(response.interest, Uint128::new(50));
+        assert_eq!(response.total_repayment, Uint128::new(1_050));
+        assert_eq!(
+            response.collateral_released,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(200))]
+        );
+
+        let repay = ExecuteMsg::Repay {
+            position_id: Uint128::one(),
+        };
+        assert_error(
+            suite.execute("borrower", repay.clone(), &coins(1_049, USDC)),
+            "Insufficient funds",
+        );
+        let lender_before = suite.balance("lender", USDC);
+        let atom_before = suite.balance("borrower", ATOM);
+        suite
+            .execute("borrower", repay, &coins(1_050, USDC))
+            .unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 1_050);
+        assert_eq!(suite.balance("borrower", ATOM), atom_before + 200);
+    }
+
+    #[test]
+    fn simulate_liquidation_matches_liquidation() {
+        let mut suite = setup();
+        suite.open_loan();
+        suite.advance(YEAR);
+        suite.set_price(ATOM, 800);
+
+        let simulation: SimulateLiquidationResponse = suite.query(QueryMsg::SimulateLiquidation {
+            position_id: Uint128::one(),
+            collateral_token: ATOM.to_string(),
+        });
+        assert_eq!(simulation.repay_amount, Uint128::new(1_100));
+        assert_eq!(simulation.interest, Uint128::new(100));
+        assert_eq!(
+            simulation.lender_share + simulation.protocol_share,
+            simulation.repay_amount
+        );
+        assert_eq!(
+            simulation.collateral_seized + simulation.collateral_returned,
+            Uint128::new(200)
+        );
+        assert!(simulation.health.liquidatable);
+
+        let lender_before = suite.balance("lender", USDC);
+        let atom_before = suite.balance("liquidator", ATOM);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::Liquidate {
+                    position_id: Uint128::one(),
+                    collateral_token: ATOM.to_string(),
+                },
+                &coins(1_100, USDC),
+            )
+            .unwrap();
+        assert_eq!(
+            suite.balance("lender", USDC),
+            lender_before + simulation.lender_share.u128()
+        );
+        assert_eq!(
+            suite.balance("liquidator", ATOM),
+            atom_before + simulation.collateral_seized.u128()
+        );
+    }
+
+    #[test]
+    fn liquidation_pays_debt_value_plus_bonus() {
+        // Solvent: 1000 USDC of debt is 100000, plus the 5% bonus is 150 ATOM at 700
+        let mut suite = setup_with(|msg| msg.liquidation_fee = Uint128::new(500));
+        suite.open_loan();
+        suite.set_price(ATOM, 700);
+        let simulation = suite.simulate_liquidation(1);
+        assert_eq!(simulation.repay_amount, Uint128::new(1_000));
+        assert_eq!(simulation.protocol_share, Uint128::new(50));
+        assert_eq!(simulation.lender_share, Uint128::new(950));
+        assert_eq!(simulation.collateral_seized, Uint128::new(150));
+        assert_eq!(simulation.collateral_returned, Uint128::new(50));
+        assert!(simulation.shortfall.is_zero());
+
+        let lender_before = suite.balance("lender", USDC);
+        let borrower_before = suite.balance("borrower", ATOM);
+        let liquidator_before = suite.balance("liquidator", ATOM);
+        suite.liquidate(1, 1_000).unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 950);
+        assert_eq!(suite.balance("liquidator", ATOM), liquidator_before + 150);
+        assert_eq!(suite.balance("borrower", ATOM), borrower_before + 50);
+        assert_eq!(suite.insurance(USDC).reserves, Uint128::new(50));
+        assert!(suite.insurance(ATOM).reserves.is_zero());
+
+        // Underwater: the 200 ATOM are worth 80000 at 400, the liquidator pays that less
+        // the bonus once, 80000 / 1.05 = 761 USDC, and the fee is waived
+        let mut suite = setup_with(|msg| msg.liquidation_fee = Uint128::new(500));
+        suite.open_loan();
+        suite.set_price(ATOM, 400);
+        let simulation = suite.simulate_liquidation(1);
+        assert_eq!(simulation.repay_amount, Uint128::new(761));
+        assert_eq!(simulation.lender_share, Uint128::new(761));
+        assert!(simulation.protocol_share.is_zero());
+        assert_eq!(simulation.collateral_seized, Uint128::new(200));
+        assert!(simulation.collateral_returned.is_zero());
+        assert_eq!(simulation.shortfall, Uint128::new(239));
+
+        let lender_before = suite.balance("lender", USDC);
+        let liquidator_before = suite.balance("liquidator", ATOM);
+        suite.liquidate(1, 761).unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 761);
+        assert_eq!(suite.balance("liquidator", ATOM), liquidator_before + 200);
+        assert!(suite.insurance(USDC).reserves.is_zero());
+    }
+
+    #[test]
+    fn simulate_borrow_values_the_request() {
+        let suite = setup();
+        let simulate = |borrow_token: &str, collateral: u128| QueryMsg::SimulateBorrow {
+            borrow_token: borrow_token.to_string(),
+            amount: Uint128::new(1_000),
+            interest_rate: Uint128::new(10),
+            collateral: vec![(ATOM.to_string(), Uint128::new(collateral))],
+        };
+
+        let response: SimulateBorrowResponse = suite.query(simulate(USDC, 200));
+        assert_eq!(
+            response.collateral_required,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(200))]
+        );
+        assert_eq!(response.amount_received, Uint128::new(1_000));
+        assert_eq!(response.health.ltv, Decimal::percent(50));
+        assert!(!response.health.liquidatable);
+
+        let response: SimulateBorrowResponse = suite.query(simulate(USDC, 140));
+        assert!(response.health.liquidatable);
+
+        assert!(suite
+            .query_error(simulate("uosmo", 200))
+            .contains("Unsupported token"));
+    }
+
+    #[test]
+    fn market_stats_follow_deposits_and_loans() {
+        let mut suite = setup();
+        suite.deposit("lender2", USDC, 5_000);
+        suite.open_loan();
+
+        let usdc = suite.market(USDC);
+        assert_eq!(
+            usdc.stats,
+            MarketStats {
+                total_deposited: Uint128::new(5_000),
+                total_lent: Uint128::new(1_000),
+                total_borrowed: Uint128::new(1_000),
+                total_collateral: Uint128::zero(),
+                active_positions: 1,
+                pool_borrowed: Uint128::zero(),
+            }
+        );
+        assert_eq!(usdc.deposited_value, Uint128::new(500_000));
+        assert_eq!(usdc.lent_value, Uint128::new(100_000));
+        assert_eq!(usdc.utilization, Decimal::from_ratio(1_000u128, 6_000u128));
+        assert_eq!(suite.market(ATOM).stats.total_collateral, Uint128::new(200));
+        assert_eq!(suite.market(ATOM).collateral_value, Uint128::new(200_000));
+
+        let protocol: ProtocolStatsResponse = suite.query(QueryMsg::GetProtocolStats {});
+        assert_eq!(protocol.total_deposited_value, Uint128::new(500_000));
+        assert_eq!(protocol.total_collateral_value, Uint128::new(200_000));
+        assert_eq!(protocol.total_value_locked, Uint128::new(700_000));
+        assert_eq!(protocol.active_positions, 1);
+        assert_eq!(protocol.markets.len(), 2);
+
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_000, USDC),
+            )
+            .unwrap();
+        suite
+            .execute(
+                "lender2",
+                ExecuteMsg::Withdraw {
+                    token: USDC.to_string(),
+                    amount: Uint128::new(2_000),
+                },
+                &[],
+            )
+            .unwrap();
+        assert_eq!(
+            suite.market(USDC).stats,
+            MarketStats {
+                total_deposited: Uint128::new(3_000),
+                ..MarketStats::default()
+            }
+        );
+        assert_eq!(suite.market(ATOM).stats, MarketStats::default());
+        let protocol: ProtocolStatsResponse = suite.query(QueryMsg::GetProtocolStats {});
+        assert_eq!(protocol.total_value_locked, Uint128::new(300_000));
+        assert_eq!(protocol.active_positions, 0);
+    }
+
+    #[test]
+    fn open_requests_filter_sort_and_page() {
+        let mut suite = setup();
+        suite.borrow("borrower", 1_000, 5, 200);
+        suite.borrow("borrower", 500, 12, 80);
+        suite.borrow("borrower", 2_000, 8, 500);
+        suite.borrow("borrower", 300, 12, 100);
+        suite
+            .execute(
+                "lender2",
+                borrow_msg(ATOM, 10, 20, USDC, 2_000),
+                &coins(2_000, USDC),
+            )
+            .unwrap();
+        suite.fill("lender", 3, 2_000);
+
+        let requests = |suite: &Suite,
+                        filter: OpenRequestsFilter,
+                        sort: Option<OpenRequestsSort>,
+                        start_after: Option<OpenRequestsCursor>,
+                        limit: Option<u32>| {
+            let response: OpenRequestsResponse = suite.query(QueryMsg::GetOpenRequests {
+                filter: Some(filter),
+                sort,
+                start_after,
+                limit,
+            });
+            let ids = response
+                .requests
+                .iter()
+                .map(|request| request.position_id.u128())
+                .collect::<Vec<_>>();
+            (ids, response.next_start_after)
+        };
+        let pair = || OpenRequestsFilter {
+            borrow_token: Some(USDC.to_string()),
+            collateral_token: Some(ATOM.to_string()),
+            ..OpenRequestsFilter::default()
+        };
+        let rate = Some(OpenRequestsSort::RateDesc);
+        let collateralization = Some(OpenRequestsSort::Collateralization);
+
+        assert_eq!(
+            requests(&suite, Default::default(), None, None, None),
+            (vec![5, 4, 2, 1], None)
+        );
+        let (page, next) = requests(&suite, Default::default(), None, None, Some(2));
+        assert_eq!(page, vec![5, 4]);
+        assert_eq!(
+            requests(&suite, Default::default(), None, next, Some(2)).0,
+            vec![2, 1]
+        );
+        let (page, next) = requests(&suite, Default::default(), rate.clone(), None, Some(2));
+        assert_eq!(page, vec![5, 4]);
+        assert_eq!(
+            requests(&suite, Default::default(), rate.clone(), next, None),
+            (vec![2, 1], None)
+        );
+
+        // The limit caps entries scanned: 5 is scanned but filtered out by the pair
+        let min_rate = || OpenRequestsFilter {
+            min_rate: Some(Uint128::new(10)),
+            ..pair()
+        };
+        let (page, next) = requests(&suite, min_rate(), rate.clone(), None, Some(2));
+        assert_eq!(page, vec![4]);
+        assert_eq!(
+            requests(&suite, min_rate(), rate, next, Some(2)),
+            (vec![2], None)
+        );
+
+        // Collateral value per unit of requested value: 4 has 10/3, 1 has 2, 2 has 8/5
+        assert_eq!(
+            requests(&suite, pair(), collateralization.clone(), None, None).0,
+            vec![4, 1, 2]
+        );
+        let (page, next) = requests(&suite, pair(), collateralization.clone(), None, Some(1));
+        assert_eq!(page, vec![4]);
+        assert_eq!(
+            next,
+            Some(OpenRequestsCursor {
+                position_id: Uint128::new(4),
+                interest_rate: Uint128::new(12),
+                collateralization: Decimal::from_ratio(10u128, 3u128).atomics(),
+            })
+        );
+        assert!(suite
+            .query_error(QueryMsg::GetOpenRequests {
+                filter: None,
+                sort: collateralization.clone(),
+                start_after: None,
+                limit: None,
+            })
+            .contains("requires borrow and collateral token filters"));
+
+        let max_ltv = OpenRequestsFilter {
+            max_ltv: Some(Decimal::percent(50)),
+            ..OpenRequestsFilter::default()
+        };
+        assert_eq!(requests(&suite, max_ltv, None, None, None).0, vec![5, 4, 1]);
+        let amounts = OpenRequestsFilter {
+            min_amount: Some(Uint128::new(400)),
+            max_amount: Some(Uint128::new(1_000)),
+            ..OpenRequestsFilter::default()
+        };
+        assert_eq!(requests(&suite, amounts, None, None, None).0, vec![2, 1]);
+
+        let (_, after_four) = requests(&suite, Default::default(), None, None, Some(2));
+        let response: OpenRequestsResponse = suite.query(QueryMsg::GetOpenRequests {
+            filter: None,
+            sort: None,
+            start_after: after_four,
+            limit: Some(1),
+        });
+        assert_eq!(
+            response.requests[0].ltv,
+            Decimal::from_ratio(50_000u128, 80_000u128)
+        );
+
+        // A cursor keeps paging once its own request has left the order book
+        suite.fill("lender", 4, 300);
+        assert_eq!(
+            requests(&suite, pair(), collateralization, next, None),
+            (vec![1, 2], None)
+        );
+    }
+
+    #[test]
+    fn flash_loan_repaid_with_fee() {
+        let mut suite = setup_with(|msg| msg.flash_loan_fee = Uint128::new(100));
+        suite.deposit("lender", USDC, 10_000);
+        let code = suite.app.store_code(Box::new(ContractWrapper::new(
+            receiver_execute,
+            empty_instantiate,
+            empty_query,
+        )));
+        let receiver = suite
+            .app
+            .instantiate_contract(code, addr("admin"), &Empty {}, &[], "receiver", None)
+            .unwrap();
+        suite
+            .app
+            .send_tokens(addr("admin"), receiver.clone(), &coins(100, USDC))
+            .unwrap();
+
+        let borrow = |amount: u128, repay: u128| ReceiverMsg::Borrow {
+            lender: suite.contract.to_string(),
+            amount: Uint128::new(amount),
+            repay: Uint128::new(repay),
+        };
+        let not_repaid = borrow(5_000, 0);
+        let underpaid = borrow(5_000, 5_000);
+        let repaid = borrow(5_000, 5_050);
+
+        let result = suite
+            .app
+            .execute_contract(addr("admin"), receiver.clone(), &not_repaid, &[]);
+        assert_error(result, "Flash loan not repaid");
+        let result = suite
+            .app
+            .execute_contract(addr("admin"), receiver.clone(), &underpaid, &[]);
+        assert_error(result, "Insufficient funds");
+        assert_eq!(suite.contract_balance(USDC), 10_000);
+
+        suite
+            .app
+            .execute_contract(addr("admin"), receiver.clone(), &repaid, &[])
+            .unwrap();
+        assert_eq!(suite.contract_balance(USDC), 10_050);
+        assert_eq!(
+            suite
+                .app
+                .wrap()
+                .query_balance(&receiver, USDC)
+                .unwrap()
+                .amount,
+            Uint128::new(50)
+        );
+
+        // Repaying outside of a flash loan is refused
+        assert_error(
+            suite.execute("lender", ExecuteMsg::RepayFlashLoan {}, &coins(1, USDC)),
+            "No flash loan in progress",
+        );
+    }
+
+    #[test]
+    fn flash_loan_limited_to_deposits_and_excess_refunded() {
+        let mut suite = setup_with(|msg| msg.flash_loan_fee = Uint128::new(100));
+        suite.deposit("lender", USDC, 1_000);
+        // Collateral held by the contract is not lendable
+        suite
+            .execute(
+                "lender2",
+                borrow_msg(ATOM, 10, 20, USDC, 2_000),
+                &coins(2_000, USDC),
+            )
+            .unwrap();
+        let code = suite.app.store_code(Box::new(ContractWrapper::new(
+            receiver_execute,
+            empty_instantiate,
+            empty_query,
+        )));
+        let receiver = suite
+            .app
+            .instantiate_contract(code, addr("admin"), &Empty {}, &[], "receiver", None)
+            .unwrap();
+        suite
+            .app
+            .send_tokens(addr("admin"), receiver.clone(), &coins(100, USDC))
+            .unwrap();
+
+        let borrow = |amount: u128, repay: u128| ReceiverMsg::Borrow {
+            lender: suite.contract.to_string(),
+            amount: Uint128::new(amount),
+            repay: Uint128::new(repay),
+        };
+        let too_much = borrow(1_001, 1_012);
+        let overpaid = borrow(1_000, 1_050);
+
+        let result = suite
+            .app
+            .execute_contract(addr("admin"), receiver.clone(), &too_much, &[]);
+        assert_error(result, "Insufficient liquidity");
+
+        // The fee is kept and the 40 sent above the 1_010 owed comes back
+        suite
+            .app
+            .execute_contract(addr("admin"), receiver.clone(), &overpaid, &[])
+            .unwrap();
+        assert_eq!(suite.contract_balance(USDC), 3_010);
+        assert_eq!(
+            suite
+                .app
+                .wrap()
+                .query_balance(&receiver, USDC)
+                .unwrap()
+                .amount,
+            Uint128::new(90)
+        );
+    }
+
+    #[test]
+    fn dutch_auction_discount_grows_up_to_max() {
+        let mut suite = setup_with(|msg| msg.auction = Some(auctions()));
+        suite.open_loan();
+        let start = ExecuteMsg::StartAuction {
+            position_id: Uint128::one(),
+        };
+        let bid = |amount: u128| ExecuteMsg::Bid {
+            position_id: Uint128::one(),
+            amount: Uint128::new(amount),
+            collateral_token: ATOM.to_string(),
+        };
+
+        assert_error(
+            suite.execute("liquidator", start.clone(), &[]),
+            "Position not undercollateralized",
+        );
+        suite.set_price(ATOM, 700);
+        assert_error(
+            suite.execute(
+                "liquidator",
+                ExecuteMsg::Liquidate {
+                    position_id: Uint128::one(),
+                    collateral_token: ATOM.to_string(),
+                },
+                &coins(1_000, USDC),
+            ),
+            "Liquidations run as auctions",
+        );
+        assert_error(
+            suite.execute("liquidator", bid(200), &coins(200, USDC)),
+            "No auction for position",
+        );
+        let liquidatable = suite.liquidatable(None, None).positions;
+        assert_eq!(liquidatable.len(), 1);
+        assert_eq!(liquidatable[0].mode, LiquidationMode::Auction);
+        suite.execute("liquidator", start.clone(), &[]).unwrap();
+        // Auctioned positions are left to their bidders
+        assert!(suite.liquidatable(None, None).positions.is_empty());
+        assert_error(
+            suite.execute("liquidator", start, &[]),
+            "Auction already started",
+        );
+        assert_error(
+            suite.execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_000, USDC),
+            ),
+            "Position is being auctioned",
+        );
+        let cancel = ExecuteMsg::CancelAuction {
+            position_id: Uint128::one(),
+        };
+        assert_error(
+            suite.execute("liquidator", cancel.clone(), &[]),
+            "Position still undercollateralized",
+        );
+        // The running auction keeps the parameters it started with
+        suite
+            .execute(
+                "admin",
+                ExecuteMsg::UpdateAuctionConfig { auction: None },
+                &[],
+            )
+            .unwrap();
+
+        // 200 USDC at 100 buy ATOM at 700 less 5%
+        let lender_before = suite.balance("lender", USDC);
+        let atom_before = suite.balance("liquidator", ATOM);
+        let response = suite
+            .execute("liquidator", bid(200), &coins(200, USDC))
+            .unwrap();
+        assert_eq!(attribute(&response, "discount"), "500");
+        assert_eq!(suite.balance("liquidator", ATOM), atom_before + 30);
+
+        suite.advance(1_800);
+        let response: AuctionResponse = suite.query(QueryMsg::GetAuction {
+            position_id: Uint128::one(),
+        });
+        assert_eq!(response.auction.discount, Uint128::new(1_250));
+        assert_eq!(response.auction.auction.debt_remaining, Uint128::new(800));
+
+        // Long after the auction's duration the discount stays at its max
+        suite.advance(10 * 3_600);
+        let response = suite
+            .execute("liquidator", bid(200), &coins(200, USDC))
+            .unwrap();
+        assert_eq!(attribute(&response, "discount"), "2000");
+        assert_eq!(suite.balance("liquidator", ATOM), atom_before + 65);
+        assert_eq!(suite.balance("lender", USDC), lender_before + 400);
+
+        // 135 ATOM at 700 cover the 600 left, so bidding stops and the loan resumes
+        assert_error(
+            suite.execute("liquidator", bid(200), &coins(200, USDC)),
+            "Position no longer undercollateralized",
+        );
+        suite.execute("liquidator", cancel, &[]).unwrap();
+        let position = suite.position(1);
+        assert_eq!(position.amount, Uint128::new(600));
+        assert_eq!(
+            position.collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(135))]
+        );
+        let isolation: IsolationResponse = suite.query(QueryMsg::GetIsolation {
+            token: ATOM.to_string(),
+        });
+        assert_eq!(
+            isolation.debt,
+            vec![(Token::Native(USDC.to_string()), Uint128::new(600))]
+        );
+        let response: AuctionsResponse = suite.query(QueryMsg::GetAuctions {
+            start_after: None,
+            limit: None,
+        });
+        assert!(response.auctions.is_empty());
+    }
+
+    #[test]
+    fn auction_config_validated() {
+        let mut suite = setup();
+        let update = |start_discount: u128, max_discount: u128| ExecuteMsg::UpdateAuctionConfig {
+            auction: Some(AuctionConfig {
+                start_discount: Uint128::new(start_discount),
+                max_discount: Uint128::new(max_discount),
+                duration: 3_600,
+            }),
+        };
+        assert_error(
+            suite.execute("admin", update(2_000, 500), &[]),
+            "Invalid auction discounts",
+        );
+        assert_error(
+            suite.execute("admin", update(500, 10_000), &[]),
+            "Invalid auction discounts",
+        );
+        assert_error(
+            suite.execute("borrower", update(500, 2_000), &[]),
+            "Unauthorized",
+        );
+        suite.execute("admin", update(500, 2_000), &[]).unwrap();
+
+        suite.open_loan();
+        suite.set_price(ATOM, 700);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::StartAuction {
+                    position_id: Uint128::one(),
+                },
+                &[],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn underwater_liquidation_draws_on_insurance() {
+        let mut suite = setup();
+        suite.open_loan();
+        suite
+            .execute(
+                "admin",
+                ExecuteMsg::DepositInsurance {
+                    token: USDC.to_string(),
+                    amount: Uint128::new(100),
+                },
+                &coins(100, USDC),
+            )
+            .unwrap();
+
+        // 200 ATOM at 400 are worth less than the 1000 USDC owed
+        suite.set_price(ATOM, 400);
+        let simulation = suite.simulate_liquidation(1);
+        assert!(!simulation.shortfall.is_zero());
+        assert_eq!(
+            simulation.repay_amount + simulation.shortfall,
+            Uint128::new(1_000)
+        );
+        assert_eq!(simulation.insurance_cover, Uint128::new(100));
+        assert_eq!(simulation.lender_share, simulation.repay_amount);
+
+        let lender_before = suite.balance("lender", USDC);
+        suite.liquidate(1, simulation.repay_amount.u128()).unwrap();
+        assert_eq!(
+            suite.balance("lender", USDC),
+            lender_before + simulation.repay_amount.u128() + 100
+        );
+
+        let fund = suite.insurance(USDC).fund;
+        assert_eq!(fund.balance, Uint128::zero());
+        assert_eq!(fund.total_covered, Uint128::new(100));
+        assert_eq!(
+            fund.total_bad_debt,
+            simulation.shortfall - Uint128::new(100)
+        );
+        let response: ShortfallsResponse = suite.query(QueryMsg::GetShortfalls {
+            start_after: None,
+            limit: None,
+        });
+        let (position_id, shortfall) = &response.shortfalls[0];
+        assert_eq!(*position_id, Uint128::one());
+        assert_eq!(shortfall.shortfall, simulation.shortfall);
+        assert_eq!(shortfall.covered, Uint128::new(100));
+        assert_eq!(shortfall.bad_debt, fund.total_bad_debt);
+    }
+
+    #[test]
+    fn reserves_move_into_insurance() {
+        let mut suite = setup_with(|msg| msg.liquidation_fee = Uint128::new(500));
+        let update_fee = |fee: u128| ExecuteMsg::UpdateLiquidationFee {
+            fee: Uint128::new(fee),
+        };
+        assert_error(
+            suite.execute("borrower", update_fee(1_000), &[]),
+            "Unauthorized",
+        );
+        assert_error(
+            suite.execute("admin", update_fee(1_001), &[]),
+            "Liquidation fee must not exceed 1000 basis points",
+        );
+        suite.execute("admin", update_fee(1_000), &[]).unwrap();
+        suite.open_loan();
+        suite.set_price(ATOM, 700);
+        let simulation = suite.simulate_liquidation(1);
+        assert!(simulation.shortfall.is_zero());
+        let lender_before = suite.balance("lender", USDC);
+        let borrower_before = suite.balance("borrower", ATOM);
+        suite.liquidate(1, 1_000).unwrap();
+
+        // Only the 10% liquidation fee is kept, the lender gets the rest of the debt
+        let usdc = suite.insurance(USDC);
+        assert_eq!(usdc.reserves, Uint128::new(100));
+        assert_eq!(usdc.reserves, simulation.protocol_share);
+        assert_eq!(suite.balance("lender", USDC), lender_before + 900);
+        // Collateral beyond the liquidator's payout goes back to the borrower, not reserves
+        assert!(suite.insurance(ATOM).reserves.is_zero());
+        assert_eq!(
+            suite.balance("borrower", ATOM),
+            borrower_before + simulation.collateral_returned.u128()
+        );
+        assert!(suite
+            .query::<ShortfallsResponse>(QueryMsg::GetShortfalls {
+                start_after: None,
+                limit: None,
+            })
+            .shortfalls
+            .is_empty());
+
+        let fund = |amount: Uint128| ExecuteMsg::FundInsurance {
+            token: USDC.to_string(),
+            amount,
+        };
+        assert_error(
+            suite.execute("borrower", fund(usdc.reserves), &[]),
+            "Unauthorized",
+        );
+        assert_error(
+            suite.execute("admin", fund(usdc.reserves + Uint128::one()), &[]),
+            "Insufficient reserves",
+        );
+        suite.execute("admin", fund(usdc.reserves), &[]).unwrap();
+        let after = suite.insurance(USDC);
+        assert_eq!(after.reserves, Uint128::zero());
+        assert_eq!(after.fund.balance, usdc.reserves);
+    }
+
+    #[test]
+    fn token_caps_limit_supply_borrow_and_collateral() {
+        let mut suite = setup();
+        let caps = |token: &str, caps: TokenCaps| ExecuteMsg::UpdateTokenCaps {
+            token: token.to_string(),
+            caps,
+        };
+        let usdc_caps = TokenCaps {
+            supply_cap: Some(Uint128::new(3_000)),
+            borrow_cap: Some(Uint128::new(1_500)),
+            collateral_cap: None,
+        };
+        let atom_caps = TokenCaps {
+            collateral_cap: Some(Uint128::new(300)),
+            ..TokenCaps::default()
+        };
+        assert_error(
+            suite.execute("borrower", caps(USDC, usdc_caps.clone()), &[]),
+            "Unauthorized",
+        );
+        suite.execute("admin", caps(USDC, usdc_caps), &[]).unwrap();
+        suite.execute("admin", caps(ATOM, atom_caps), &[]).unwrap();
+
+        suite.deposit("lender2", USDC, 2_000);
+        assert_error(
+            suite.execute(
+                "lender2",
+                ExecuteMsg::Deposit {
+                    token: USDC.to_string(),
+                    amount: Uint128::new(1_500),
+                },
+                &coins(1_500, USDC),
+            ),
+            "Supply cap exceeded: 1000 available",
+        );
+
+        let borrow =
+            |amount: u128, collateral: u128| borrow_msg(USDC, amount, 10, ATOM, collateral);
+        assert_error(
+            suite.execute("borrower", borrow(2_000, 400), &coins(400, ATOM)),
+            "Borrow cap exceeded: 1500 available",
+        );
+        suite.open_loan();
+        assert_error(
+            suite.execute("borrower", borrow(500, 150), &coins(150, ATOM)),
+            "Collateral cap exceeded: 100 available",
+        );
+
+        let usdc = suite.market(USDC);
+        assert_eq!(usdc.supply_headroom, Some(Uint128::zero()));
+        assert_eq!(usdc.borrow_headroom, Some(Uint128::new(500)));
+        assert_eq!(usdc.collateral_headroom, None);
+        assert_eq!(
+            suite.market(ATOM).collateral_headroom,
+            Some(Uint128::new(100))
+        );
+
+        // The request fits under the borrow cap but lenders have no supply room left
+        suite.borrow("borrower", 500, 10, 100);
+        assert_error(
+            suite.execute(
+                "lender",
+                ExecuteMsg::FillPosition {
+                    position_id: Uint128::new(2),
+                    amount: Uint128::new(500),
+                },
+                &coins(500, USDC),
+            ),
+            "Supply cap exceeded: 0 available",
+        );
+    }
+
+    #[test]
+    fn isolated_collateral_limits_borrow_tokens_and_debt() {
+        let mut suite = setup();
+        let isolate = |borrow_tokens: &[&str]| ExecuteMsg::UpdateIsolation {
+            token: ATOM.to_string(),
+            isolation: Some(Isolation {
+                borrow_tokens: borrow_tokens
+                    .iter()
+                    .map(|token| token.to_string())
+                    .collect(),
+                debt_ceiling: Uint128::new(150_000),
+            }),
+        };
+        assert_error(
+            suite.execute("borrower", isolate(&[USDC]), &[]),
+            "Unauthorized",
+        );
+        suite.execute("admin", isolate(&[ATOM]), &[]).unwrap();
+        assert_error(
+            suite.execute(
+                "borrower",
+                borrow_msg(USDC, 1_000, 10, ATOM, 200),
+                &coins(200, ATOM),
+            ),
+            "Borrow token not allowed against isolated collateral",
+        );
+
+        suite.execute("admin", isolate(&[USDC]), &[]).unwrap();
+        suite.open_loan();
+        assert_error(
+            suite.execute(
+                "borrower",
+                borrow_msg(USDC, 600, 10, ATOM, 200),
+                &coins(200, ATOM),
+            ),
+            "Debt ceiling exceeded: 50000 available",
+        );
+
+        let isolation = |suite: &Suite| -> IsolationResponse {
+            suite.query(QueryMsg::GetIsolation {
+                token: ATOM.to_string(),
+            })
+        };
+        let response = isolation(&suite);
+        assert_eq!(
+            response.debt,
+            vec![(Token::Native(USDC.to_string()), Uint128::new(1_000))]
+        );
+        assert_eq!(response.debt_value, Uint128::new(100_000));
+        assert_eq!(response.headroom, Uint128::new(50_000));
+        assert_eq!(
+            response.ceiling_used,
+            Decimal::from_ratio(100_000u128, 150_000u128)
+        );
+        let configs: TokenConfigsResponse = suite.query(QueryMsg::GetTokenConfigs {});
+        assert_eq!(
+            configs
+                .tokens
+                .iter()
+                .map(|config| config.isolated)
+                .collect::<Vec<_>>(),
+            vec![true, false]
+        );
+
+        // Repaying frees the ceiling again
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_000, USDC),
+            )
+            .unwrap();
+        let response = isolation(&suite);
+        assert_eq!(response.debt_value, Uint128::zero());
+        assert_eq!(response.headroom, Uint128::new(150_000));
+
+        // Open requests take no headroom, the ceiling is checked again once funded
+        suite.borrow("borrower", 1_000, 10, 200);
+        suite.borrow("borrower", 1_000, 10, 200);
+        assert_eq!(isolation(&suite).debt_value, Uint128::zero());
+        suite.fill("lender", 2, 1_000);
+        assert_error(
+            suite.execute(
+                "lender",
+                ExecuteMsg::FillPosition {
+                    position_id: Uint128::new(3),
+                    amount: Uint128::new(1_000),
+                },
+                &coins(1_000, USDC),
+            ),
+            "Debt ceiling exceeded: 50000 available",
+        );
+        assert_eq!(isolation(&suite).debt_value, Uint128::new(100_000));
+
+        // Debt stays tracked while isolation is lifted, so loans opened meanwhile count
+        // once the token is isolated again
+        suite
+            .execute(
+                "admin",
+                ExecuteMsg::UpdateIsolation {
+                    token: ATOM.to_string(),
+                    isolation: None,
+                },
+                &[],
+            )
+            .unwrap();
+        suite.fill("lender", 3, 1_000);
+        suite.execute("admin", isolate(&[USDC]), &[]).unwrap();
+        let response = isolation(&suite);
+        assert_eq!(response.debt_value, Uint128::new(200_000));
+        assert_eq!(response.headroom, Uint128::zero());
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::new(3),
+                },
+                &coins(1_000, USDC),
+            )
+            .unwrap();
+        assert_eq!(isolation(&suite).debt_value, Uint128::new(100_000));
+    }
+
+    #[test]
+    fn e_mode_category_overrides_risk_parameters() {
+        let mut suite = setup();
+        let category = |max_ltv: u64, threshold: u128, bonus: u128| EModeCategory {
+            tokens: vec![ATOM.to_string(), USDC.to_string()],
+            max_ltv: Decimal::percent(max_ltv),
+            liquidation_threshold: Uint128::new(threshold),
+            liquidation_bonus: Uint128::new(bonus),
+        };
+        let update =
+            |category: Option<EModeCategory>| ExecuteMsg::UpdateEModeCategory { id: 1, category };
+        let borrow = borrow_msg(USDC, 1_000, 10, ATOM, 120);
+
+        // 83% LTV is above the default max of 1/1.5
+        assert_error(
+            suite.execute("borrower", borrow.clone(), &coins(120, ATOM)),
+            "Insufficient collateral",
+        );
+
+        assert_error(
+            suite.execute("borrower", update(Some(category(90, 110, 200))), &[]),
+            "Unauthorized",
+        );
+        assert_error(
+            suite.execute("admin", update(Some(category(90, 99, 200))), &[]),
+            "Liquidation threshold must be at least 100",
+        );
+        assert_error(
+            suite.execute("admin", update(Some(category(95, 110, 200))), &[]),
+            "Max LTV must not open positions below the liquidation threshold",
+        );
+        assert_error(
+            suite.execute("admin", update(Some(category(90, 110, 10_000))), &[]),
+            "Liquidation bonus must be below 10000",
+        );
+        suite
+            .execute("admin", update(Some(category(90, 110, 200))), &[])
+            .unwrap();
+        let response: EModeCategoriesResponse = suite.query(QueryMsg::GetEModeCategories {});
+        assert_eq!(response.categories, vec![(1, category(90, 110, 200))]);
+
+        suite
+            .execute("borrower", borrow, &coins(120, ATOM))
+            .unwrap();
+        suite.fill("lender", 1, 1_000);
+        let health = suite.position_health(1);
+        assert_eq!(health.e_mode_category, Some(1));
+        assert_eq!(health.max_ltv, Decimal::percent(90));
+        assert_eq!(health.liquidation_threshold, Uint128::new(110));
+        assert_eq!(health.liquidation_bonus, Uint128::new(200));
+        assert!(!health.liquidatable);
+
+        // Liquidatable once collateral is worth less than 110% of the debt
+        suite.set_price(ATOM, 917);
+        assert!(!suite.position_health(1).liquidatable);
+        suite.set_price(ATOM, 916);
+        assert!(suite.position_health(1).liquidatable);
+
+        // Running loans keep the parameters they started with, new requests do not
+        suite.execute("admin", update(None), &[]).unwrap();
+        let health = suite.position_health(1);
+        assert_eq!(health.e_mode_category, Some(1));
+        assert_eq!(health.liquidation_threshold, Uint128::new(110));
+        suite.borrow("borrower", 1_000, 10, 200);
+        let health = suite.position_health(2);
+        assert_eq!(health.e_mode_category, None);
+        assert_eq!(health.liquidation_threshold, Uint128::new(150));
+        assert_eq!(
+            health.liquidation_bonus,
+            Uint128::new(DEFAULT_LIQUIDATION_BONUS)
+        );
+
+        // The bonus is a premium on the repaid debt value, so the e-mode position's 2%
+        // pays out less collateral than the default 5% for the same 100000 of debt
+        suite.fill("lender", 2, 1_000);
+        assert_eq!(
+            suite.simulate_liquidation(1).collateral_seized,
+            Uint128::new(111)
+        );
+        assert_eq!(
+            suite.simulate_liquidation(2).collateral_seized,
+            Uint128::new(114)
+        );
+    }
+
+    #[test]
+    fn margin_account_borrows_against_weighted_collateral() {
+        let mut suite = setup();
+        let enable = |token: &str, weight: u64, borrowable: bool| ExecuteMsg::UpdateMarginParams {
+            token: token.to_string(),
+            params: Some(MarginParams {
+                collateral_weight: Decimal::percent(weight),
+                max_ltv: Decimal::percent(50),
+                borrow_rate: Uint128::new(10),
+                borrowable,
+            }),
+        };
+        assert_error(
+            suite.execute("borrower", enable(ATOM, 80, false), &[]),
+            "Unauthorized",
+        );
+        assert_error(
+            suite.execute("admin", enable(ATOM, 120, false), &[]),
+            "Collateral weight must not exceed 1",
+        );
+        assert_error(
+            suite.execute(
+                "admin",
+                ExecuteMsg::UpdateMarginParams {
+                    token: ATOM.to_string(),
+                    params: Some(MarginParams {
+                        collateral_weight: Decimal::percent(80),
+                        max_ltv: Decimal::percent(60),
+                        borrow_rate: Uint128::new(10),
+                        borrowable: false,
+                    }),
+                },
+                &[],
+            ),
+            "Max LTV must be below the liquidation LTV",
+        );
+        suite
+            .execute("admin", enable(ATOM, 80, false), &[])
+            .unwrap();
+        suite.execute("admin", enable(USDC, 90, true), &[]).unwrap();
+        suite.deposit("lender", USDC, 1_000);
+
+        let account = |token: &str, amount: u128| ExecuteMsg::AccountDeposit {
+            token: token.to_string(),
+            amount: Uint128::new(amount),
+        };
+        let borrow = |amount: u128| ExecuteMsg::AccountBorrow {
+            token: USDC.to_string(),
+            amount: Uint128::new(amount),
+        };
+        suite
+            .execute("borrower", account(ATOM, 100), &coins(100, ATOM))
+            .unwrap();
+        assert_error(
+            suite.execute(
+                "borrower",
+                ExecuteMsg::AccountBorrow {
+                    token: ATOM.to_string(),
+                    amount: Uint128::new(1),
+                },
+                &[],
+            ),
+            "Token not borrowable from margin accounts",
+        );
+
+        // 100 ATOM at a 50% max LTV support 50000 of debt value
+        let margin = |suite: &Suite| -> MarginAccountResponse {
+            suite.query(QueryMsg::GetMarginAccount {
+                address: addr("borrower").to_string(),
+            })
+        };
+        let health = margin(&suite).health;
+        assert_eq!(health.collateral_value, Uint128::new(100_000));
+        assert_eq!(health.weighted_collateral_value, Uint128::new(80_000));
+        assert_eq!(health.borrow_limit_value, Uint128::new(50_000));
+        assert_eq!(health.borrow_capacity, Uint128::new(50_000));
+        assert_eq!(health.health_factor, None);
+        assert_error(
+            suite.execute("borrower", borrow(501), &[]),
+            "Account would exceed its borrow limit",
+        );
+        suite.execute("borrower", borrow(500), &[]).unwrap();
+        assert_eq!(suite.market(USDC).stats.pool_borrowed, Uint128::new(500));
+        assert_error(
+            suite.execute(
+                "lender",
+                ExecuteMsg::Withdraw {
+                    token: USDC.to_string(),
+                    amount: Uint128::new(600),
+                },
+                &[],
+            ),
+            "Insufficient liquidity",
+        );
+        assert_error(
+            suite.execute(
+                "borrower",
+                ExecuteMsg::AccountWithdraw {
+                    token: ATOM.to_string(),
+                    amount: Uint128::new(10),
+                },
+                &[],
+            ),
+            "Account would exceed its borrow limit",
+        );
+
+        // A year at 10% adds 50 of interest, repaid before principal
+        suite.advance(YEAR);
+        let response = margin(&suite);
+        assert_eq!(response.debts[0].1.interest, Uint128::new(50));
+        assert_eq!(response.health.debt_value, Uint128::new(55_000));
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::AccountRepay {
+                    token: USDC.to_string(),
+                    amount: Uint128::new(100),
+                    borrower: None,
+                },
+                &coins(100, USDC),
+            )
+            .unwrap();
+        let response = margin(&suite);
+        assert_eq!(response.debts[0].1.interest, Uint128::zero());
+        assert_eq!(response.debts[0].1.principal, Uint128::new(450));
+        // 10% of the interest is kept as reserves, the rest goes to depositors
+        assert_eq!(suite.insurance(USDC).reserves, Uint128::new(5));
+        assert_eq!(suite.deposited("lender", USDC), 1_045);
+        assert_error(
+            suite.execute(
+                "admin",
+                ExecuteMsg::UpdateMarginParams {
+                    token: ATOM.to_string(),
+                    params: None,
+                },
+                &[],
+            ),
+            "Token still held or owed by margin accounts",
+        );
+
+        // At ATOM 800 the weighted 64000 is below 450 * 100 * 1.5
+        suite.set_price(ATOM, 800);
+        assert!(margin(&suite).health.liquidatable);
+        let liquidate = |amount: u128| ExecuteMsg::LiquidateAccount {
+            borrower: addr("borrower").to_string(),
+            debt_token: USDC.to_string(),
+            amount: Uint128::new(amount),
+            collateral_token: ATOM.to_string(),
+        };
+        let atom_before = suite.balance("liquidator", ATOM);
+        let usdc_before = suite.balance("liquidator", USDC);
+        suite
+            .execute("liquidator", liquidate(200), &coins(200, USDC))
+            .unwrap();
+        // 200 USDC worth 20000 plus the 5% bonus buys 21000 / 800 ATOM
+        assert_eq!(suite.balance("liquidator", ATOM), atom_before + 26);
+        assert_eq!(suite.balance("liquidator", USDC), usdc_before - 200);
+        let response = margin(&suite);
+        assert_eq!(
+            response.collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(74))]
+        );
+        assert_eq!(response.debts[0].1.principal, Uint128::new(250));
+
+        // A missing price can't be used to size the seized collateral
+        suite.set_price(ATOM, 0);
+        assert_error(
+            suite.execute("liquidator", liquidate(100), &coins(100, USDC)),
+            "Missing oracle price",
+        );
+    }
+
+    #[test]
+    fn basket_collateral_liquidates_one_token_at_a_time() {
+        let mut suite = setup_with(|msg| msg.initial_tokens.push(JUNO.to_string()));
+        suite.set_price(JUNO, 100);
+        let basket = |atom: u128, juno: u128| ExecuteMsg::Borrow {
+            borrow_token: USDC.to_string(),
+            amount: Uint128::new(1_000),
+            interest_rate: Uint128::new(10),
+            collateral: vec![
+                (ATOM.to_string(), Uint128::new(atom)),
+                (JUNO.to_string(), Uint128::new(juno)),
+            ],
+            term: None,
+            allowed_lenders: None,
+            collateral_source: None,
+            return_to_deposits: None,
+        };
+        let funds = |atom: u128, juno: u128| vec![coin(atom, ATOM), coin(juno, JUNO)];
+        assert_error(
+            suite.execute(
+                "borrower",
+                ExecuteMsg::Borrow {
+                    borrow_token: USDC.to_string(),
+                    amount: Uint128::new(1_000),
+                    interest_rate: Uint128::new(10),
+                    collateral: vec![
+                        (ATOM.to_string(), Uint128::new(100)),
+                        (ATOM.to_string(), Uint128::new(100)),
+                    ],
+                    term: None,
+                    allowed_lenders: None,
+                    collateral_source: None,
+                    return_to_deposits: None,
+                },
+                &coins(200, ATOM),
+            ),
+            "Duplicate collateral token",
+        );
+        suite
+            .execute("borrower", basket(100, 1_000), &funds(100, 1_000))
+            .unwrap();
+        suite.fill("lender", 1, 1_000);
+
+        let health = suite.position_health(1);
+        assert_eq!(health.collateral_value, Uint128::new(200_000));
+        assert_eq!(health.collateral_market_value, Uint128::new(200_000));
+        assert_eq!(health.liquidation_price, None);
+
+        // Half weight on JUNO counts 50000 of its 100000
+        let weight = |weight: Option<Decimal>| ExecuteMsg::UpdateCollateralWeight {
+            token: JUNO.to_string(),
+            weight,
+        };
+        assert_error(
+            suite.execute("borrower", weight(Some(Decimal::percent(50))), &[]),
+            "Unauthorized",
+        );
+        assert_error(
+            suite.execute("admin", weight(Some(Decimal::percent(101))), &[]),
+            "Collateral weight must not exceed 1",
+        );
+        suite
+            .execute("admin", weight(Some(Decimal::percent(50))), &[])
+            .unwrap();
+        let health = suite.position_health(1);
+        assert_eq!(health.collateral_value, Uint128::new(150_000));
+        assert_eq!(health.collateral_market_value, Uint128::new(200_000));
+        assert!(!health.liquidatable);
+
+        suite.set_price(ATOM, 900);
+        assert!(suite.position_health(1).liquidatable);
+        // The bot is pointed at the most valuable token of the basket
+        let entry = suite.liquidatable(None, None).positions.remove(0);
+        assert_eq!(entry.collateral_token, Token::Native(JUNO.to_string()));
+
+        // JUNO is 100000 of the 190000 basket value and settles that share of the debt
+        let simulation: SimulateLiquidationResponse = suite.query(QueryMsg::SimulateLiquidation {
+            position_id: Uint128::one(),
+            collateral_token: JUNO.to_string(),
+        });
+        assert_eq!(simulation.repay_amount, Uint128::new(526));
+        let juno_before = suite.balance("liquidator", JUNO);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::Liquidate {
+                    position_id: Uint128::one(),
+                    collateral_token: JUNO.to_string(),
+                },
+                &coins(526, USDC),
+            )
+            .unwrap();
+        assert_eq!(
+            suite.balance("liquidator", JUNO),
+            juno_before + simulation.collateral_seized.u128()
+        );
+
+        let response: PositionResponse = suite.query(QueryMsg::GetPosition {
+            position_id: Uint128::one(),
+        });
+        let position = response.position;
+        assert_eq!(position.amount, Uint128::new(474));
+        assert_eq!(
+            position.collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(100))]
+        );
+        // Left on ATOM alone, the remaining principal counts against ATOM's debt
+        let isolation: IsolationResponse = suite.query(QueryMsg::GetIsolation {
+            token: ATOM.to_string(),
+        });
+        assert_eq!(
+            isolation.debt,
+            vec![(Token::Native(USDC.to_string()), Uint128::new(474))]
+        );
+        assert!(!suite.position_health(1).liquidatable);
+
+        // Without collateral prices there is no market value to split the debt by
+        suite.set_price(ATOM, 0);
+        assert!(suite.position_health(1).liquidatable);
+        assert!(suite.liquidatable(None, None).positions.is_empty());
+        assert!(suite
+            .query_error(QueryMsg::SimulateLiquidation {
+                position_id: Uint128::one(),
+                collateral_token: ATOM.to_string(),
+            })
+            .contains("Missing oracle price"));
+        assert_error(suite.liquidate(1, 474), "Missing oracle price");
+    }
+
+    #[test]
+    fn several_lenders_share_a_request() {
+        let mut suite = setup();
+        suite.borrow("borrower", 1_000, 10, 200);
+        let fill = |amount: u128| ExecuteMsg::FillPosition {
+            position_id: Uint128::one(),
+            amount: Uint128::new(amount),
+        };
+        suite.fill("lender", 1, 700);
+        assert_error(
+            suite.execute("lender2", fill(400), &coins(400, USDC)),
+            "Amount mismatch: 300 left to fund",
+        );
+        let borrower_before = suite.balance("borrower", USDC);
+        suite.fill("lender2", 1, 300);
+        assert_eq!(suite.balance("borrower", USDC), borrower_before + 1_000);
+
+        let response: PositionResponse = suite.query(QueryMsg::GetPosition {
+            position_id: Uint128::one(),
+        });
+        assert!(response.position.filled);
+        assert_eq!(
+            response.position.lenders,
+            vec![
+                (addr("lender"), Uint128::new(700)),
+                (addr("lender2"), Uint128::new(300))
+            ]
+        );
+
+        // Repayment is split pro-rata to the funded shares
+        suite.advance(YEAR);
+        let lender_before = suite.balance("lender", USDC);
+        let lender2_before = suite.balance("lender2", USDC);
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_100, USDC),
+            )
+            .unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 770);
+        assert_eq!(suite.balance("lender2", USDC), lender2_before + 330);
+    }
+
+    #[test]
+    fn partial_fill_cancelled_or_accepted() {
+        let mut suite = setup();
+        suite.borrow("borrower", 1_000, 10, 200);
+        let position = |msg: fn(Uint128) -> ExecuteMsg| msg(Uint128::one());
+        let cancel = || position(|position_id| ExecuteMsg::CancelFill { position_id });
+        let accept = || position(|position_id| ExecuteMsg::AcceptPartialFill { position_id });
+
+        assert_error(
+            suite.execute("borrower", accept(), &[]),
+            "Position not funded",
+        );
+        suite.fill("lender", 1, 300);
+        suite.fill("lender2", 1, 200);
+        assert_error(
+            suite.execute("liquidator", cancel(), &[]),
+            "No funding to cancel",
+        );
+        let lender2_before = suite.balance("lender2", USDC);
+        suite.execute("lender2", cancel(), &[]).unwrap();
+        assert_eq!(suite.balance("lender2", USDC), lender2_before + 200);
+
+        assert_error(suite.execute("lender", accept(), &[]), "Not borrower");
+        let borrower_before = suite.balance("borrower", USDC);
+        suite.execute("borrower", accept(), &[]).unwrap();
+        assert_eq!(suite.balance("borrower", USDC), borrower_before + 300);
+
+        let response: PositionResponse = suite.query(QueryMsg::GetPosition {
+            position_id: Uint128::one(),
+        });
+        assert!(response.position.filled);
+        assert_eq!(response.position.amount, Uint128::new(300));
+        assert_eq!(
+            response.position.lenders,
+            vec![(addr("lender"), Uint128::new(300))]
+        );
+        assert_error(
+            suite.execute("lender", cancel(), &[]),
+            "Position already filled",
+        );
+    }
+
+    /// Messages of a minimal cw721 holding lender notes
+    #[cw_serde]
+    enum NftMsg {
+        Mint {
+            token_id: String,
+            owner: String,
+            token_uri: Option<String>,
+            extension: Option<Empty>,
+        },
+        TransferNft {
+            recipient: String,
+            token_id: String,
+        },
+    }
+
+    const NFT_OWNERS: Map<&str, Addr> = Map::new("owners");
+
+    fn nft_execute(
+        deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        msg: NftMsg,
+    ) -> StdResult<Response> {
+        match msg {
+            NftMsg::Mint {
+                token_id, owner, ..
+            } => NFT_OWNERS.save(deps.storage, &token_id, &deps.api.addr_validate(&owner)?)?,
+            NftMsg::TransferNft {
+                recipient,
+                token_id,
+            } => {
+                if NFT_OWNERS.load(deps.storage, &token_id)? != info.sender {
+                    return Err(StdError::generic_err("Unauthorized"));
+                }
+                NFT_OWNERS.save(
+                    deps.storage,
+                    &token_id,
+                    &deps.api.addr_validate(&recipient)?,
+                )?
+            }
+        }
+        Ok(Response::new())
+    }
+
+    fn nft_query(deps: Deps, _env: Env, msg: crate::cw721::Cw721QueryMsg) -> StdResult<Binary> {
+        let crate::cw721::Cw721QueryMsg::OwnerOf { token_id, .. } = msg;
+        to_json_binary(&crate::cw721::OwnerOfResponse {
+            owner: NFT_OWNERS.load(deps.storage, &token_id)?.to_string(),
+            approvals: vec![],
+        })
+    }
+
+    #[test]
+    fn lender_note_holder_receives_repayment() {
+        let mut suite = setup();
+        let code = suite.app.store_code(Box::new(ContractWrapper::new(
+            nft_execute,
+            empty_instantiate,
+            nft_query,
+        )));
+        let nft = suite
+            .app
+            .instantiate_contract(code, addr("admin"), &Empty {}, &[], "notes", None)
+            .unwrap();
+        let update = ExecuteMsg::UpdateLenderNft {
+            address: Some(nft.to_string()),
+        };
+        assert_error(
+            suite.execute("borrower", update.clone(), &[]),
+            "Unauthorized",
+        );
+        suite.execute("admin", update, &[]).unwrap();
+        suite.open_loan();
+
+        let notes = |suite: &Suite| -> LenderNotesResponse {
+            suite.query(QueryMsg::GetLenderNotes {
+                position_id: Uint128::one(),
+            })
+        };
+        assert_eq!(
+            notes(&suite),
+            LenderNotesResponse {
+                lender_nft: Some(nft.clone()),
+                notes: vec![LenderNote {
+                    token_id: Some("1-0-0".to_string()),
+                    holder: addr("lender"),
+                    share: Uint128::new(1_000),
+                }],
+            }
+        );
+
+        suite
+            .app
+            .execute_contract(
+                addr("lender"),
+                nft,
+                &NftMsg::TransferNft {
+                    recipient: addr("lender2").to_string(),
+                    token_id: "1-0-0".to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+        assert_eq!(notes(&suite).notes[0].holder, addr("lender2"));
+
+        suite.advance(YEAR);
+        let lender_before = suite.balance("lender", USDC);
+        let lender2_before = suite.balance("lender2", USDC);
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_100, USDC),
+            )
+            .unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before);
+        assert_eq!(suite.balance("lender2", USDC), lender2_before + 1_100);
+    }
+
+    #[test]
+    fn refinance_with_signed_offer() {
+        let mut suite = setup();
+        suite.open_loan();
+        let key = SigningKey::from_bytes(&[9u8; 32].into()).unwrap();
+        suite.register_key(&key);
+        suite.deposit("lender", USDC, 5_000);
+        suite.advance(YEAR);
+
+        // 10% over a year: the payoff is 1100
+        let (offer, signature) = suite.signed_offer(&key, 1, 2_000, 5);
+        let refinance = |offer: LendOffer, signature: Binary| ExecuteMsg::Refinance {
+            position_id: Uint128::one(),
+            offer,
+            signature,
+        };
+        assert_error(
+            suite.execute("lender", refinance(offer.clone(), signature.clone()), &[]),
+            "Not borrower or operator",
+        );
+        let (expensive, expensive_signature) = suite.signed_offer(&key, 2, 2_000, 10);
+        assert_error(
+            suite.execute("borrower", refinance(expensive, expensive_signature), &[]),
+            "Refinance rate must be below the current rate",
+        );
+        let (short, short_signature) = suite.signed_offer(&key, 3, 1_000, 5);
+        assert_error(
+            suite.execute("borrower", refinance(short, short_signature), &[]),
+            "Offer amount 1000 below the payoff 1100",
+        );
+
+        let mut offer = offer;
+        offer.term = Some(86_400);
+        let signature = sign(&key, &offer);
+        suite
+            .execute("borrower", refinance(offer, signature), &[])
+            .unwrap();
+        let position = suite.position(1);
+        assert_eq!(position.amount, Uint128::new(1_100));
+        assert_eq!(position.interest_rate, Uint128::new(5));
+        assert_eq!(
+            position.lenders,
+            vec![(addr("lender"), Uint128::new(1_100))]
+        );
+        assert_eq!(position.refinance_count, 1);
+        assert_eq!(
+            position.maturity,
+            Some(suite.app.block_info().time.seconds() + 86_400)
+        );
+        assert_eq!(suite.deposited("lender", USDC), 5_000 - 1_100);
+    }
+
+    #[test]
+    fn new_lender_takes_over_loan() {
+        let mut suite = setup();
+        suite.open_loan();
+        suite.advance(YEAR);
+        let take_over = |interest_rate: u128| ExecuteMsg::TakeOverLoan {
+            position_id: Uint128::one(),
+            interest_rate: Uint128::new(interest_rate),
+        };
+
+        // 10% over a year: the payoff is 1100
+        assert_error(
+            suite.execute("lender2", take_over(10), &coins(1_100, USDC)),
+            "Refinance rate must be below the current rate",
+        );
+        assert_error(
+            suite.execute("lender2", take_over(5), &coins(1_099, USDC)),
+            "Insufficient funds",
+        );
+        let lender_before = suite.balance("lender", USDC);
+        let lender2_before = suite.balance("lender2", USDC);
+        let maturity = suite.position(1).maturity;
+        suite
+            .execute("lender2", take_over(5), &coins(1_200, USDC))
+            .unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 1_100);
+        assert_eq!(suite.balance("lender2", USDC), lender2_before - 1_100);
+
+        let position = suite.position(1);
+        assert_eq!(position.amount, Uint128::new(1_100));
+        assert_eq!(position.interest_rate, Uint128::new(5));
+        assert_eq!(
+            position.lenders,
+            vec![(addr("lender2"), Uint128::new(1_100))]
+        );
+        assert_eq!(position.refinance_count, 1);
+        assert_eq!(position.maturity, maturity);
+        assert_eq!(suite.market(USDC).stats.total_lent, Uint128::new(1_100));
+
+        // The new lender is repaid at the new rate
+        suite.advance(YEAR);
+        let lender2_before = suite.balance("lender2", USDC);
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_155, USDC),
+            )
+            .unwrap();
+        assert_eq!(suite.balance("lender2", USDC), lender2_before + 1_155);
+    }
+
+    #[test]
+    fn extension_approved_by_all_lenders_capitalizes_interest() {
+        let mut suite = setup();
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Borrow {
+                    borrow_token: USDC.to_string(),
+                    amount: Uint128::new(1_000),
+                    interest_rate: Uint128::new(10),
+                    collateral: vec![(ATOM.to_string(), Uint128::new(200))],
+                    term: Some(YEAR),
+                    allowed_lenders: None,
+                    collateral_source: None,
+                    return_to_deposits: None,
+                },
+                &coins(200, ATOM),
+            )
+            .unwrap();
+        suite.fill("lender", 1, 600);
+        suite.fill("lender2", 1, 400);
+        let start = suite.app.block_info().time.seconds();
+        let position = |suite: &Suite| {
+            suite
+                .query::<PositionResponse>(QueryMsg::GetPosition {
+                    position_id: Uint128::one(),
+                })
+                .position
+        };
+        assert_eq!(position(&suite).maturity, Some(start + YEAR));
+
+        let propose = |maturity: u64| ExecuteMsg::ProposeExtension {
+            position_id: Uint128::one(),
+            maturity,
+            interest_rate: Some(Uint128::new(8)),
+            capitalize_interest: true,
+        };
+        let accept = ExecuteMsg::AcceptExtension {
+            position_id: Uint128::one(),
+        };
+        assert_error(
+            suite.execute("liquidator", propose(start + 2 * YEAR), &[]),
+            "Not lender",
+        );
+        assert_error(
+            suite.execute("lender", propose(start + YEAR), &[]),
+            "Maturity must be after the current maturity",
+        );
+        suite.advance(YEAR / 2);
+        suite
+            .execute("lender", propose(start + 2 * YEAR), &[])
+            .unwrap();
+        assert_error(
+            suite.execute("borrower", accept.clone(), &[]),
+            "Extension not approved by all lenders",
+        );
+        assert_error(
+            suite.execute("lender2", propose(start + 3 * YEAR), &[]),
+            "Another extension proposal is pending",
+        );
+        suite
+            .execute("lender2", propose(start + 2 * YEAR), &[])
+            .unwrap();
+        let response: ExtensionProposalResponse = suite.query(QueryMsg::GetExtensionProposal {
+            position_id: Uint128::one(),
+        });
+        assert_eq!(
+            response.proposal.unwrap().approvals,
+            vec![addr("lender"), addr("lender2")]
+        );
+        suite.execute("borrower", accept, &[]).unwrap();
+
+        // Half a year at 10% is capitalized and split over the shares
+        let extended = position(&suite);
+        assert_eq!(extended.amount, Uint128::new(1_050));
+        assert_eq!(extended.interest_rate, Uint128::new(8));
+        assert_eq!(extended.maturity, Some(start + 2 * YEAR));
+        assert_eq!(
+            extended.lenders,
+            vec![
+                (addr("lender"), Uint128::new(630)),
+                (addr("lender2"), Uint128::new(420))
+            ]
+        );
+        let response: PositionHistoryResponse = suite.query(QueryMsg::GetPositionHistory {
+            position_id: Uint128::one(),
+        });
+        assert_eq!(
+            response.history,
+            vec![PositionEvent::Extended {
+                timestamp: start + YEAR / 2,
+                previous_maturity: start + YEAR,
+                maturity: start + 2 * YEAR,
+                interest_rate: Uint128::new(8),
+                interest: Uint128::new(50),
+                capitalized: true,
+            }]
+        );
+
+        // Overdue loans can be liquidated whatever their collateral
+        suite.advance(YEAR);
+        assert!(!suite.position_health(1).liquidatable);
+        suite.advance(YEAR / 2);
+        assert!(suite.position_health(1).liquidatable);
+    }
+
+    #[test]
+    fn extension_after_partial_liquidation() {
+        let mut suite = setup_with(|msg| msg.initial_tokens.push(JUNO.to_string()));
+        suite.set_price(JUNO, 100);
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Borrow {
+                    borrow_token: USDC.to_string(),
+                    amount: Uint128::new(1_000),
+                    interest_rate: Uint128::new(10),
+                    collateral: vec![
+                        (ATOM.to_string(), Uint128::new(100)),
+                        (JUNO.to_string(), Uint128::new(1_000)),
+                    ],
+                    term: Some(YEAR),
+                    allowed_lenders: None,
+                    collateral_source: None,
+                    return_to_deposits: None,
+                },
+                &[coin(100, ATOM), coin(1_000, JUNO)],
+            )
+            .unwrap();
+        suite.fill("lender", 1, 600);
+        suite.fill("lender2", 1, 400);
+        suite
+            .execute(
+                "admin",
+                ExecuteMsg::UpdateCollateralWeight {
+                    token: JUNO.to_string(),
+                    weight: Some(Decimal::percent(50)),
+                },
+                &[],
+            )
+            .unwrap();
+        suite.set_price(ATOM, 900);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::Liquidate {
+                    position_id: Uint128::one(),
+                    collateral_token: JUNO.to_string(),
+                },
+                &coins(526, USDC),
+            )
+            .unwrap();
+
+        // The shares follow the principal left after the liquidation
+        let position = suite.position(1);
+        assert_eq!(position.amount, Uint128::new(474));
+        assert_eq!(
+            position.lenders,
+            vec![
+                (addr("lender"), Uint128::new(284)),
+                (addr("lender2"), Uint128::new(190))
+            ]
+        );
+
+        suite.advance(YEAR / 2);
+        let start = position.start_time;
+        let propose = ExecuteMsg::ProposeExtension {
+            position_id: Uint128::one(),
+            maturity: start + 2 * YEAR,
+            interest_rate: None,
+            capitalize_interest: true,
+        };
+        suite.execute("lender", propose.clone(), &[]).unwrap();
+        suite.execute("lender2", propose, &[]).unwrap();
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::AcceptExtension {
+                    position_id: Uint128::one(),
+                },
+                &[],
+            )
+            .unwrap();
+        let extended = suite.position(1);
+        assert_eq!(extended.amount, Uint128::new(497));
+        assert_eq!(
+            extended.lenders,
+            vec![
+                (addr("lender"), Uint128::new(297)),
+                (addr("lender2"), Uint128::new(200))
+            ]
+        );
+    }
+
+    #[test]
+    fn signed_offer_taken_once() {
+        let mut suite = setup();
+        let key = SigningKey::from_bytes(&[7u8; 32].into()).unwrap();
+        suite.register_key(&key);
+        let response: OfferKeyResponse = suite.query(QueryMsg::GetOfferKey {
+            lender: addr("lender").to_string(),
+        });
+        assert_eq!(
+            response.public_key,
+            Some(Binary::from(
+                key.verifying_key().to_encoded_point(true).as_bytes()
+            ))
+        );
+        suite.deposit("lender", USDC, 5_000);
+
+        let (offer, signature) = suite.signed_offer(&key, 1, 1_000, 10);
+        let take = |offer: LendOffer, signature: Binary| ExecuteMsg::TakeSignedOffer {
+            offer,
+            signature,
+            collateral: vec![(ATOM.to_string(), Uint128::new(200))],
+        };
+        let usdc_before = suite.balance("borrower", USDC);
+        suite
+            .execute(
+                "borrower",
+                take(offer.clone(), signature.clone()),
+                &coins(200, ATOM),
+            )
+            .unwrap();
+        assert_eq!(suite.balance("borrower", USDC), usdc_before + 1_000);
+        assert_eq!(suite.deposited("lender", USDC), 4_000);
+        let position = suite.position(1);
+        assert!(position.filled);
+        assert_eq!(
+            position.lenders,
+            vec![(addr("lender"), Uint128::new(1_000))]
+        );
+        let nonce = |suite: &Suite, nonce: u64| -> OfferNonceResponse {
+            suite.query(QueryMsg::GetOfferNonce {
+                lender: addr("lender").to_string(),
+                nonce,
+            })
+        };
+        assert_eq!(
+            nonce(&suite, 1),
+            OfferNonceResponse {
+                used: true,
+                cancelled: false
+            }
+        );
+
+        // Replayed
+        assert_error(
+            suite.execute("borrower", take(offer, signature), &coins(200, ATOM)),
+            "Offer already taken or cancelled",
+        );
+
+        // Cancelled before being taken
+        let (offer, signature) = suite.signed_offer(&key, 2, 1_000, 10);
+        suite
+            .execute("lender", ExecuteMsg::CancelOffers { nonces: vec![2] }, &[])
+            .unwrap();
+        assert!(nonce(&suite, 2).cancelled);
+        assert_error(
+            suite.execute("borrower", take(offer, signature), &coins(200, ATOM)),
+            "Offer already taken or cancelled",
+        );
+
+        // Tampered with after signing
+        let (mut offer, signature) = suite.signed_offer(&key, 3, 1_000, 10);
+        offer.interest_rate = Uint128::new(1);
+        assert_error(
+            suite.execute("borrower", take(offer, signature), &coins(200, ATOM)),
+            "Invalid offer signature",
+        );
+
+        // Expired
+        let (offer, signature) = suite.signed_offer(&key, 4, 1_000, 10);
+        suite.advance(3_600);
+        assert_error(
+            suite.execute("borrower", take(offer, signature), &coins(200, ATOM)),
+            "Offer expired",
+        );
+        assert_eq!(suite.deposited("lender", USDC), 4_000);
+    }
+
+    #[test]
+    fn private_request_only_fillable_by_allowed_lenders() {
+        let mut suite = setup();
+        let private = |allowed_lenders: Vec<String>| ExecuteMsg::Borrow {
+            borrow_token: USDC.to_string(),
+            amount: Uint128::new(1_000),
+            interest_rate: Uint128::new(10),
+            collateral: vec![(ATOM.to_string(), Uint128::new(200))],
+            term: None,
+            allowed_lenders: Some(allowed_lenders),
+            collateral_source: None,
+            return_to_deposits: None,
+        };
+        assert_error(
+            suite.execute("borrower", private(vec![]), &coins(200, ATOM)),
+            "No allowed lenders",
+        );
+        suite
+            .execute(
+                "borrower",
+                private(vec![addr("lender2").to_string()]),
+                &coins(200, ATOM),
+            )
+            .unwrap();
+        suite.borrow("borrower", 500, 10, 100);
+
+        // Only the public request shows in the order book
+        let response: OpenRequestsResponse = suite.query(QueryMsg::GetOpenRequests {
+            filter: None,
+            sort: None,
+            start_after: None,
+            limit: None,
+        });
+        assert_eq!(
+            response
+                .requests
+                .iter()
+                .map(|request| request.position_id.u128())
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        assert_error(
+            suite.execute(
+                "lender",
+                ExecuteMsg::FillPosition {
+                    position_id: Uint128::one(),
+                    amount: Uint128::new(1_000),
+                },
+                &coins(1_000, USDC),
+            ),
+            "Not an allowed lender",
+        );
+        suite.fill("lender2", 1, 1_000);
+        assert!(suite.position(1).filled);
+    }
+
+    #[test]
+    fn counter_offer_accepted_refunds_other_funding() {
+        let mut suite = setup();
+        suite.borrow("borrower", 1_000, 10, 200);
+        suite.fill("lender", 1, 300);
+        let counter = |amount: u128, rate: u128| ExecuteMsg::CounterOffer {
+            position_id: Uint128::one(),
+            amount: Uint128::new(amount),
+            interest_rate: Uint128::new(rate),
+            term: None,
+        };
+        assert_error(
+            suite.execute("lender2", counter(1_001, 8), &coins(1_001, USDC)),
+            "Amount mismatch: at most 1000 can be offered",
+        );
+        suite
+            .execute("lender2", counter(800, 8), &coins(800, USDC))
+            .unwrap();
+        assert_error(
+            suite.execute("lender2", counter(800, 7), &coins(800, USDC)),
+            "Counter-offer already posted",
+        );
+        suite
+            .execute("liquidator", counter(1_000, 9), &coins(1_000, USDC))
+            .unwrap();
+        let offers: CounterOffersResponse = suite.query(QueryMsg::GetCounterOffers {
+            position_id: Uint128::one(),
+        });
+        assert_eq!(offers.offers.len(), 2);
+
+        // Withdrawn before acceptance
+        let withdraw = ExecuteMsg::WithdrawCounterOffer {
+            position_id: Uint128::one(),
+        };
+        let liquidator_before = suite.balance("liquidator", USDC);
+        suite.execute("liquidator", withdraw.clone(), &[]).unwrap();
+        assert_eq!(suite.balance("liquidator", USDC), liquidator_before + 1_000);
+        assert_error(
+            suite.execute("liquidator", withdraw, &[]),
+            "No counter-offer to withdraw",
+        );
+
+        let accept = |lender: &str| ExecuteMsg::AcceptCounterOffer {
+            position_id: Uint128::one(),
+            lender: addr(lender).to_string(),
+        };
+        assert_error(
+            suite.execute("lender2", accept("lender2"), &[]),
+            "Not borrower",
+        );
+        assert_error(
+            suite.execute("borrower", accept("liquidator"), &[]),
+            "No counter-offer from this lender",
+        );
+        let lender_before = suite.balance("lender", USDC);
+        let borrower_before = suite.balance("borrower", USDC);
+        suite.execute("borrower", accept("lender2"), &[]).unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 300);
+        assert_eq!(suite.balance("borrower", USDC), borrower_before + 800);
+
+        let position = suite.position(1);
+        assert!(position.filled);
+        assert_eq!(position.amount, Uint128::new(800));
+        assert_eq!(position.interest_rate, Uint128::new(8));
+        assert_eq!(position.lenders, vec![(addr("lender2"), Uint128::new(800))]);
+        let offers: CounterOffersResponse = suite.query(QueryMsg::GetCounterOffers {
+            position_id: Uint128::one(),
+        });
+        assert!(offers.offers.is_empty());
+
+        // A counter-offer left on a request filled otherwise is refundable after repayment
+        suite.borrow("borrower", 1_000, 10, 200);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::CounterOffer {
+                    position_id: Uint128::new(2),
+                    amount: Uint128::new(500),
+                    interest_rate: Uint128::new(8),
+                    term: None,
+                },
+                &coins(500, USDC),
+            )
+            .unwrap();
+        suite.fill("lender", 2, 1_000);
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::new(2),
+                },
+                &coins(1_000, USDC),
+            )
+            .unwrap();
+        let liquidator_before = suite.balance("liquidator", USDC);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::WithdrawCounterOffer {
+                    position_id: Uint128::new(2),
+                },
+                &[],
+            )
+            .unwrap();
+        assert_eq!(suite.balance("liquidator", USDC), liquidator_before + 500);
+    }
+
+    #[test]
+    fn third_party_repays_and_operator_manages_collateral() {
+        let mut suite = setup();
+        suite.open_loan();
+        let manage = |withdraw: bool, amount: u128| {
+            let (position_id, token, amount) =
+                (Uint128::one(), ATOM.to_string(), Uint128::new(amount));
+            if withdraw {
+                ExecuteMsg::WithdrawCollateral {
+                    position_id,
+                    token,
+                    amount,
+                }
+            } else {
+                ExecuteMsg::AddCollateral {
+                    position_id,
+                    token,
+                    amount,
+                    source: None,
+                }
+            }
+        };
+        assert_error(
+            suite.execute("lender2", manage(false, 10), &coins(10, ATOM)),
+            "Not borrower or operator",
+        );
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::ApproveOperator {
+                    operator: addr("lender2").to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+        let operators: OperatorsResponse = suite.query(QueryMsg::GetOperators {
+            borrower: addr("borrower").to_string(),
+        });
+        assert_eq!(operators.operators, vec![addr("lender2")]);
+
+        // The operator tops up, and withdrawals go to the borrower within the max LTV
+        suite
+            .execute("lender2", manage(false, 10), &coins(10, ATOM))
+            .unwrap();
+        assert_eq!(
+            suite.position(1).collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(210))]
+        );
+        assert_error(
+            suite.execute("lender2", manage(true, 61), &[]),
+            "Insufficient collateral",
+        );
+        let borrower_atom = suite.balance("borrower", ATOM);
+        suite.execute("lender2", manage(true, 60), &[]).unwrap();
+        assert_eq!(suite.balance("borrower", ATOM), borrower_atom + 60);
+
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::RevokeOperator {
+                    operator: addr("lender2").to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+        assert_error(
+            suite.execute("lender2", manage(true, 1), &[]),
+            "Not borrower or operator",
+        );
+
+        // Anyone can repay, the collateral still goes back to the borrower
+        suite.advance(YEAR);
+        let lender_before = suite.balance("lender", USDC);
+        let borrower_atom = suite.balance("borrower", ATOM);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_100, USDC),
+            )
+            .unwrap();
+        assert_eq!(suite.balance("lender", USDC), lender_before + 1_100);
+        assert_eq!(suite.balance("borrower", ATOM), borrower_atom + 150);
+    }
+
+    #[test]
+    fn collateral_posted_from_and_returned_to_deposits() {
+        let mut suite = setup();
+        suite.deposit("borrower", ATOM, 300);
+        let borrow = |collateral: u128| ExecuteMsg::Borrow {
+            borrow_token: USDC.to_string(),
+            amount: Uint128::new(1_000),
+            interest_rate: Uint128::new(10),
+            collateral: vec![(ATOM.to_string(), Uint128::new(collateral))],
+            term: None,
+            allowed_lenders: None,
+            collateral_source: Some(CollateralSource::Deposits),
+            return_to_deposits: Some(true),
+        };
+        assert_error(
+            suite.execute("borrower", borrow(301), &[]),
+            "Insufficient deposit",
+        );
+        let atom_before = suite.balance("borrower", ATOM);
+        suite.execute("borrower", borrow(200), &[]).unwrap();
+        assert_eq!(suite.deposited("borrower", ATOM), 100);
+        assert_eq!(suite.market(ATOM).stats.total_deposited, Uint128::new(100));
+        suite.fill("lender", 1, 1_000);
+
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::AddCollateral {
+                    position_id: Uint128::one(),
+                    token: ATOM.to_string(),
+                    amount: Uint128::new(50),
+                    source: Some(CollateralSource::Deposits),
+                },
+                &[],
+            )
+            .unwrap();
+        assert_eq!(suite.deposited("borrower", ATOM), 50);
+
+        // Repaying credits all 250 back to deposits instead of the wallet
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_000, USDC),
+            )
+            .unwrap();
+        assert_eq!(suite.deposited("borrower", ATOM), 300);
+        assert_eq!(suite.balance("borrower", ATOM), atom_before);
+        assert_eq!(suite.market(ATOM).stats.total_deposited, Uint128::new(300));
+    }
+
+    #[test]
+    fn batch_reverts_as_a_whole() {
+        let mut suite = setup();
+        let deposit = |token: &str, amount: u128| Action {
+            msg: ExecuteMsg::Deposit {
+                token: token.to_string(),
+                amount: Uint128::new(amount),
+            },
+            funds: coins(amount, token),
+        };
+
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Batch(vec![deposit(ATOM, 50), deposit(USDC, 300)]),
+                &[coin(50, ATOM), coin(300, USDC)],
+            )
+            .unwrap();
+        assert_eq!(suite.deposited("borrower", ATOM), 50);
+        assert_eq!(suite.deposited("borrower", USDC), 300);
+
+        // The failing withdrawal undoes the deposit before it
+        let withdraw = Action {
+            msg: ExecuteMsg::Withdraw {
+                token: USDC.to_string(),
+                amount: Uint128::new(1_000),
+            },
+            funds: vec![],
+        };
+        let usdc_before = suite.balance("borrower", USDC);
+        assert_error(
+            suite.execute(
+                "borrower",
+                ExecuteMsg::Batch(vec![deposit(USDC, 300), withdraw]),
+                &coins(300, USDC),
+            ),
+            "Insufficient deposit",
+        );
+        assert_eq!(suite.deposited("borrower", USDC), 300);
+        assert_eq!(suite.balance("borrower", USDC), usdc_before);
+
+        assert_error(
+            suite.execute(
+                "borrower",
+                ExecuteMsg::Batch(vec![deposit(USDC, 300)]),
+                &coins(400, USDC),
+            ),
+            "Batch funds must match the funds sent",
+        );
+        let flash_loan = Action {
+            msg: ExecuteMsg::FlashLoan {
+                token: USDC.to_string(),
+                amount: Uint128::new(100),
+                callback: Binary::default(),
+            },
+            funds: vec![],
+        };
+        assert_error(
+            suite.execute("borrower", ExecuteMsg::Batch(vec![flash_loan]), &[]),
+            "Action not allowed in a batch",
+        );
+    }
+
+    #[test]
+    fn swap_collateral_settles_in_reply() {
+        let mut suite = setup();
+        suite.open_loan();
+        let swap = |min_receive: u128| ExecuteMsg::SwapCollateral {
+            position_id: Uint128::one(),
+            from_token: ATOM.to_string(),
+            amount: Uint128::new(50),
+            to_token: USDC.to_string(),
+            min_receive: Uint128::new(min_receive),
+        };
+        assert_error(
+            suite.execute("borrower", swap(500), &[]),
+            "No swap router configured",
+        );
+        suite.use_router();
+
+        assert_error(
+            suite.execute("lender", swap(500), &[]),
+            "Not borrower or operator",
+        );
+        // The router itself refuses to return less than the minimum
+        assert_error(
+            suite.execute("borrower", swap(501), &[]),
+            "minimum receive amount: 501",
+        );
+        suite.execute("borrower", swap(500), &[]).unwrap();
+        assert_eq!(
+            suite.position(1).collateral,
+            vec![
+                (Token::Native(ATOM.to_string()), Uint128::new(150)),
+                (Token::Native(USDC.to_string()), Uint128::new(500)),
+            ]
+        );
+
+        // A router ignoring the minimum is caught by the reply
+        suite.use_short_router();
+        assert_error(
+            suite.execute("borrower", swap(500), &[]),
+            "Swap returned 1, below the minimum 500",
+        );
+        assert_eq!(
+            suite.position(1).collateral[0],
+            (Token::Native(ATOM.to_string()), Uint128::new(150))
+        );
+    }
+
+    #[test]
+    fn leverage_and_deleverage_settle_in_replies() {
+        let mut suite = setup();
+        for (token, borrowable) in [(ATOM, false), (USDC, true)] {
+            suite
+                .execute(
+                    "admin",
+                    ExecuteMsg::UpdateMarginParams {
+                        token: token.to_string(),
+                        params: Some(MarginParams {
+                            collateral_weight: Decimal::percent(80),
+                            max_ltv: Decimal::percent(50),
+                            borrow_rate: Uint128::new(10),
+                            borrowable,
+                        }),
+                    },
+                    &[],
+                )
+                .unwrap();
+        }
+        suite.deposit("lender", USDC, 100_000);
+        suite.use_router();
+        let open = |leverage: &str, min_receive: u128| ExecuteMsg::OpenLeveraged {
+            collateral_token: ATOM.to_string(),
+            amount: Uint128::new(100),
+            debt_token: USDC.to_string(),
+            leverage: leverage.parse().unwrap(),
+            min_receive: Uint128::new(min_receive),
+        };
+        let margin = |suite: &Suite| -> MarginAccountResponse {
+            suite.query(QueryMsg::GetMarginAccount {
+                address: addr("borrower").to_string(),
+            })
+        };
+
+        assert_error(
+            suite.execute("borrower", open("1", 0), &coins(100, ATOM)),
+            "Leverage must be above 1",
+        );
+        // 3x exposure borrows 2000 USDC against 300 ATOM, beyond their weighted value
+        assert_error(
+            suite.execute("borrower", open("3", 200), &coins(100, ATOM)),
+            "Account would exceed its borrow limit",
+        );
+        suite
+            .execute("borrower", open("1.5", 50), &coins(100, ATOM))
+            .unwrap();
+        let account = margin(&suite);
+        assert_eq!(
+            account.collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(150))]
+        );
+        assert_eq!(account.debts[0].1.principal, Uint128::new(500));
+
+        // Selling 60 ATOM repays the 500 USDC owed and returns the other 100
+        let deleverage = |min_receive: u128| ExecuteMsg::Deleverage {
+            collateral_token: ATOM.to_string(),
+            amount: Uint128::new(60),
+            debt_token: USDC.to_string(),
+            min_receive: Uint128::new(min_receive),
+        };
+        assert_error(
+            suite.execute("borrower", deleverage(601), &[]),
+            "minimum receive amount: 601",
+        );
+        let usdc_before = suite.balance("borrower", USDC);
+        suite.execute("borrower", deleverage(600), &[]).unwrap();
+        let account = margin(&suite);
+        assert!(account.debts.is_empty());
+        assert_eq!(
+            account.collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(90))]
+        );
+        assert_eq!(suite.balance("borrower", USDC), usdc_before + 100);
+
+        // A router ignoring the minimum is caught by the reply
+        suite.use_short_router();
+        assert_error(
+            suite.execute("borrower", open("1.5", 50), &coins(100, ATOM)),
+            "Swap returned 1, below the minimum 50",
+        );
+    }
+
+    #[test]
+    fn flash_loan_fee_must_be_below_100_percent() {
+        let mut app = App::default();
+        let code_id = app.store_code(Box::new(ContractWrapper::new(execute, instantiate, query)));
+        let result = app.instantiate_contract(
+            code_id,
+            addr("admin"),
+            &InstantiateMsg {
+                mock_oracle: addr("oracle").to_string(),
+                liquidation_threshold: Uint128::new(150),
+                initial_tokens: vec![],
+                flash_loan_fee: Uint128::new(10_000),
+                liquidation_fee: Uint128::zero(),
+                auction: None,
+                lender_nft: None,
+                swap_router: None,
+            },
+            &[],
+            "encke",
+            None,
+        );
+        assert_error(result, "Flash loan fee must be below 10000 basis points");
+    }
+
+    #[test]
+    fn margin_bad_debt_written_off() {
+        let mut suite = setup();
+        for (token, borrowable) in [(ATOM, false), (USDC, true)] {
+            suite
+                .execute(
+                    "admin",
+                    ExecuteMsg::UpdateMarginParams {
+                        token: token.to_string(),
+                        params: Some(MarginParams {
+                            collateral_weight: Decimal::percent(80),
+                            max_ltv: Decimal::percent(50),
+                            borrow_rate: Uint128::new(10),
+                            borrowable,
+                        }),
+                    },
+                    &[],
+                )
+                .unwrap();
+        }
+        suite.deposit("lender", USDC, 100_000);
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::AccountDeposit {
+                    token: ATOM.to_string(),
+                    amount: Uint128::new(100),
+                },
+                &coins(100, ATOM),
+            )
+            .unwrap();
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::AccountBorrow {
+                    token: USDC.to_string(),
+                    amount: Uint128::new(500),
+                },
+                &[],
+            )
+            .unwrap();
+
+        // Seizing all 100 ATOM at 100 leaves debt with nothing behind it, written off
+        // against depositors
+        suite.set_price(ATOM, 100);
+        suite
+            .execute(
+                "liquidator",
+                ExecuteMsg::LiquidateAccount {
+                    borrower: addr("borrower").to_string(),
+                    debt_token: USDC.to_string(),
+                    amount: Uint128::new(500),
+                    collateral_token: ATOM.to_string(),
+                },
+                &coins(500, USDC),
+            )
+            .unwrap();
+        let account: MarginAccountResponse = suite.query(QueryMsg::GetMarginAccount {
+            address: addr("borrower").to_string(),
+        });
+        assert!(account.collateral.is_empty());
+        assert!(account.debts.is_empty());
+        let bad_debt = account.shortfalls[0].bad_debt;
+        assert!(!bad_debt.is_zero());
+        assert_eq!(account.shortfalls[0].shortfall, bad_debt);
+        assert_eq!(suite.deposited("lender", USDC), 100_000 - bad_debt.u128());
+        assert_eq!(suite.market(USDC).stats.pool_borrowed, Uint128::zero());
+    }
+
+    #[test]
+    fn migrate_converts_legacy_positions() {
+        let mut suite = setup();
+        let now = suite.app.block_info().time.seconds();
+        let legacy = |lender: Option<Addr>, filled: bool| LegacyPosition {
+            borrower: addr("borrower"),
+            lender,
+            borrow_token: Token::Native(USDC.to_string()),
+            collateral_token: Token::Native(ATOM.to_string()),
+            amount: Uint128::new(1_000),
+            interest_rate: Uint128::new(10),
+            collateral: Uint128::new(200),
+            start_time: now,
+            filled,
+        };
+        {
+            let mut storage = suite.app.contract_storage_mut(&suite.contract);
+            LEGACY_CONFIG
+                .save(
+                    storage.as_mut(),
+                    &LegacyConfig {
+                        admin: addr("admin"),
+                        liquidation_threshold: Uint128::new(150),
+                        mock_oracle: suite.oracle.clone(),
+                    },
+                )
+                .unwrap();
+            LEGACY_POSITIONS
+                .save(storage.as_mut(), 1, &legacy(Some(addr("lender")), true))
+                .unwrap();
+            LEGACY_POSITIONS
+                .save(storage.as_mut(), 2, &legacy(None, false))
+                .unwrap();
+            MARKET_STATS.clear(storage.as_mut());
+        }
+
+        suite
+            .app
+            .migrate_contract(
+                addr("admin"),
+                suite.contract.clone(),
+                &MigrateMsg {},
+                suite.code_id,
+            )
+            .unwrap();
+        let position = suite.position(1);
+        assert_eq!(
+            position.lenders,
+            vec![(addr("lender"), Uint128::new(1_000))]
+        );
+        assert_eq!(
+            position.collateral,
+            vec![(Token::Native(ATOM.to_string()), Uint128::new(200))]
+        );
+        assert!(!suite.position(2).filled);
+        let response: OpenRequestsResponse = suite.query(QueryMsg::GetOpenRequests {
+            filter: Some(OpenRequestsFilter {
+                borrow_token: Some(USDC.to_string()),
+                collateral_token: Some(ATOM.to_string()),
+                ..OpenRequestsFilter::default()
+            }),
+            sort: Some(OpenRequestsSort::Collateralization),
+            start_after: None,
+            limit: None,
+        });
+        assert_eq!(response.requests.len(), 1);
+        assert_eq!(response.requests[0].position_id, Uint128::new(2));
+
+        {
+            let storage = suite.app.contract_storage(&suite.contract);
+            let usdc = MARKET_STATS.load(storage.as_ref(), USDC).unwrap();
+            assert_eq!(usdc.total_lent, Uint128::new(1_000));
+            assert_eq!(usdc.active_positions, 1);
+            let atom = MARKET_STATS.load(storage.as_ref(), ATOM).unwrap();
+            assert_eq!(atom.total_collateral, Uint128::new(400));
+            let config = CONFIG.load(storage.as_ref()).unwrap();
+            assert_eq!(config.admin, addr("admin"));
+            assert_eq!(config.flash_loan_fee, Uint128::zero());
+        }
+
+        // The rebuilt statistics account for the legacy loan when it closes
+        suite
+            .app
+            .send_tokens(addr("borrower"), suite.contract.clone(), &coins(400, ATOM))
+            .unwrap();
+        suite
+            .execute(
+                "borrower",
+                ExecuteMsg::Repay {
+                    position_id: Uint128::one(),
+                },
+                &coins(1_000, USDC),
+            )
+            .unwrap();
+        let stats = suite.market(USDC).stats;
+        assert_eq!(stats.total_lent, Uint128::zero());
+        assert_eq!(stats.active_positions, 0);
+        assert_eq!(suite.market(ATOM).stats.total_collateral, Uint128::new(200));
+    }
 }
@@ -0,0 +1,44 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{Empty, Timestamp};
+
+/// Subset of the cw721-base execute messages used to mint lender notes
+#[cw_serde]
+pub enum Cw721ExecuteMsg {
+    Mint {
+        token_id: String,          // Unique id of the NFT
+        owner: String,             // Address receiving the NFT
+        token_uri: Option<String>, // Optional metadata URI
+        extension: Option<Empty>,  // No on-chain metadata
+    },
+}
+
+/// Subset of the cw721-base queries used to resolve note holders
+#[cw_serde]
+pub enum Cw721QueryMsg {
+    OwnerOf {
+        token_id: String,
+        include_expired: Option<bool>,
+    },
+}
+
+/// Response for OwnerOf
+#[cw_serde]
+pub struct OwnerOfResponse {
+    pub owner: String,            // Current holder of the NFT
+    pub approvals: Vec<Approval>, // Operators approved to transfer it
+}
+
+/// Approval to transfer an NFT
+#[cw_serde]
+pub struct Approval {
+    pub spender: String,
+    pub expires: Expiration,
+}
+
+/// Expiration of an approval
+#[cw_serde]
+pub enum Expiration {
+    AtHeight(u64),
+    AtTime(Timestamp),
+    Never {},
+}
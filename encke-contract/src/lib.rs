@@ -1,4 +1,5 @@
 pub mod contract;
+pub mod cw721;
 mod error;
 pub mod msg;
 pub mod state;
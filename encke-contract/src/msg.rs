@@ -1,7 +1,11 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::Uint128;
+use cosmwasm_std::{Addr, Binary, Coin, Decimal, Uint128};
 
-use crate::state::{Config, Deposit, Position, Token};
+use crate::state::{
+    AccountDebt, Auction, AuctionConfig, Config, CounterOffer, Deposit, EModeCategory,
+    ExtensionProposal, InsuranceFund, Isolation, MarginParams, MarketStats, Position,
+    PositionEvent, Shortfall, Token, TokenCaps,
+};
 
 /// Message to instantiate the contract
 #[cw_serde]
@@ -9,8 +13,19 @@ pub struct InstantiateMsg {
     pub mock_oracle: String,            // Address of the mock oracle
     pub liquidation_threshold: Uint128, // Liquidation threshold (e.g., 150 for 1.5x)
     pub initial_tokens: Vec<String>,    // Initial list of supported tokens
+    #[serde(default)]
+    pub flash_loan_fee: Uint128, // Flash loan fee in bps (e.g., 9 = 0.09%), 0 if unset
+    #[serde(default)]
+    pub liquidation_fee: Uint128, // Protocol fee on liquidation repayments in bps, 0 if unset
+    pub auction: Option<AuctionConfig>, // Liquidate through Dutch auctions when set
+    pub lender_nft: Option<String>,     // Cw721 contract minting lender notes, if tokenized
+    pub swap_router: Option<String>,    // DEX router swapping position collateral
 }
 
+/// Message to migrate the contract, converting state stored by earlier versions
+#[cw_serde]
+pub struct MigrateMsg {}
+
 /// Messages to execute contract actions
 #[cw_serde]
 pub enum ExecuteMsg {
@@ -26,8 +41,11 @@ pub enum ExecuteMsg {
         borrow_token: String,
         amount: Uint128,
         interest_rate: Uint128,
-        collateral_token: String,
-        collateral: Uint128,
+        collateral: Vec<(String, Uint128)>, // Collateral basket, one entry per token
+        term: Option<u64>, // Seconds until the loan is due once filled, None if open-ended
+        allowed_lenders: Option<Vec<String>>, // Make the request private to these lenders
+        collateral_source: Option<CollateralSource>, // Where the collateral comes from, wallet if unset
+        return_to_deposits: Option<bool>, // Credit the collateral to deposits when the position closes
     },
     Withdraw {
         token: String,
@@ -36,13 +54,187 @@ pub enum ExecuteMsg {
     FillPosition {
         position_id: Uint128,
         amount: Uint128,
-    }, // Fill a borrow position
+    }, // Fund part or all of a borrow request
+    AcceptPartialFill {
+        position_id: Uint128,
+    }, // Start a partially funded loan for the amount funded so far (borrower only)
+    CancelFill {
+        position_id: Uint128,
+    }, // Take back the sender's funding of a request that has not started yet
+    CounterOffer {
+        position_id: Uint128,
+        amount: Uint128,
+        interest_rate: Uint128,
+        term: Option<u64>,
+    }, // Offer other terms on an open request, escrowing up to the requested amount
+    WithdrawCounterOffer {
+        position_id: Uint128,
+    }, // Take back the sender's counter-offer and its escrow
+    AcceptCounterOffer {
+        position_id: Uint128,
+        lender: String,
+    }, // Start the loan on a lender's counter-offer, refunding other fills and counter-offers (borrower only)
+    Refinance {
+        position_id: Uint128,
+        offer: LendOffer,
+        signature: Binary,
+    }, // Move a loan to the lender of a signed offer at its lower rate and term (borrower only)
+    TakeOverLoan {
+        position_id: Uint128,
+        interest_rate: Uint128,
+    }, // Pay off the lenders of a loan with the sent funds and take it over at a lower rate
+    ProposeExtension {
+        position_id: Uint128,
+        maturity: u64,
+        interest_rate: Option<Uint128>,
+        capitalize_interest: bool,
+    }, // Propose or approve new terms for a term loan (lenders only)
+    AcceptExtension {
+        position_id: Uint128,
+    }, // Apply the extension approved by all lenders, paying accrued interest unless capitalized (borrower only)
+    RegisterOfferKey {
+        public_key: Binary,
+    }, // Set the compressed secp256k1 public key signing the sender's lend offers
+    CancelOffers {
+        nonces: Vec<u64>,
+    }, // Revoke signed lend offers of the sender by nonce
+    TakeSignedOffer {
+        offer: LendOffer,
+        signature: Binary,
+        collateral: Vec<(String, Uint128)>, // Collateral basket, one entry per token
+    }, // Borrow from a lend offer signed off-chain, funded from the lender's deposit
+    ApproveOperator {
+        operator: String,
+    }, // Let an address manage the collateral of the sender's positions
+    RevokeOperator {
+        operator: String,
+    }, // Remove an operator of the sender's positions
+    AddCollateral {
+        position_id: Uint128,
+        token: String,
+        amount: Uint128,
+        source: Option<CollateralSource>,
+    }, // Add collateral to a position (borrower or operator)
+    WithdrawCollateral {
+        position_id: Uint128,
+        token: String,
+        amount: Uint128,
+    }, // Return collateral to the borrower while staying under the max LTV (borrower or operator)
     Repay {
         position_id: Uint128,
     }, // Repay a position
     Liquidate {
         position_id: Uint128,
-    }, // Liquidate an undercollateralized position
+        collateral_token: String,
+    }, // Liquidate an undercollateralized position, receiving one of its collateral tokens
+    FlashLoan {
+        token: String,
+        amount: Uint128,
+        callback: Binary,
+    }, // Lend contract-held tokens to the sender and execute `callback` on it
+    RepayFlashLoan {}, // Return the flash loan in progress plus its fee
+    UpdateAuctionConfig {
+        auction: Option<AuctionConfig>,
+    }, // Switch liquidations to auctions, or back to fixed liquidation (admin only)
+    UpdateLiquidationFee {
+        fee: Uint128,
+    }, // Set the protocol's fee on liquidation repayments, in basis points (admin only)
+    StartAuction {
+        position_id: Uint128,
+    }, // Start a collateral auction for an undercollateralized position
+    Bid {
+        position_id: Uint128,
+        amount: Uint128,
+        collateral_token: String,
+    }, // Repay up to `amount` of an auctioned debt for discounted collateral of one token
+    CancelAuction {
+        position_id: Uint128,
+    }, // Stop the auction of a position that is no longer undercollateralized
+    DepositInsurance {
+        token: String,
+        amount: Uint128,
+    }, // Add funds to the insurance fund of a token
+    FundInsurance {
+        token: String,
+        amount: Uint128,
+    }, // Move protocol reserves into the insurance fund (admin only)
+    UpdateTokenCaps {
+        token: String,
+        caps: TokenCaps,
+    }, // Set supply, borrow and collateral caps of a token (admin only)
+    UpdateIsolation {
+        token: String,
+        isolation: Option<Isolation>,
+    }, // Isolate a collateral token, or lift its isolation (admin only)
+    UpdateEModeCategory {
+        id: u8,
+        category: Option<EModeCategory>,
+    }, // Create, replace or remove an e-mode category (admin only)
+    UpdateCollateralWeight {
+        token: String,
+        weight: Option<Decimal>,
+    }, // Set the risk weight of a token as position collateral, None resets it to 1 (admin only)
+    UpdateMarginParams {
+        token: String,
+        params: Option<MarginParams>,
+    }, // Set or remove cross-margin parameters of a token (admin only)
+    UpdateLenderNft {
+        address: Option<String>,
+    }, // Set the cw721 contract minting lender notes for new loans, None stops tokenizing (admin only)
+    UpdateSwapRouter {
+        address: Option<String>,
+    }, // Set the DEX router used by SwapCollateral, None disables swaps (admin only)
+    SwapCollateral {
+        position_id: Uint128,
+        from_token: String,
+        amount: Uint128,
+        to_token: String,
+        min_receive: Uint128,
+    }, // Swap part of a position's collateral into another token through the router (borrower or operator)
+    AccountDeposit {
+        token: String,
+        amount: Uint128,
+    }, // Add collateral to the sender's cross-margin account
+    AccountWithdraw {
+        token: String,
+        amount: Uint128,
+    }, // Take collateral out of the sender's account if it stays healthy
+    AccountBorrow {
+        token: String,
+        amount: Uint128,
+    }, // Borrow deposited liquidity against the sender's account
+    AccountRepay {
+        token: String,
+        amount: Uint128,
+        borrower: Option<String>,
+    }, // Repay account debt, interest first (defaults to the sender's account)
+    LiquidateAccount {
+        borrower: String,
+        debt_token: String,
+        amount: Uint128,
+        collateral_token: String,
+    }, // Repay debt of an unhealthy account for discounted collateral
+    OpenLeveraged {
+        collateral_token: String,
+        amount: Uint128,
+        debt_token: String,
+        leverage: Decimal,
+        min_receive: Uint128,
+    }, // Post `amount` to the sender's account and flash-borrow `debt_token`, swapped into more collateral, up to `leverage` times the exposure
+    Deleverage {
+        collateral_token: String,
+        amount: Uint128,
+        debt_token: String,
+        min_receive: Uint128,
+    }, // Sell account collateral through the router to repay `debt_token` debt, sending back any excess
+    Batch(Vec<Action>), // Run several actions in order, atomically, splitting the attached native funds
+}
+
+/// One step of a Batch
+#[cw_serde]
+pub struct Action {
+    pub msg: ExecuteMsg,  // Message to execute, as the batch sender
+    pub funds: Vec<Coin>, // Native funds passed to this step, out of those sent with the batch
 }
 
 /// Query messages with responses
@@ -62,6 +254,122 @@ pub enum QueryMsg {
     }, // Get paginated positions
     #[returns(ConfigResponse)]
     GetConfig {}, // Get config
+    #[returns(PositionHealthResponse)]
+    GetPositionHealth { position_id: Uint128 }, // Get health metrics of a position
+    #[returns(AccountHealthResponse)]
+    GetAccountHealth { address: String }, // Get aggregate health of a borrower
+    #[returns(LiquidatablePositionsResponse)]
+    GetLiquidatablePositions {
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    }, // Get liquidatable positions among the next `limit` positions after `start_after`
+    #[returns(SimulateRepayResponse)]
+    SimulateRepay {
+        position_id: Uint128,
+        at_time: Option<u64>,
+    }, // Preview a repayment at a given time (defaults to now)
+    #[returns(SimulateLiquidationResponse)]
+    SimulateLiquidation {
+        position_id: Uint128,
+        collateral_token: String,
+    }, // Preview a liquidation now
+    #[returns(SimulateBorrowResponse)]
+    SimulateBorrow {
+        borrow_token: String,
+        amount: Uint128,
+        interest_rate: Uint128,
+        collateral: Vec<(String, Uint128)>,
+    }, // Preview a borrow request as if filled now
+    #[returns(MarketStatsResponse)]
+    GetMarketStats { token: String }, // Get aggregate statistics of a token
+    #[returns(ProtocolStatsResponse)]
+    GetProtocolStats {}, // Get aggregate statistics of all tokens
+    #[returns(OpenRequestsResponse)]
+    GetOpenRequests {
+        filter: Option<OpenRequestsFilter>,
+        sort: Option<OpenRequestsSort>,
+        start_after: Option<OpenRequestsCursor>,
+        limit: Option<u32>,
+    }, // Get matching unfilled borrow requests among the next `limit` in the sort order
+    #[returns(AuctionResponse)]
+    GetAuction { position_id: Uint128 }, // Get a running auction
+    #[returns(AuctionsResponse)]
+    GetAuctions {
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    }, // Get paginated running auctions
+    #[returns(InsuranceFundsResponse)]
+    GetInsuranceFunds {}, // Get insurance and reserve balances of supported tokens
+    #[returns(ShortfallsResponse)]
+    GetShortfalls {
+        start_after: Option<Uint128>,
+        limit: Option<u32>,
+    }, // Get paginated history of liquidation shortfalls
+    #[returns(IsolationResponse)]
+    GetIsolation { token: String }, // Get isolation settings and debt ceiling usage of a collateral token
+    #[returns(EModeCategoriesResponse)]
+    GetEModeCategories {}, // Get all e-mode categories
+    #[returns(MarginAccountResponse)]
+    GetMarginAccount { address: String }, // Get collateral, debts and health of a cross-margin account
+    #[returns(MarginParamsResponse)]
+    GetMarginParams {}, // Get cross-margin parameters of all enabled tokens
+    #[returns(LenderNotesResponse)]
+    GetLenderNotes { position_id: Uint128 }, // Get the current holders of a position's lender side
+    #[returns(ExtensionProposalResponse)]
+    GetExtensionProposal { position_id: Uint128 }, // Get the pending extension of a loan
+    #[returns(PositionHistoryResponse)]
+    GetPositionHistory { position_id: Uint128 }, // Get the term changes of a loan
+    #[returns(OperatorsResponse)]
+    GetOperators { borrower: String }, // Get the operators managing a borrower's positions
+    #[returns(CounterOffersResponse)]
+    GetCounterOffers { position_id: Uint128 }, // Get the counter-offers on an open request
+    #[returns(OfferKeyResponse)]
+    GetOfferKey { lender: String }, // Get the public key signing a lender's offers
+    #[returns(OfferNonceResponse)]
+    GetOfferNonce { lender: String, nonce: u64 }, // Get whether a signed offer was taken or cancelled
+}
+
+/// Lend offer signed off-chain by the lender's registered key. The signature covers the
+/// SHA-256 hash of the offer's JSON serialization.
+#[cw_serde]
+pub struct LendOffer {
+    pub lender: String,         // Lender whose deposit funds the loan
+    pub token: String,          // Token lent
+    pub amount: Uint128,        // Principal lent
+    pub interest_rate: Uint128, // Annual interest rate
+    pub term: Option<u64>,      // Seconds until the loan is due, None if open-ended
+    pub max_ltv: Decimal,       // Highest loan-to-value the lender accepts
+    pub nonce: u64,             // Lender-chosen id, each nonce can be taken once
+    pub expiry: u64,            // Timestamp after which the offer cannot be taken
+    pub chain_id: String,       // Chain the offer is valid on
+    pub contract: String,       // Contract the offer is valid on
+}
+
+/// Where collateral comes from when it enters a position
+#[cw_serde]
+pub enum CollateralSource {
+    Wallet,   // Transferred with the message (native funds or cw20 allowance)
+    Deposits, // Moved from the sender's deposit balance
+}
+
+/// Filters for GetOpenRequests, all optional
+#[cw_serde]
+#[derive(Default)]
+pub struct OpenRequestsFilter {
+    pub borrow_token: Option<String>,
+    pub collateral_token: Option<String>,
+    pub min_rate: Option<Uint128>,
+    pub max_ltv: Option<Decimal>,
+    pub min_amount: Option<Uint128>,
+    pub max_amount: Option<Uint128>,
+}
+
+/// Sort orders for GetOpenRequests
+#[cw_serde]
+pub enum OpenRequestsSort {
+    RateDesc,          // Highest interest rate first
+    Newest,            // Most recent request first (default)
+    Collateralization, // Most collateral value per unit of requested value first, needs both token filters
 }
 
 /// Response for GetTokenConfigs
@@ -75,6 +383,8 @@ pub struct TokenConfigsResponse {
 pub struct TokenConfig {
     pub token: Token,
     pub is_supported: bool,
+    pub isolated: bool,             // Whether the token is isolated as collateral
+    pub collateral_weight: Decimal, // Risk weight of the token as position collateral
 }
 
 /// Response for GetUserInfo
@@ -107,3 +417,291 @@ pub struct PositionsResponse {
 pub struct ConfigResponse {
     pub config: Config,
 }
+
+/// Health metrics of a position, valued in the oracle quote currency
+#[cw_serde]
+pub struct PositionHealth {
+    pub collateral_value: Uint128, // Sum of collateral amounts times price and risk weight
+    pub collateral_market_value: Uint128, // Sum of collateral amounts times price
+    pub debt_value: Uint128,       // Total debt times borrow token price
+    pub total_debt: Uint128,       // Principal plus accrued interest, in borrow token
+    pub interest: Uint128,         // Interest accrued so far, in borrow token
+    pub ltv: Decimal,              // Debt value over collateral value
+    pub health_factor: Option<Decimal>, // Below 1 means liquidatable (None without debt)
+    pub liquidation_price: Option<Decimal>, // Collateral price at which liquidation opens (single-token baskets only)
+    pub liquidatable: bool,                 // Whether Liquidate would succeed now
+    pub collateral_prices: Vec<(Token, Uint128)>, // Oracle price of each collateral token
+    pub borrow_price: Uint128,              // Oracle price of the borrow token
+    pub e_mode_category: Option<u8>,        // E-mode category shared by both tokens, if any
+    pub max_ltv: Decimal,                   // Max LTV when opening the position
+    pub liquidation_threshold: Uint128,     // Threshold applied to the position
+    pub liquidation_bonus: Uint128, // Liquidator premium on repaid debt value in basis points
+}
+
+/// Response for GetPositionHealth
+#[cw_serde]
+pub struct PositionHealthResponse {
+    pub health: PositionHealth,
+}
+
+/// Response for GetAccountHealth
+#[cw_serde]
+pub struct AccountHealthResponse {
+    pub collateral_value: Uint128,
+    pub debt_value: Uint128,
+    pub ltv: Decimal,
+    pub health_factor: Option<Decimal>,
+    pub liquidatable: bool, // Whether any of the positions is liquidatable
+    pub positions: Vec<(Uint128, PositionHealth)>,
+}
+
+/// How an undercollateralized position is liquidated
+#[cw_serde]
+pub enum LiquidationMode {
+    FixedPrice, // Liquidate repays the debt at the fixed bonus quoted
+    Auction,    // StartAuction opens a Dutch auction for bidders
+}
+
+/// A position that can be liquidated at the current block time
+#[cw_serde]
+pub struct LiquidatablePosition {
+    pub position_id: Uint128,
+    pub position: Position,
+    pub mode: LiquidationMode,      // Whether to Liquidate or StartAuction
+    pub collateral_token: Token,    // Most valuable collateral token, the one priced below
+    pub repay_amount: Uint128,      // Debt the liquidator must send, in borrow token
+    pub collateral_payout: Uint128, // Collateral sent to the liquidator
+    pub health: PositionHealth,
+}
+
+/// Response for GetLiquidatablePositions
+#[cw_serde]
+pub struct LiquidatablePositionsResponse {
+    pub positions: Vec<LiquidatablePosition>,
+    pub next_start_after: Option<Uint128>, // Cursor for the next page, None once every position was scanned
+}
+
+/// Response for SimulateRepay
+#[cw_serde]
+pub struct SimulateRepayResponse {
+    pub borrow_token: Token,
+    pub principal: Uint128,
+    pub interest: Uint128,
+    pub total_repayment: Uint128, // Funds Repay expects, in borrow token
+    pub collateral_released: Vec<(Token, Uint128)>, // Collateral returned to the borrower
+}
+
+/// Response for SimulateLiquidation
+#[cw_serde]
+pub struct SimulateLiquidationResponse {
+    pub borrow_token: Token,
+    pub repay_amount: Uint128, // Funds Liquidate expects, in borrow token
+    pub interest: Uint128,
+    pub lender_share: Uint128,   // Part of the repayment sent to the lender
+    pub protocol_share: Uint128, // Part of the repayment kept by the contract
+    pub collateral_token: Token,
+    pub collateral_seized: Uint128, // Collateral sent to the liquidator
+    pub collateral_returned: Uint128, // Rest of the collateral token, sent back to the borrower
+    pub shortfall: Uint128,         // Debt the seized collateral does not cover
+    pub insurance_cover: Uint128,   // Part of the shortfall the insurance fund would pay
+    pub health: PositionHealth,     // Health before liquidation
+}
+
+/// Response for SimulateBorrow
+#[cw_serde]
+pub struct SimulateBorrowResponse {
+    pub collateral_required: Vec<(Token, Uint128)>, // Collateral Borrow expects
+    pub borrow_token: Token,
+    pub amount_received: Uint128, // Amount sent to the borrower once filled
+    pub health: PositionHealth,   // Health right after the fill
+}
+
+/// Response for GetMarketStats, values in the oracle quote currency
+#[cw_serde]
+pub struct MarketStatsResponse {
+    pub token: Token,
+    pub stats: MarketStats,
+    pub price: Uint128,
+    pub deposited_value: Uint128,
+    pub lent_value: Uint128,
+    pub borrowed_value: Uint128,
+    pub collateral_value: Uint128,
+    pub utilization: Decimal, // Lent out over deposited plus lent out
+    pub caps: TokenCaps,
+    pub supply_headroom: Option<Uint128>, // Room left under the supply cap (None if uncapped)
+    pub borrow_headroom: Option<Uint128>, // Room left under the borrow cap (None if uncapped)
+    pub collateral_headroom: Option<Uint128>, // Room left under the collateral cap (None if uncapped)
+}
+
+/// Response for GetProtocolStats, values in the oracle quote currency
+#[cw_serde]
+pub struct ProtocolStatsResponse {
+    pub total_deposited_value: Uint128,
+    pub total_lent_value: Uint128,
+    pub total_borrowed_value: Uint128,
+    pub total_collateral_value: Uint128,
+    pub total_value_locked: Uint128, // Deposits plus escrowed collateral
+    pub active_positions: u64,
+    pub markets: Vec<MarketStatsResponse>,
+}
+
+/// An unfilled borrow request
+#[cw_serde]
+pub struct OpenRequest {
+    pub position_id: Uint128,
+    pub position: Position,
+    pub ltv: Decimal, // Requested amount value over collateral value
+}
+
+/// Where a GetOpenRequests page ended: the last request scanned and its sort keys, so the
+/// next page resumes even after that request left the order book
+#[cw_serde]
+pub struct OpenRequestsCursor {
+    pub position_id: Uint128,
+    pub interest_rate: Uint128,
+    pub collateralization: Uint128,
+}
+
+/// Response for GetOpenRequests
+#[cw_serde]
+pub struct OpenRequestsResponse {
+    pub requests: Vec<OpenRequest>,
+    pub next_start_after: Option<OpenRequestsCursor>, // Cursor for the next page, None once the order book was scanned
+}
+
+/// A running auction with its current discount
+#[cw_serde]
+pub struct AuctionInfo {
+    pub position_id: Uint128,
+    pub auction: Auction,
+    pub position: Position,
+    pub discount: Uint128, // Current collateral discount in basis points
+}
+
+/// Response for GetAuction
+#[cw_serde]
+pub struct AuctionResponse {
+    pub auction: AuctionInfo,
+}
+
+/// Response for GetAuctions
+#[cw_serde]
+pub struct AuctionsResponse {
+    pub auctions: Vec<AuctionInfo>,
+}
+
+/// Insurance and reserve balances of a token
+#[cw_serde]
+pub struct InsuranceFundInfo {
+    pub token: Token,
+    pub fund: InsuranceFund,
+    pub reserves: Uint128,
+}
+
+/// Response for GetInsuranceFunds
+#[cw_serde]
+pub struct InsuranceFundsResponse {
+    pub funds: Vec<InsuranceFundInfo>,
+}
+
+/// Response for GetShortfalls
+#[cw_serde]
+pub struct ShortfallsResponse {
+    pub shortfalls: Vec<(Uint128, Shortfall)>,
+}
+
+/// Response for GetIsolation
+#[cw_serde]
+pub struct IsolationResponse {
+    pub token: Token,
+    pub isolation: Option<Isolation>,
+    pub debt: Vec<(Token, Uint128)>, // Principal backed by the collateral, per borrow token
+    pub debt_value: Uint128,         // Value of that principal in quote currency
+    pub ceiling_used: Decimal,       // Debt value over the debt ceiling
+    pub headroom: Uint128,           // Value left under the debt ceiling
+}
+
+/// Response for GetEModeCategories
+#[cw_serde]
+pub struct EModeCategoriesResponse {
+    pub categories: Vec<(u8, EModeCategory)>,
+}
+
+/// Health of a cross-margin account, values in the oracle quote currency
+#[cw_serde]
+pub struct MarginAccountHealth {
+    pub collateral_value: Uint128, // Collateral valued at oracle prices
+    pub weighted_collateral_value: Uint128, // Collateral value times each token's weight
+    pub borrow_limit_value: Uint128, // Collateral value times each token's max LTV
+    pub debt_value: Uint128,       // Principal plus interest valued at oracle prices
+    pub health_factor: Option<Decimal>, // Below 1 means liquidatable (None without debt)
+    pub borrow_capacity: Uint128,  // Further debt value the account may take on within its limit
+    pub liquidatable: bool,
+}
+
+/// Response for GetMarginAccount
+#[cw_serde]
+pub struct MarginAccountResponse {
+    pub collateral: Vec<(Token, Uint128)>,
+    pub debts: Vec<(Token, AccountDebt)>, // Interest accrued up to the current block
+    pub health: MarginAccountHealth,
+    pub shortfalls: Vec<Shortfall>, // Debt written off after the account ran out of collateral
+}
+
+/// Response for GetMarginParams
+#[cw_serde]
+pub struct MarginParamsResponse {
+    pub params: Vec<(Token, MarginParams)>,
+}
+
+/// Lender side of a position, with the address settlement pays
+#[cw_serde]
+pub struct LenderNote {
+    pub token_id: Option<String>, // Cw721 token id, None if the position is not tokenized
+    pub holder: Addr,             // Current holder of the note
+    pub share: Uint128,           // Principal the note represents
+}
+
+/// Response for GetLenderNotes
+#[cw_serde]
+pub struct LenderNotesResponse {
+    pub lender_nft: Option<Addr>,
+    pub notes: Vec<LenderNote>,
+}
+
+/// Response for GetExtensionProposal
+#[cw_serde]
+pub struct ExtensionProposalResponse {
+    pub proposal: Option<ExtensionProposal>,
+}
+
+/// Response for GetPositionHistory
+#[cw_serde]
+pub struct PositionHistoryResponse {
+    pub history: Vec<PositionEvent>,
+}
+
+/// Response for GetOfferKey
+#[cw_serde]
+pub struct OfferKeyResponse {
+    pub public_key: Option<Binary>,
+}
+
+/// Response for GetOfferNonce
+#[cw_serde]
+pub struct OfferNonceResponse {
+    pub used: bool,
+    pub cancelled: bool,
+}
+
+/// Response for GetCounterOffers
+#[cw_serde]
+pub struct CounterOffersResponse {
+    pub offers: Vec<(Addr, CounterOffer)>,
+}
+
+/// Response for GetOperators
+#[cw_serde]
+pub struct OperatorsResponse {
+    pub operators: Vec<Addr>,
+}
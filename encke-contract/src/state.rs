@@ -1,4 +1,4 @@
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Binary, Decimal, Empty, Uint128};
 use cw_storage_plus::{Item, Map};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -9,6 +9,19 @@ pub struct Config {
     pub admin: Addr,                    // Address of the contract admin
     pub liquidation_threshold: Uint128, // Threshold for liquidation (e.g., 150 for 1.5x)
     pub mock_oracle: Addr,              // Address of the mock oracle contract
+    pub flash_loan_fee: Uint128,        // Flash loan fee in basis points (e.g., 9 = 0.09%)
+    pub liquidation_fee: Uint128,       // Protocol fee on liquidation repayments in basis points
+    pub auction: Option<AuctionConfig>, // Liquidate through Dutch auctions when set
+    pub lender_nft: Option<Addr>,       // Cw721 contract minting lender notes, if tokenized
+    pub swap_router: Option<Addr>,      // DEX router swapping position collateral
+}
+
+/// Parameters of Dutch-auction liquidations
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AuctionConfig {
+    pub start_discount: Uint128, // Collateral discount when the auction starts, in basis points
+    pub max_discount: Uint128,   // Discount reached after `duration`, in basis points
+    pub duration: u64,           // Seconds for the discount to grow from start to max
 }
 
 /// Represents a token type: native (e.g., "untrn") or CW20
@@ -18,18 +31,46 @@ pub enum Token {
     Cw20(Addr),     // CW20 token contract address
 }
 
-/// A lending position between a borrower and lender
+/// A lending position between a borrower and one or more lenders
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Position {
-    pub borrower: Addr,          // Address of the borrower
-    pub lender: Option<Addr>,    // Address of the lender (None if unfilled)
-    pub borrow_token: Token,     // Token being borrowed
-    pub collateral_token: Token, // Token used as collateral
-    pub amount: Uint128,         // Amount borrowed
-    pub interest_rate: Uint128,  // Annual interest rate in basis points (e.g., 500 = 5%)
-    pub collateral: Uint128,     // Amount of collateral
-    pub start_time: u64,         // Timestamp when position was filled
-    pub filled: bool,            // Whether the position has been filled by a lender
+    pub borrower: Addr,                      // Address of the borrower
+    pub lenders: Vec<(Addr, Uint128)>,       // Lenders and the principal each funded
+    pub borrow_token: Token,                 // Token being borrowed
+    pub amount: Uint128,                     // Amount borrowed (requested until filled)
+    pub interest_rate: Uint128,              // Annual interest rate in percent (e.g., 5 = 5%)
+    pub collateral: Vec<(Token, Uint128)>,   // Collateral basket, one entry per token
+    pub start_time: u64,                     // Timestamp when position was filled
+    pub filled: bool,                        // Whether the loan has started
+    pub lender_nft: Option<Addr>, // Cw721 contract holding the lender notes, if tokenized
+    pub refinance_count: u32,     // Times the loan moved to a new lender
+    pub term: Option<u64>, // Seconds from the start until the loan is due, None if open-ended
+    pub maturity: Option<u64>, // Timestamp when the loan is due, set when it starts
+    pub allowed_lenders: Option<Vec<Addr>>, // Only these may fund a private request, None if public
+    pub return_to_deposits: bool, // Credit the collateral to the borrower's deposits on close
+    pub e_mode: Option<(u8, EModeCategory)>, // E-mode category and parameters when the loan started
+}
+
+/// Configuration as stored before the fee, auction and router settings, read by migrate
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LegacyConfig {
+    pub admin: Addr,
+    pub liquidation_threshold: Uint128,
+    pub mock_oracle: Addr,
+}
+
+/// Position as stored before collateral baskets and multiple lenders, read by migrate
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LegacyPosition {
+    pub borrower: Addr,
+    pub lender: Option<Addr>,
+    pub borrow_token: Token,
+    pub collateral_token: Token,
+    pub amount: Uint128,
+    pub interest_rate: Uint128,
+    pub collateral: Uint128,
+    pub start_time: u64,
+    pub filled: bool,
 }
 
 /// A user's deposit in the contract
@@ -39,9 +80,210 @@ pub struct Deposit {
     pub amount: Uint128, // Amount deposited
 }
 
+/// Aggregate statistics of a token across all users and positions. Lenders' principal
+/// counts towards supply while borrow caps apply to all borrowing, so `total_borrowed` is
+/// `total_lent` plus `pool_borrowed`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct MarketStats {
+    pub total_deposited: Uint128,  // Deposits held for users
+    pub total_lent: Uint128,       // Principal lenders supplied to positions
+    pub total_borrowed: Uint128,   // Principal owed by positions and margin accounts
+    pub total_collateral: Uint128, // Collateral escrowed by positions
+    pub active_positions: u64,     // Number of filled positions borrowing the token
+    pub pool_borrowed: Uint128,    // Principal margin accounts drew from deposits
+}
+
+/// Limits on how much of a token the market may hold, None meaning uncapped
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct TokenCaps {
+    pub supply_cap: Option<Uint128>,     // Max deposited plus lent out
+    pub borrow_cap: Option<Uint128>,     // Max outstanding principal
+    pub collateral_cap: Option<Uint128>, // Max escrowed as collateral
+}
+
+/// Restrictions on positions backed by an isolated collateral token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Isolation {
+    pub borrow_tokens: Vec<String>, // Tokens that may be borrowed against the collateral
+    pub debt_ceiling: Uint128,      // Max value of debt backed by the collateral, in quote currency
+}
+
+/// Efficiency mode category grouping correlated tokens with their own risk parameters
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct EModeCategory {
+    pub tokens: Vec<String>,            // Tokens in the category
+    pub max_ltv: Decimal,               // Max LTV when opening a position
+    pub liquidation_threshold: Uint128, // Replaces Config.liquidation_threshold (e.g., 105 for 1.05x)
+    pub liquidation_bonus: Uint128,     // Liquidator premium on repaid debt value in basis points
+}
+
+/// Parameters of a token in cross-margin accounts, unset tokens cannot be used there
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MarginParams {
+    pub collateral_weight: Decimal, // Share of collateral value counted towards borrowing (0 disables)
+    pub borrow_rate: Uint128,       // Annual interest rate in percent charged on account debt
+    pub borrowable: bool,           // Whether accounts may borrow the token from deposits
+    #[serde(default)]
+    pub max_ltv: Decimal, // Share of collateral value that may be borrowed, below the liquidation line
+}
+
+/// Debt of a cross-margin account in one token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct AccountDebt {
+    pub principal: Uint128, // Amount drawn from deposits and not yet repaid
+    pub interest: Uint128,  // Interest accrued up to last_accrual
+    pub last_accrual: u64,  // Block time interest was last accrued
+}
+
+/// A flash loan in progress, cleared once its callback has returned
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FlashLoan {
+    pub receiver: Addr,  // Contract that received the loan and callback
+    pub token: Token,    // Token lent
+    pub amount: Uint128, // Amount lent
+    pub fee: Uint128,    // Fee owed on top of the amount
+    pub repaid: bool,    // Whether RepayFlashLoan has been called
+}
+
+/// A collateral swap in progress, checked in the reply of the router call
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingSwap {
+    pub position_id: u128,       // Position whose collateral is swapped
+    pub from_token: Token,       // Collateral token sold
+    pub amount: Uint128,         // Amount of collateral sold
+    pub to_token: Token,         // Collateral token bought
+    pub min_receive: Uint128,    // Least amount of `to_token` accepted
+    pub balance_before: Uint128, // Contract balance of `to_token` before the swap
+}
+
+/// A leveraged margin position being opened, checked in the reply of the router call
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingLeverage {
+    pub user: Addr,              // Account receiving the collateral and the debt
+    pub collateral_token: Token, // Token bought and posted as collateral
+    pub deposit: Uint128,        // Collateral brought by the user
+    pub debt_token: Token,       // Token flash-borrowed from the deposit pool and sold
+    pub borrowed: Uint128,       // Amount flash-borrowed, becoming account principal
+    pub fee: Uint128,            // Flash loan fee, becoming account interest
+    pub min_receive: Uint128,    // Least amount of collateral accepted from the swap
+    pub balance_before: Uint128, // Contract balance of the collateral token before the swap
+}
+
+/// A margin position being unwound, checked in the reply of the router call
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingDeleverage {
+    pub user: Addr,              // Account whose collateral is sold
+    pub collateral_token: Token, // Collateral token sold
+    pub amount: Uint128,         // Amount of collateral sold
+    pub debt_token: Token,       // Token bought to repay the account debt
+    pub min_receive: Uint128,    // Least amount of `debt_token` accepted
+    pub balance_before: Uint128, // Contract balance of `debt_token` before the swap
+}
+
+/// A Dutch auction selling the collateral of an undercollateralized position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Auction {
+    pub start_time: u64,         // Timestamp when the auction started
+    pub debt_remaining: Uint128, // Debt still to be repaid by bidders, in borrow token
+    pub config: AuctionConfig,   // Auction parameters when it started, kept for its whole run
+}
+
+/// Insurance fund of a token covering lender shortfalls on liquidation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct InsuranceFund {
+    pub balance: Uint128,        // Funds available to cover shortfalls
+    pub total_covered: Uint128,  // Shortfalls paid to lenders so far
+    pub total_bad_debt: Uint128, // Shortfalls left uncovered so far
+}
+
+/// A liquidation that did not recover the full debt
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Shortfall {
+    pub token: Token,       // Borrow token of the position
+    pub shortfall: Uint128, // Debt not repaid by the liquidation
+    pub covered: Uint128,   // Part of the shortfall paid by the insurance fund
+    pub bad_debt: Uint128,  // Part of the shortfall lost by the lender
+    pub timestamp: u64,     // Block time of the liquidation
+}
+
+/// Alternative terms a lender offers on an open request, with the amount escrowed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CounterOffer {
+    pub token: Token,           // Token escrowed, refundable after the request is gone
+    pub amount: Uint128,        // Principal offered and escrowed
+    pub interest_rate: Uint128, // Annual interest rate offered
+    pub term: Option<u64>,      // Seconds until the loan is due, None if open-ended
+}
+
+/// Terms of a loan extension proposed by its lenders, awaiting the borrower
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExtensionProposal {
+    pub maturity: u64,                  // New maturity
+    pub interest_rate: Option<Uint128>, // New rate, None to keep the current one
+    pub capitalize_interest: bool, // Add accrued interest to the principal instead of paying it
+    pub approvals: Vec<Addr>,      // Lenders (or note holders) agreeing to these terms
+}
+
+/// Change to the terms of a running loan
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum PositionEvent {
+    Extended {
+        timestamp: u64,         // Block time of the extension
+        previous_maturity: u64, // Maturity before the extension
+        maturity: u64,          // Maturity after the extension
+        interest_rate: Uint128, // Rate from the extension on
+        interest: Uint128,      // Interest accrued up to the extension
+        capitalized: bool,      // Whether that interest was added to the principal
+    },
+}
+
+/// Sort keys an open request is indexed under, kept so its index entries can be found
+/// again whatever happens to the position
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OpenRequestKeys {
+    pub interest_rate: Uint128,     // Requested annual rate
+    pub collateralization: Uint128, // Collateral value over requested value at oracle prices, as Decimal atomics
+}
+
 /// Storage items
 pub const CONFIG: Item<Config> = Item::new("config"); // Contract configuration
 pub const SUPPORTED_TOKENS: Map<&str, bool> = Map::new("supported_tokens"); // Supported tokens map
 pub const POSITIONS: Map<u128, Position> = Map::new("positions"); // Positions map
 pub const DEPOSITS: Map<(&Addr, &str), Uint128> = Map::new("deposits"); // User deposits map
 pub const POSITION_COUNTER: Item<Uint128> = Item::new("position_counter"); // Counter for position IDs
+pub const LEGACY_CONFIG: Item<LegacyConfig> = Item::new("config"); // Pre-upgrade view of CONFIG
+pub const LEGACY_POSITIONS: Map<u128, LegacyPosition> = Map::new("positions"); // Pre-upgrade view of POSITIONS
+pub const MARKET_STATS: Map<&str, MarketStats> = Map::new("market_stats"); // Per-token aggregates
+pub const TOKEN_CAPS: Map<&str, TokenCaps> = Map::new("token_caps"); // Per-token market limits
+pub const E_MODE_CATEGORIES: Map<u8, EModeCategory> = Map::new("e_mode_categories"); // Categories by id
+pub const TOKEN_E_MODE: Map<&str, u8> = Map::new("token_e_mode"); // Category id of each grouped token
+pub const COLLATERAL_WEIGHTS: Map<&str, Decimal> = Map::new("collateral_weights"); // Risk weight of position collateral (1 if unset)
+pub const MARGIN_PARAMS: Map<&str, MarginParams> = Map::new("margin_params"); // Cross-margin settings per token
+pub const ACCOUNT_COLLATERAL: Map<(&Addr, &str), Uint128> = Map::new("account_collateral"); // Margin collateral by (user, token)
+pub const ACCOUNT_DEBTS: Map<(&Addr, &str), AccountDebt> = Map::new("account_debts"); // Margin debt by (user, token)
+pub const ISOLATED_TOKENS: Map<&str, Isolation> = Map::new("isolated_tokens"); // Isolated collateral
+pub const ISOLATED_DEBT: Map<(&str, &str), Uint128> = Map::new("isolated_debt"); // Principal of single-collateral loans by (collateral, borrow token)
+pub const RESERVES: Map<&str, Uint128> = Map::new("reserves"); // Protocol reserves per token
+pub const DEPOSIT_INDEX: Map<&str, Decimal> = Map::new("deposit_index"); // Growth of deposits per token (1 if unset)
+pub const DEPOSIT_CHECKPOINTS: Map<(&Addr, &str), Decimal> = Map::new("deposit_checkpoints"); // Deposit index at each deposit's last update (1 if unset)
+pub const FLASH_LOAN: Item<FlashLoan> = Item::new("flash_loan"); // Flash loan in progress
+pub const PENDING_SWAP: Item<PendingSwap> = Item::new("pending_swap"); // Collateral swap in progress
+pub const PENDING_LEVERAGE: Item<PendingLeverage> = Item::new("pending_leverage"); // Leveraged open in progress
+pub const PENDING_DELEVERAGE: Item<PendingDeleverage> = Item::new("pending_deleverage"); // Unwind in progress
+pub const AUCTIONS: Map<u128, Auction> = Map::new("auctions"); // Running auctions by position id
+pub const INSURANCE_FUNDS: Map<&str, InsuranceFund> = Map::new("insurance_funds"); // Per-token insurance
+pub const SHORTFALLS: Map<u128, Shortfall> = Map::new("shortfalls"); // Shortfalls by position id
+pub const ACCOUNT_SHORTFALLS: Map<(&Addr, &str), Shortfall> = Map::new("account_shortfalls"); // Written off margin debt by (user, token)
+pub const EXTENSION_PROPOSALS: Map<u128, ExtensionProposal> = Map::new("extension_proposals"); // Pending extensions by position id
+pub const POSITION_HISTORY: Map<u128, Vec<PositionEvent>> = Map::new("position_history"); // Term changes by position id
+pub const OPERATORS: Map<(&Addr, &Addr), Empty> = Map::new("operators"); // Addresses managing a borrower's positions, by (borrower, operator)
+pub const COUNTER_OFFERS: Map<(u128, &Addr), CounterOffer> = Map::new("counter_offers"); // Counter-offers by (position id, lender)
+pub const OFFER_KEYS: Map<&Addr, Binary> = Map::new("offer_keys"); // Secp256k1 public key signing each lender's offers
+pub const USED_NONCES: Map<(&Addr, u64), Empty> = Map::new("used_nonces"); // Signed offers already taken by (lender, nonce)
+pub const CANCELLED_NONCES: Map<(&Addr, u64), Empty> = Map::new("cancelled_nonces"); // Signed offers revoked by (lender, nonce)
+
+/// Indexes over open (unfilled) borrow requests
+pub const OPEN_REQUESTS: Map<u128, OpenRequestKeys> = Map::new("open_requests"); // Sort keys by position id
+pub const OPEN_REQUESTS_BY_RATE: Map<(u128, u128), Empty> = Map::new("open_requests_by_rate"); // (rate, id)
+pub const OPEN_REQUESTS_BY_COLLATERALIZATION: Map<((&str, &str), u128, u128), Empty> =
+    Map::new("open_requests_by_collateralization"); // ((borrow, collateral), collateralization, id)
@@ -0,0 +1,418 @@
+#[cfg(not(feature = "library"))]
+use cosmwasm_std::entry_point;
+use cosmwasm_std::{
+    from_json, to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdError,
+    StdResult, Uint128,
+};
+use cw2::set_contract_version;
+use execute::{execute_set_rate, execute_swap_operations};
+use query::simulate_swap_operations;
+
+use crate::msg::{AssetInfo, Cw20HookMsg, ExecuteMsg, InstantiateMsg, QueryMsg, SwapOperation};
+use crate::state::{ADMIN, RATES};
+
+// version info for migration info
+const CONTRACT_NAME: &str = "crates.io:encke-router";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn instantiate(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    _msg: InstantiateMsg,
+) -> StdResult<Response> {
+    ADMIN.save(deps.storage, &info.sender.clone())?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    Ok(Response::new()
+        .add_attribute("action", "instantiate")
+        .add_attribute("admin", info.sender))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn execute(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> StdResult<Response> {
+    match msg {
+        ExecuteMsg::Receive(cw20_msg) => match from_json(&cw20_msg.msg)? {
+            Cw20HookMsg::ExecuteSwapOperations {
+                operations,
+                minimum_receive,
+                to,
+                ..
+            } => {
+                let offer = AssetInfo::Token {
+                    contract_addr: info.sender,
+                };
+                let recipient = to.unwrap_or(cw20_msg.sender);
+                execute_swap_operations(
+                    deps,
+                    offer,
+                    cw20_msg.amount,
+                    operations,
+                    minimum_receive,
+                    recipient,
+                )
+            }
+        },
+        ExecuteMsg::ExecuteSwapOperations {
+            operations,
+            minimum_receive,
+            to,
+            ..
+        } => {
+            let [coin] = info.funds.as_slice() else {
+                return Err(StdError::generic_err("Send exactly one native coin"));
+            };
+            let offer = AssetInfo::NativeToken {
+                denom: coin.denom.clone(),
+            };
+            let recipient = to.unwrap_or(info.sender.to_string());
+            execute_swap_operations(
+                deps,
+                offer,
+                coin.amount,
+                operations,
+                minimum_receive,
+                recipient,
+            )
+        }
+        ExecuteMsg::SetRate {
+            offer_asset_info,
+            ask_asset_info,
+            rate,
+        } => execute_set_rate(deps, info, offer_asset_info, ask_asset_info, rate),
+    }
+}
+
+/// Key of an asset in the rates map
+fn asset_key(asset: &AssetInfo) -> String {
+    match asset {
+        AssetInfo::Token { contract_addr } => contract_addr.to_string(),
+        AssetInfo::NativeToken { denom } => denom.clone(),
+    }
+}
+
+/// Amount of the last ask asset received for `amount` of `offer` through the operations
+fn swap_amount(
+    deps: Deps,
+    offer: &AssetInfo,
+    amount: Uint128,
+    operations: &[SwapOperation],
+) -> StdResult<(AssetInfo, Uint128)> {
+    let mut asset = offer.clone();
+    let mut amount = amount;
+    for SwapOperation::AstroSwap {
+        offer_asset_info,
+        ask_asset_info,
+    } in operations
+    {
+        if *offer_asset_info != asset {
+            return Err(StdError::generic_err("Operations do not chain"));
+        }
+        let rate = RATES
+            .may_load(
+                deps.storage,
+                (&asset_key(offer_asset_info), &asset_key(ask_asset_info)),
+            )?
+            .ok_or_else(|| StdError::generic_err("No rate for pair"))?;
+        amount = amount.mul_floor(rate);
+        asset = ask_asset_info.clone();
+    }
+    Ok((asset, amount))
+}
+
+pub mod execute {
+    use cosmwasm_std::{BankMsg, Coin, CosmosMsg, Decimal, WasmMsg};
+    use cw20::Cw20ExecuteMsg;
+
+    use super::*;
+
+    /// Swap `amount` of `offer` through the operations and send the result to `recipient`
+    pub fn execute_swap_operations(
+        deps: DepsMut,
+        offer: AssetInfo,
+        amount: Uint128,
+        operations: Vec<SwapOperation>,
+        minimum_receive: Option<Uint128>,
+        recipient: String,
+    ) -> StdResult<Response> {
+        if operations.is_empty() {
+            return Err(StdError::generic_err("No swap operations"));
+        }
+        let (ask, return_amount) = swap_amount(deps.as_ref(), &offer, amount, &operations)?;
+        if return_amount < minimum_receive.unwrap_or_default() {
+            return Err(StdError::generic_err(format!(
+                "Assertion failed; minimum receive amount: {}, swap amount: {}",
+                minimum_receive.unwrap_or_default(),
+                return_amount
+            )));
+        }
+
+        let msg: CosmosMsg = match &ask {
+            AssetInfo::NativeToken { denom } => BankMsg::Send {
+                to_address: recipient.clone(),
+                amount: vec![Coin {
+                    denom: denom.clone(),
+                    amount: return_amount,
+                }],
+            }
+            .into(),
+            AssetInfo::Token { contract_addr } => WasmMsg::Execute {
+                contract_addr: contract_addr.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: recipient.clone(),
+                    amount: return_amount,
+                })?,
+                funds: vec![],
+            }
+            .into(),
+        };
+        Ok(Response::new()
+            .add_message(msg)
+            .add_attribute("action", "swap")
+            .add_attribute("offer_asset", asset_key(&offer))
+            .add_attribute("ask_asset", asset_key(&ask))
+            .add_attribute("offer_amount", amount.to_string())
+            .add_attribute("return_amount", return_amount.to_string())
+            .add_attribute("recipient", recipient))
+    }
+
+    /// Set the rate of a pair (admin only)
+    pub fn execute_set_rate(
+        deps: DepsMut,
+        info: MessageInfo,
+        offer_asset_info: AssetInfo,
+        ask_asset_info: AssetInfo,
+        rate: Decimal,
+    ) -> StdResult<Response> {
+        let admin = ADMIN.load(deps.storage)?;
+        if info.sender != admin {
+            return Err(StdError::generic_err("Unauthorized"));
+        }
+        RATES.save(
+            deps.storage,
+            (&asset_key(&offer_asset_info), &asset_key(&ask_asset_info)),
+            &rate,
+        )?;
+        Ok(Response::new()
+            .add_attribute("action", "set_rate")
+            .add_attribute("offer_asset", asset_key(&offer_asset_info))
+            .add_attribute("ask_asset", asset_key(&ask_asset_info))
+            .add_attribute("rate", rate.to_string()))
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::SimulateSwapOperations {
+            offer_amount,
+            operations,
+        } => to_json_binary(&simulate_swap_operations(deps, offer_amount, operations)?),
+    }
+}
+
+pub mod query {
+    use Uint128;
+
+    use crate::msg::SimulateSwapOperationsResponse;
+
+    use super::*;
+
+    pub fn simulate_swap_operations(
+        deps: Deps,
+        offer_amount: Uint128,
+        operations: Vec<SwapOperation>,
+    ) -> StdResult<SimulateSwapOperationsResponse> {
+        let Some(SwapOperation::AstroSwap {
+            offer_asset_info, ..
+        }) = operations.first()
+        else {
+            return Err(StdError::generic_err("No swap operations"));
+        };
+        let (_, amount) = swap_amount(deps, offer_asset_info, offer_amount, &operations)?;
+        Ok(SimulateSwapOperationsResponse { amount })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{message_info, mock_dependencies, mock_env, MockApi};
+    use cosmwasm_std::{coin, coins, from_json, BankMsg, Decimal, OwnedDeps};
+
+    use crate::msg::SimulateSwapOperationsResponse;
+
+    use super::*;
+
+    type MockDeps =
+        OwnedDeps<cosmwasm_std::MemoryStorage, MockApi, cosmwasm_std::testing::MockQuerier>;
+
+    fn native(denom: &str) -> AssetInfo {
+        AssetInfo::NativeToken {
+            denom: denom.to_string(),
+        }
+    }
+
+    fn hop(offer: &str, ask: &str) -> SwapOperation {
+        SwapOperation::AstroSwap {
+            offer_asset_info: native(offer),
+            ask_asset_info: native(ask),
+        }
+    }
+
+    /// Router owned by "admin" swapping ATOM to USDC at 9.5 and USDC to OSMO at 2
+    fn setup() -> MockDeps {
+        let mut deps = mock_dependencies();
+        let admin = deps.api.addr_make("admin");
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            message_info(&admin, &[]),
+            InstantiateMsg {},
+        )
+        .unwrap();
+        for (offer, ask, rate) in [
+            ("uatom", "uusdc", Decimal::permille(9_500)),
+            ("uusdc", "uosmo", Decimal::percent(200)),
+        ] {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                message_info(&admin, &[]),
+                ExecuteMsg::SetRate {
+                    offer_asset_info: native(offer),
+                    ask_asset_info: native(ask),
+                    rate,
+                },
+            )
+            .unwrap();
+        }
+        deps
+    }
+
+    fn swap(operations: Vec<SwapOperation>, minimum_receive: Option<u128>) -> ExecuteMsg {
+        ExecuteMsg::ExecuteSwapOperations {
+            operations,
+            minimum_receive: minimum_receive.map(Uint128::new),
+            to: None,
+            max_spread: None,
+        }
+    }
+
+    #[test]
+    fn set_rate_admin_only() {
+        let mut deps = setup();
+        let user = deps.api.addr_make("user");
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            message_info(&user, &[]),
+            ExecuteMsg::SetRate {
+                offer_asset_info: native("uatom"),
+                ask_asset_info: native("uusdc"),
+                rate: Decimal::one(),
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Unauthorized"));
+    }
+
+    #[test]
+    fn swap_pays_out_at_rate() {
+        let mut deps = setup();
+        let user = deps.api.addr_make("user");
+        let response = execute(
+            deps.as_mut(),
+            mock_env(),
+            message_info(&user, &coins(100, "uatom")),
+            swap(vec![hop("uatom", "uusdc")], Some(950)),
+        )
+        .unwrap();
+        assert_eq!(
+            response.messages[0].msg,
+            BankMsg::Send {
+                to_address: user.to_string(),
+                amount: coins(950, "uusdc"),
+            }
+            .into()
+        );
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            message_info(&user, &coins(100, "uatom")),
+            swap(vec![hop("uatom", "uusdc")], Some(951)),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("minimum receive amount: 951, swap amount: 950"));
+    }
+
+    #[test]
+    fn chained_operations_must_connect() {
+        let mut deps = setup();
+        let user = deps.api.addr_make("user");
+        let info = message_info(&user, &coins(10, "uatom"));
+        let response = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            swap(vec![hop("uatom", "uusdc"), hop("uusdc", "uosmo")], None),
+        )
+        .unwrap();
+        assert_eq!(
+            response.messages[0].msg,
+            BankMsg::Send {
+                to_address: user.to_string(),
+                amount: coins(190, "uosmo"),
+            }
+            .into()
+        );
+
+        for (operations, expected) in [
+            (vec![hop("uusdc", "uosmo")], "Operations do not chain"),
+            (vec![hop("uatom", "uosmo")], "No rate for pair"),
+            (vec![], "No swap operations"),
+        ] {
+            let err = execute(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                swap(operations, None),
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            message_info(&user, &[coin(10, "uatom"), coin(10, "uusdc")]),
+            swap(vec![hop("uatom", "uusdc")], None),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Send exactly one native coin"));
+    }
+
+    #[test]
+    fn simulate_matches_swap() {
+        let deps = setup();
+        let response: SimulateSwapOperationsResponse = from_json(
+            query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::SimulateSwapOperations {
+                    offer_amount: Uint128::new(10),
+                    operations: vec![hop("uatom", "uusdc"), hop("uusdc", "uosmo")],
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(response.amount, Uint128::new(190));
+    }
+}
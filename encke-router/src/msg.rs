@@ -0,0 +1,67 @@
+use cosmwasm_schema::{cw_serde, QueryResponses};
+use cosmwasm_std::{Addr, Decimal, Uint128};
+use cw20::Cw20ReceiveMsg;
+
+/// Message to instantiate the mock router
+#[cw_serde]
+pub struct InstantiateMsg {}
+
+/// Messages to execute router actions, following the Astroport router interface
+#[cw_serde]
+pub enum ExecuteMsg {
+    Receive(Cw20ReceiveMsg), // Swap cw20 tokens sent with an ExecuteSwapOperations hook
+    ExecuteSwapOperations {
+        operations: Vec<SwapOperation>,
+        minimum_receive: Option<Uint128>,
+        to: Option<String>,
+        max_spread: Option<Decimal>,
+    }, // Swap the native funds sent through the operations in order
+    SetRate {
+        offer_asset_info: AssetInfo,
+        ask_asset_info: AssetInfo,
+        rate: Decimal,
+    }, // Set the ask amount paid per offer amount (admin only)
+}
+
+/// Hook messages for cw20 tokens sent to the router
+#[cw_serde]
+pub enum Cw20HookMsg {
+    ExecuteSwapOperations {
+        operations: Vec<SwapOperation>,
+        minimum_receive: Option<Uint128>,
+        to: Option<String>,
+        max_spread: Option<Decimal>,
+    }, // Swap the tokens received through the operations in order
+}
+
+/// A single hop of a swap route
+#[cw_serde]
+pub enum SwapOperation {
+    AstroSwap {
+        offer_asset_info: AssetInfo,
+        ask_asset_info: AssetInfo,
+    },
+}
+
+/// Native or cw20 asset
+#[cw_serde]
+pub enum AssetInfo {
+    Token { contract_addr: Addr },
+    NativeToken { denom: String },
+}
+
+/// Query messages for the router
+#[cw_serde]
+#[derive(QueryResponses)]
+pub enum QueryMsg {
+    #[returns(SimulateSwapOperationsResponse)]
+    SimulateSwapOperations {
+        offer_amount: Uint128,
+        operations: Vec<SwapOperation>,
+    }, // Get the amount a swap would return
+}
+
+#[cw_serde]
+pub struct SimulateSwapOperationsResponse {
+    pub amount: Uint128,
+}
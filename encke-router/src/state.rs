@@ -0,0 +1,6 @@
+use cosmwasm_std::{Addr, Decimal};
+use cw_storage_plus::{Item, Map};
+
+/// Storage for swap rates, ask amount per offer amount by (offer, ask) asset
+pub const RATES: Map<(&str, &str), Decimal> = Map::new("rates");
+pub const ADMIN: Item<Addr> = Item::new("admin");
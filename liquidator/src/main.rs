@@ -11,12 +11,13 @@ use cosmrs::{
     AccountId, Any, Coin, Denom,
 };
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{to_json_binary, Addr, CosmosMsg, Uint128, WasmMsg, WasmQuery};
+use cosmwasm_std::{to_json_binary, Addr, CosmosMsg, Decimal, Uint128, WasmMsg, WasmQuery};
 use log::{error, info, warn};
 use prost::Message;
+use serde::Deserialize;
 use std::fs;
 use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 use std::{error::Error as StdError, str::FromStr};
 
 // Custom error type to unify error handling
@@ -24,20 +25,35 @@ type Error = Box<dyn StdError + 'static>;
 
 // Configuration constants
 const NEUTRON_RPC_ENDPOINT: &str = "https://rpc-palvus.pion-1.ntrn.tech"; // Neutron testnet RPC
-const PAGE_SIZE: u32 = 100; // Number of positions per page
+const PAGE_SIZE: u32 = 100; // Number of positions scanned per page
 const BATCH_SIZE: usize = 50; // Number of positions per batch
+const REPAY_BUFFER_BPS: u128 = 50; // Extra repayment for interest accruing until the tx lands, refunded by the contract
 
 #[cw_serde]
 pub struct Position {
     pub borrower: Addr,
-    pub lender: Option<Addr>,
+    pub lenders: Vec<(Addr, Uint128)>,
     pub borrow_token: Token,
-    pub collateral_token: Token,
     pub amount: Uint128,
     pub interest_rate: Uint128,
-    pub collateral: Uint128,
+    pub collateral: Vec<(Token, Uint128)>,
     pub start_time: u64,
     pub filled: bool,
+    pub lender_nft: Option<Addr>,
+    pub refinance_count: u32,
+    pub term: Option<u64>,
+    pub maturity: Option<u64>,
+    pub allowed_lenders: Option<Vec<Addr>>,
+    pub return_to_deposits: bool,
+    pub e_mode: Option<(u8, EModeCategory)>,
+}
+
+#[cw_serde]
+pub struct EModeCategory {
+    pub tokens: Vec<String>,
+    pub max_ltv: Decimal,
+    pub liquidation_threshold: Uint128,
+    pub liquidation_bonus: Uint128,
 }
 
 #[cw_serde]
@@ -48,7 +64,7 @@ pub enum Token {
 
 #[cw_serde]
 pub enum QueryMsg {
-    GetAllPositions {
+    GetLiquidatablePositions {
         start_after: Option<Uint128>,
         limit: Option<u32>,
     },
@@ -56,19 +72,42 @@ pub enum QueryMsg {
 
 #[cw_serde]
 pub enum ExecuteMsg {
-    Liquidate { position_id: Uint128 },
+    Liquidate {
+        position_id: Uint128,
+        collateral_token: String,
+    },
+    StartAuction {
+        position_id: Uint128,
+    },
 }
 
 #[cw_serde]
-pub struct PositionsResponse {
-    pub positions: Vec<(Uint128, Position)>,
+pub enum LiquidationMode {
+    FixedPrice,
+    Auction,
+}
+
+// Only the fields the bot acts on; the contract also returns health metrics
+#[derive(Deserialize, Clone, Debug)]
+pub struct LiquidatablePosition {
+    pub position_id: Uint128,
+    pub position: Position,
+    pub mode: LiquidationMode,
+    pub collateral_token: Token,
+    pub repay_amount: Uint128,
+    pub collateral_payout: Uint128,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LiquidatablePositionsResponse {
+    pub positions: Vec<LiquidatablePosition>,
+    pub next_start_after: Option<Uint128>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::init(); // Initialize logging
     let contract_addr = Addr::unchecked("neutron1contractaddress"); // Replace with actual address
-    let oracle_addr = Addr::unchecked("neutron1oracleaddress"); // Replace with actual address
     let client = HttpClient::new(NEUTRON_RPC_ENDPOINT).expect("NEUTRON_RPC_ENDPOINT is not set");
     let key_path = "key.txt";
     let key = load_signing_key(key_path).expect("Failed to load signing key");
@@ -79,16 +118,7 @@ async fn main() -> Result<(), Error> {
     info!("Starting liquidation bot for contract: {}", contract_addr);
 
     loop {
-        match process_positions(
-            &client,
-            &contract_addr,
-            &oracle_addr,
-            &key,
-            &account_id,
-            &chain_id,
-        )
-        .await
-        {
+        match process_positions(&client, &contract_addr, &key, &account_id, &chain_id).await {
             Ok(_) => info!("Position check cycle completed"),
             Err(e) => error!("Error in position check cycle: {}", e),
         }
@@ -104,11 +134,10 @@ fn load_signing_key<P: AsRef<Path>>(path: P) -> Result<SigningKey, Box<dyn StdEr
     Ok(signing_key)
 }
 
-/// Process all positions in batches synchronously
+/// Process all liquidatable positions in batches synchronously
 async fn process_positions(
     client: &HttpClient,
     contract_addr: &Addr,
-    oracle_addr: &Addr,
     key: &SigningKey,
     account_id: &AccountId,
     chain_id: &str,
@@ -116,39 +145,30 @@ async fn process_positions(
     let mut start_after: Option<Uint128> = None;
     let mut all_positions = Vec::new();
 
-    // Fetch all positions with pagination
+    // Fetch all liquidatable positions with pagination, pages being counted in positions scanned
     loop {
-        let positions = query_positions(client, contract_addr, start_after).await?;
-        all_positions.extend(positions.clone());
+        let page = query_liquidatable_positions(client, contract_addr, start_after).await?;
         info!(
-            "Fetched {} positions, total now: {}",
-            positions.len(),
-            all_positions.len()
+            "Fetched {} liquidatable positions, total now: {}",
+            page.positions.len(),
+            all_positions.len() + page.positions.len()
         );
+        all_positions.extend(page.positions);
 
-        if positions.len() < PAGE_SIZE as usize {
+        if page.next_start_after.is_none() {
             break;
         }
-        start_after = positions.last().map(|(id, _)| *id);
+        start_after = page.next_start_after;
     }
 
     // Process positions in batches synchronously
-    let batches: Vec<Vec<(Uint128, Position)>> = all_positions
+    let batches: Vec<Vec<LiquidatablePosition>> = all_positions
         .chunks(BATCH_SIZE)
         .map(|chunk| chunk.to_vec())
         .collect();
 
     for batch in batches {
-        process_batch(
-            client,
-            contract_addr,
-            oracle_addr,
-            key,
-            account_id,
-            chain_id,
-            &batch,
-        )
-        .await?;
+        process_batch(client, contract_addr, key, account_id, chain_id, &batch).await?;
     }
 
     Ok(())
@@ -158,69 +178,59 @@ async fn process_positions(
 async fn process_batch(
     client: &HttpClient,
     contract_addr: &Addr,
-    oracle_addr: &Addr,
     key: &SigningKey,
     account_id: &AccountId,
     chain_id: &str,
-    batch: &[(Uint128, Position)],
+    batch: &[LiquidatablePosition],
 ) -> Result<(), Error> {
-    for (id, pos) in batch {
-        if !pos.filled {
-            continue; // Skip unfilled positions
-        }
-
-        let token_str = match &pos.collateral_token {
-            Token::Native(denom) => denom.clone(),
-            Token::Cw20(addr) => addr.to_string(),
+    for liquidatable in batch {
+        let id = liquidatable.position_id;
+        let (msg, funds) = match liquidatable.mode {
+            // Bidding is left to auction participants, the bot only opens the auction
+            LiquidationMode::Auction => {
+                info!("Starting auction for position {}", id);
+                (ExecuteMsg::StartAuction { position_id: id }, vec![])
+            }
+            LiquidationMode::FixedPrice => {
+                info!(
+                    "Liquidating position {}: repay_amount={}, collateral_payout={} {:?}",
+                    id,
+                    liquidatable.repay_amount,
+                    liquidatable.collateral_payout,
+                    liquidatable.collateral_token
+                );
+                liquidation_msg(liquidatable)
+            }
         };
-        let price = query_price(client, oracle_addr, &token_str).await?;
-        let collateral_value = pos.collateral * price;
-        let time_elapsed = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs()
-            - pos.start_time;
-        let interest = pos.amount * pos.interest_rate * Uint128::from(time_elapsed)
-            / Uint128::from(31_536_000u64 * 100u64);
-        let total_debt = pos.amount + interest;
-        let threshold = Uint128::from(150u128); // Assuming 1.5x from config
-
-        if collateral_value < total_debt * threshold / Uint128::from(100u128) {
-            info!(
-                "Liquidating position {}: collateral_value={}, total_debt={}",
-                id, collateral_value, total_debt
-            );
-            let tx = build_and_sign_tx(
-                client,
-                key,
-                account_id,
-                chain_id,
-                contract_addr,
-                *id,
-                &pos.borrow_token,
-                total_debt,
-            )
-            .await?;
-            match broadcast_tx(client, tx).await {
-                Ok(_) => {
-                    info!("Liquidation tx broadcasted for position {}", id);
-                }
-                Err(e) => {
-                    warn!("Broadcast failed for position {}: {}", id, e);
-                }
+        let tx = build_and_sign_tx(
+            client,
+            key,
+            account_id,
+            chain_id,
+            contract_addr,
+            &msg,
+            funds,
+        )
+        .await?;
+        match broadcast_tx(client, tx).await {
+            Ok(_) => {
+                info!("Liquidation tx broadcasted for position {}", id);
+            }
+            Err(e) => {
+                warn!("Broadcast failed for position {}: {}", id, e);
             }
-            info!("Liquidation tx broadcasted for position {}", id);
         }
     }
     Ok(())
 }
 
 #[allow(deprecated)]
-async fn query_positions(
+async fn query_liquidatable_positions(
     client: &HttpClient,
     contract_addr: &Addr,
     start_after: Option<Uint128>,
-) -> Result<Vec<(Uint128, Position)>, Error> {
-    let query_msg = QueryMsg::GetAllPositions {
+) -> Result<LiquidatablePositionsResponse, Error> {
+    let query_msg = QueryMsg::GetLiquidatablePositions {
         start_after,
         limit: Some(PAGE_SIZE),
     };
@@ -237,32 +247,34 @@ async fn query_positions(
         error!("Failed to decode response: {:?}", response.value);
         e
     })?;
-    let positions: PositionsResponse = serde_json::from_slice(&decoded)?;
-    Ok(positions.positions)
+    Ok(serde_json::from_slice(&decoded)?)
 }
 
-#[allow(deprecated)]
-async fn query_price(
-    client: &HttpClient,
-    oracle_addr: &Addr,
-    token: &str,
-) -> Result<Uint128, Error> {
-    let query_msg = oracle::QueryMsg::GetPrice {
-        token: token.to_string(),
+/// Liquidate message for a fixed-price liquidation, with the repayment and a buffer for
+/// interest accruing until the tx lands
+fn liquidation_msg(liquidatable: &LiquidatablePosition) -> (ExecuteMsg, Vec<cosmwasm_std::Coin>) {
+    let total_debt = liquidatable.repay_amount
+        + liquidatable
+            .repay_amount
+            .multiply_ratio(REPAY_BUFFER_BPS, 10_000u128);
+    let msg = ExecuteMsg::Liquidate {
+        position_id: liquidatable.position_id,
+        collateral_token: match &liquidatable.collateral_token {
+            Token::Native(denom) => denom.clone(),
+            Token::Cw20(addr) => addr.to_string(),
+        },
     };
-    let query_data = base64::encode(serde_json::to_vec(&WasmQuery::Smart {
-        contract_addr: oracle_addr.to_string(),
-        msg: to_json_binary(&query_msg)?,
-    })?);
-    let response = client
-        .abci_query(None, format!("wasm/query/{}", query_data), None, false)
-        .await?;
-
-    let decoded = base64::decode(response.value)?;
-    let price: Uint128 = serde_json::from_slice(&decoded)?;
-    Ok(price)
+    let funds = match &liquidatable.position.borrow_token {
+        Token::Native(denom) => vec![cosmwasm_std::Coin {
+            denom: denom.clone(),
+            amount: total_debt,
+        }],
+        Token::Cw20(_) => vec![],
+    };
+    (msg, funds)
 }
-/// Build and sign a liquidation transaction
+
+/// Build and sign a transaction executing `msg` on the contract
 #[allow(deprecated)]
 async fn build_and_sign_tx(
     client: &HttpClient,
@@ -270,21 +282,13 @@ async fn build_and_sign_tx(
     account_id: &AccountId,
     chain_id: &str,
     contract_addr: &Addr,
-    position_id: Uint128,
-    borrow_token: &Token,
-    total_debt: Uint128,
+    msg: &ExecuteMsg,
+    funds: Vec<cosmwasm_std::Coin>,
 ) -> Result<TxRaw, Box<dyn std::error::Error>> {
-    let msg = ExecuteMsg::Liquidate { position_id };
     let wasm_msg: CosmosMsg<ExecuteMsg> = CosmosMsg::Wasm(WasmMsg::Execute {
         contract_addr: contract_addr.to_string(),
-        msg: to_json_binary(&msg)?,
-        funds: match borrow_token {
-            Token::Native(denom) => vec![cosmwasm_std::Coin {
-                denom: denom.clone(),
-                amount: total_debt,
-            }],
-            Token::Cw20(_) => vec![],
-        },
+        msg: to_json_binary(msg)?,
+        funds,
     });
 
     // Convert CosmosMsg to MsgExecuteContract
@@ -429,12 +433,3 @@ async fn broadcast_tx(client: &HttpClient, tx: TxRaw) -> Result<(), Error> {
     }
     unreachable!()
 }
-
-mod oracle {
-    use cosmwasm_schema::cw_serde;
-
-    #[cw_serde]
-    pub enum QueryMsg {
-        GetPrice { token: String },
-    }
-}